@@ -0,0 +1,187 @@
+use crate::errors::Errcode;
+
+use nix::sys::socket::{
+    bind, recv, setsockopt, socket, sockopt, AddressFamily, MsgFlags, NetlinkAddr, SockFlag,
+    SockProtocol, SockType,
+};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::unistd::{close, Pid};
+use std::collections::BTreeSet;
+use std::fs::read_link;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{self};
+use log::{debug, error, info};
+
+//audit recordのtype(SECCOMP)
+const AUDIT_SECCOMP: u16 = 1326;
+//CAP_AUDIT_READで読めるmulticast group(auditdとは別に受信できる)
+const AUDIT_NLGRP_READLOG: u32 = 1;
+const NLMSG_HDRLEN: usize = 16;
+const RECV_BUF_SIZE: usize = 65536;
+//停止を確認する間隔
+const RECV_TIMEOUT_MS: i64 = 200;
+
+/// Syscall logged by a seccomp filter (SECCOMP_RET_LOG).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SeccompRecord {
+    pub pid: i32,
+    /// AUDIT_ARCH_* of the call, the same value as the SCMP_ARCH_* tokens
+    pub arch: u32,
+    pub syscall: i32,
+}
+
+/// Parse the text of an AUDIT_SECCOMP record:
+/// `audit(...): auid=... pid=42 comm="ls" ... arch=c000003e syscall=257 compat=0 ip=... code=0x7ffc0000`
+pub fn parse_record(text: &str) -> Option<SeccompRecord> {
+    let (mut pid, mut arch, mut syscall, mut code) = (None, None, None, None);
+    for field in text.split_ascii_whitespace() {
+        match field.split_once('=') {
+            Some(("pid", v)) => pid = v.parse().ok(),
+            Some(("arch", v)) => arch = u32::from_str_radix(v, 16).ok(),
+            Some(("syscall", v)) => syscall = v.parse().ok(),
+            Some(("code", v)) => code = u32::from_str_radix(v.trim_start_matches("0x"), 16).ok(),
+            _ => {}
+        }
+    }
+    // only calls logged instead of denied, not kills of other filters
+    if code? != libc::SECCOMP_RET_LOG {
+        return None;
+    }
+    Some(SeccompRecord {
+        pid: pid?,
+        arch: arch?,
+        syscall: syscall?,
+    })
+}
+
+/// PID namespace of a process, None once it has exited.
+fn pid_namespace(pid: i32) -> Option<PathBuf> {
+    read_link(format!("/proc/{}/ns/pid", pid)).ok()
+}
+
+/// Collects the syscalls logged by the container from the kernel audit log
+/// while it runs.
+/// Records are read from the audit netlink socket, which unlike the kernel
+/// log (dmesg) is not rate limited.
+pub struct Recorder {
+    stop: Arc<AtomicBool>,
+    namespace: Arc<Mutex<Option<PathBuf>>>,
+    handle: JoinHandle<Vec<(SeccompRecord, Option<PathBuf>)>>,
+}
+
+impl Recorder {
+    /// Start listening, before the container is created so no call is missed.
+    pub fn start() -> anyhow::Result<Recorder> {
+        let fd = open_audit_socket()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let handle = thread::spawn(move || {
+            let records = receive_records(fd, &stop_thread);
+            let _ = close(fd);
+            records
+        });
+        Ok(Recorder {
+            stop,
+            namespace: Arc::new(Mutex::new(None)),
+            handle,
+        })
+    }
+
+    /// Keep only the calls made in the PID namespace of `pid`.
+    pub fn watch(&self, pid: Pid) {
+        *self.namespace.lock().unwrap() = pid_namespace(pid.as_raw());
+    }
+
+    /// Stop listening and return the calls of the container.
+    /// Processes gone before their record was read can not be checked and are kept.
+    pub fn stop(self) -> BTreeSet<(u32, i32)> {
+        self.stop.store(true, Ordering::Relaxed);
+        let records = self.handle.join().unwrap_or_default();
+        let namespace = self.namespace.lock().unwrap().clone();
+        let calls: BTreeSet<(u32, i32)> = records
+            .into_iter()
+            .filter(|(_, ns)| ns.is_none() || *ns == namespace)
+            .map(|(record, _)| (record.arch, record.syscall))
+            .collect();
+        info!("{} distinct syscalls recorded", calls.len());
+        calls
+    }
+}
+
+fn open_audit_socket() -> anyhow::Result<RawFd> {
+    let fd = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkAudit,
+    )
+    .map_err(|e| {
+        error!("Unable to open audit socket: {:?}", e);
+        Errcode::SyscallsError(7)
+    })?;
+    let timeout = TimeVal::milliseconds(RECV_TIMEOUT_MS);
+    let ready = bind(fd, &NetlinkAddr::new(0, AUDIT_NLGRP_READLOG))
+        .and_then(|_| setsockopt(fd, sockopt::ReceiveTimeout, &timeout));
+    if let Err(e) = ready {
+        error!("Unable to listen to the audit log: {:?}", e);
+        let _ = close(fd);
+        return Err(Errcode::SyscallsError(7).into());
+    }
+    Ok(fd)
+}
+
+/// Receive until stopped and nothing is left to read.
+fn receive_records(fd: RawFd, stop: &AtomicBool) -> Vec<(SeccompRecord, Option<PathBuf>)> {
+    let mut records = vec![];
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    loop {
+        let len = match recv(fd, &mut buf, MsgFlags::empty()) {
+            Ok(len) => len,
+            Err(_) if stop.load(Ordering::Relaxed) => break,
+            Err(_) => continue,
+        };
+        // one record per message
+        if len < NLMSG_HDRLEN || u16::from_ne_bytes([buf[4], buf[5]]) != AUDIT_SECCOMP {
+            continue;
+        }
+        let msg_len = (u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize).min(len);
+        let text = String::from_utf8_lossy(&buf[NLMSG_HDRLEN..msg_len]);
+        if let Some(record) = parse_record(&text) {
+            // the namespace is read now, while the process most likely still exists
+            records.push((record, pid_namespace(record.pid)));
+        }
+    }
+    debug!("{} seccomp records received", records.len());
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_record_success() {
+        let text = "audit(1792367693.397:2): auid=4294967295 uid=0 gid=0 ses=4294967295 \
+            subj=kernel pid=21723 comm=\"ls\" exe=\"/usr/bin/ls\" sig=0 arch=c000003e \
+            syscall=39 compat=0 ip=0x7fc7800ee4e7 code=0x7ffc0000";
+        assert_eq!(
+            parse_record(text),
+            Some(SeccompRecord {
+                pid: 21723,
+                arch: 0xc000003e,
+                syscall: 39
+            })
+        );
+        // denied or killed calls are not recorded
+        assert_eq!(
+            parse_record(&text.replace("0x7ffc0000", "0x80000000")),
+            None
+        );
+        assert_eq!(parse_record("audit(1.0:1): pid=1 code=0x7ffc0000"), None);
+    }
+}
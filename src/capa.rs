@@ -1,44 +1,646 @@
 use crate::errors::Errcode;
+use crate::ipc::recv_boolean;
 
-use capctl::caps::Cap;
-use capctl::caps::FullCapState;
-use log::debug;
+use capctl::caps::{ambient, bounding};
+use capctl::caps::{Cap, CapSet, CapState};
+use capctl::prctl::{set_keepcaps, set_no_new_privs, set_securebits, Secbits};
+use clap::{Args, ValueEnum};
+use log::{debug, error, info, warn};
+use nix::unistd::Pid;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::RawFd;
 
 use anyhow::{self};
 
-const CAPABILITIES_DROP: [Cap; 21] = [
-    Cap::AUDIT_CONTROL,
-    Cap::AUDIT_READ,
+/// Capabilities kept by default, the same as the common runtimes
+const DEFAULT_CAPABILITIES: [Cap; 14] = [
     Cap::AUDIT_WRITE,
-    Cap::BLOCK_SUSPEND,
-    Cap::DAC_READ_SEARCH,
+    Cap::CHOWN,
     Cap::DAC_OVERRIDE,
+    Cap::FOWNER,
     Cap::FSETID,
-    Cap::IPC_LOCK,
-    Cap::MAC_ADMIN,
-    Cap::MAC_OVERRIDE,
+    Cap::KILL,
     Cap::MKNOD,
+    Cap::NET_BIND_SERVICE,
+    Cap::NET_RAW,
     Cap::SETFCAP,
-    Cap::SYSLOG,
-    Cap::SYS_ADMIN,
-    Cap::SYS_BOOT,
-    Cap::SYS_MODULE,
-    Cap::SYS_NICE,
-    Cap::SYS_RAWIO,
-    Cap::SYS_RESOURCE,
-    Cap::SYS_TIME,
-    Cap::WAKE_ALARM,
+    Cap::SETGID,
+    Cap::SETPCAP,
+    Cap::SETUID,
+    Cap::SYS_CHROOT,
 ];
 
-pub fn set_capa() -> anyhow::Result<()> {
-    debug!("Clearing unwanted capabilities ...");
-    if let Ok(mut caps) = FullCapState::get_current() {
-        caps.bounding
-            .drop_all(CAPABILITIES_DROP.iter().copied());
-        caps.inheritable
-            .drop_all(CAPABILITIES_DROP.iter().copied());
-        Ok(())
+/// Securebits flags (see capabilities(7)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Securebit {
+    /// root does not get capabilities on execve
+    Noroot,
+    NorootLocked,
+    /// Capabilities are not adjusted when switching from or to UID 0
+    NoSetuidFixup,
+    NoSetuidFixupLocked,
+    /// The permitted set is kept when switching to a non-root UID
+    KeepCaps,
+    KeepCapsLocked,
+    /// Ambient capabilities can not be raised
+    NoCapAmbientRaise,
+    NoCapAmbientRaiseLocked,
+}
+
+impl Securebit {
+    pub fn flag(&self) -> Secbits {
+        match self {
+            Securebit::Noroot => Secbits::NOROOT,
+            Securebit::NorootLocked => Secbits::NOROOT_LOCKED,
+            Securebit::NoSetuidFixup => Secbits::NO_SETUID_FIXUP,
+            Securebit::NoSetuidFixupLocked => Secbits::NO_SETUID_FIXUP_LOCKED,
+            Securebit::KeepCaps => Secbits::KEEP_CAPS,
+            Securebit::KeepCapsLocked => Secbits::KEEP_CAPS_LOCKED,
+            Securebit::NoCapAmbientRaise => Secbits::NO_CAP_AMBIENT_RAISE,
+            Securebit::NoCapAmbientRaiseLocked => Secbits::NO_CAP_AMBIENT_RAISE_LOCKED,
+        }
+    }
+}
+
+/// Capability sets of the container process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapConfig {
+    pub bounding: CapSet,
+    pub permitted: CapSet,
+    pub effective: CapSet,
+    pub inheritable: CapSet,
+    /// Kept across execve by a non-root user
+    pub ambient: CapSet,
+    pub securebits: Secbits,
+    /// Forbid gaining privileges with execve (setuid binaries, file capabilities)
+    pub no_new_privs: bool,
+}
+
+impl Default for CapConfig {
+    fn default() -> Self {
+        let caps = DEFAULT_CAPABILITIES.iter().copied().collect::<CapSet>();
+        CapConfig {
+            bounding: caps,
+            permitted: caps,
+            effective: caps,
+            inheritable: CapSet::empty(),
+            ambient: CapSet::empty(),
+            securebits: Secbits::empty(),
+            no_new_privs: true,
+        }
+    }
+}
+
+/// Parse `NET_ADMIN`, `CAP_NET_ADMIN` or `net_admin`.
+/// `ALL` is handled by the caller.
+fn parse_cap(name: &str, arg: &'static str) -> anyhow::Result<Cap> {
+    let full = if name.len() > 4 && name[..4].eq_ignore_ascii_case("CAP_") {
+        name.to_string()
     } else {
-        Err(Errcode::CapaError(0).into())
+        format!("CAP_{}", name)
+    };
+    full.parse::<Cap>().map_err(|_| {
+        error!("Unknown capability {}", name);
+        Errcode::InvalidArgument(arg).into()
+    })
+}
+
+fn is_all(name: &str) -> bool {
+    name.eq_ignore_ascii_case("ALL")
+}
+
+/// A whole set given with `ALL`, names, or an empty string for no capability.
+fn parse_set(names: &[String], arg: &'static str) -> anyhow::Result<CapSet> {
+    if names.iter().any(|name| is_all(name)) {
+        return Ok(Cap::iter().collect());
+    }
+    let mut caps = CapSet::empty();
+    for name in names.iter().filter(|name| !name.is_empty()) {
+        caps.add(parse_cap(name, arg)?);
+    }
+    Ok(caps)
+}
+
+/// `set` must be included in `of` (see capabilities(7)).
+fn check_subset(set: CapSet, of: CapSet, msg: &str, arg: &'static str) -> anyhow::Result<()> {
+    let extra = set - of;
+    if !extra.is_empty() {
+        error!("Capabilities {:?} {}", extra, msg);
+        return Err(Errcode::InvalidArgument(arg).into());
+    }
+    Ok(())
+}
+
+/// Capability sets given one by one.
+/// A set that is not given follows the one it must be included in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct CapSetArgs {
+    /// boundingセットをカンマ区切りで指定(ALLですべて, 空文字列でなし)
+    /// 指定しない場合はデフォルトのセット. --cap-add, --cap-dropはこのセットに適用される
+    #[clap(long, value_delimiter = ',')]
+    pub cap_bounding: Option<Vec<String>>,
+
+    /// permittedセット(boundingに含まれる必要がある, 指定しない場合はboundingと同じ)
+    /// exec時にroot以外はambient, rootはbounding+inheritableに再計算されるので、
+    /// それと異なる値はエラーになる
+    #[clap(long, value_delimiter = ',')]
+    pub cap_permitted: Option<Vec<String>>,
+
+    /// effectiveセット(permittedに含まれる必要がある, 指定しない場合はpermittedと同じ)
+    /// --cap-permittedと同様にexec後の値と異なる場合はエラー
+    #[clap(long, value_delimiter = ',')]
+    pub cap_effective: Option<Vec<String>>,
+
+    /// inheritableセット(boundingに含まれる必要がある, 指定しない場合は--cap-ambientと同じ)
+    #[clap(long, value_delimiter = ',')]
+    pub cap_inheritable: Option<Vec<String>>,
+}
+
+impl CapConfig {
+    /// Sets for `--cap-add`, `--cap-drop`, `--cap-ambient` and the sets of `CapSetArgs`.
+    /// `--cap-drop ALL` starts from no capability and `--cap-add ALL` from every one,
+    /// then the other names are added and dropped from the bounding set.
+    /// effective ⊆ permitted ⊆ bounding, inheritable ⊆ bounding
+    /// and ambient ⊆ permitted ∩ inheritable must hold.
+    pub fn new(
+        add: &[String],
+        drop: &[String],
+        ambient: &[String],
+        sets: &CapSetArgs,
+    ) -> anyhow::Result<CapConfig> {
+        let mut bounding = match &sets.cap_bounding {
+            Some(names) => parse_set(names, "cap-bounding")?,
+            None => CapConfig::default().bounding,
+        };
+        if drop.iter().any(|name| is_all(name)) {
+            bounding.clear();
+        }
+        if add.iter().any(|name| is_all(name)) {
+            bounding = Cap::iter().collect();
+        }
+        for name in add.iter().filter(|name| !is_all(name)) {
+            bounding.add(parse_cap(name, "cap-add")?);
+        }
+        for name in drop.iter().filter(|name| !is_all(name)) {
+            bounding.drop(parse_cap(name, "cap-drop")?);
+        }
+
+        let mut ambient_caps = CapSet::empty();
+        for name in ambient.iter() {
+            ambient_caps.add(parse_cap(name, "cap-ambient")?);
+        }
+        let permitted = match &sets.cap_permitted {
+            Some(names) => parse_set(names, "cap-permitted")?,
+            None => bounding,
+        };
+        let effective = match &sets.cap_effective {
+            Some(names) => parse_set(names, "cap-effective")?,
+            None => permitted,
+        };
+        let inheritable = match &sets.cap_inheritable {
+            Some(names) => parse_set(names, "cap-inheritable")?,
+            None => ambient_caps,
+        };
+
+        check_subset(
+            permitted,
+            bounding,
+            "are not in the bounding set",
+            "cap-permitted",
+        )?;
+        check_subset(
+            effective,
+            permitted,
+            "are not in the permitted set",
+            "cap-effective",
+        )?;
+        check_subset(
+            inheritable,
+            bounding,
+            "are not in the bounding set",
+            "cap-inheritable",
+        )?;
+        check_subset(
+            ambient_caps,
+            permitted,
+            "are not in the permitted set",
+            "cap-ambient",
+        )?;
+        check_subset(
+            ambient_caps,
+            inheritable,
+            "are not in the inheritable set",
+            "cap-ambient",
+        )?;
+
+        Ok(CapConfig {
+            bounding,
+            permitted,
+            effective,
+            inheritable,
+            ambient: ambient_caps,
+            ..Default::default()
+        })
+    }
+}
+
+impl CapConfig {
+    /// Permitted and effective sets given by execve to the container command.
+    /// Root gets the bounding and inheritable sets back (unless NOROOT),
+    /// other users only keep the ambient set.
+    pub fn after_exec(&self, uid: u32) -> CapSet {
+        if uid == 0 && !self.securebits.contains(Secbits::NOROOT) {
+            self.bounding.union(self.inheritable)
+        } else {
+            self.ambient
+        }
+    }
+
+    /// `--cap-permitted` and `--cap-effective` only last until execve,
+    /// a set the command would not get (see `after_exec`) is rejected.
+    pub fn check_exec(&self, sets: &CapSetArgs, uid: u32) -> anyhow::Result<()> {
+        let after = self.after_exec(uid);
+        for (given, set, arg) in [
+            (
+                sets.cap_permitted.is_some(),
+                self.permitted,
+                "cap-permitted",
+            ),
+            (
+                sets.cap_effective.is_some(),
+                self.effective,
+                "cap-effective",
+            ),
+        ] {
+            if given && set != after {
+                error!(
+                    "--{} {:?} is replaced by {:?} when uid {} executes the command",
+                    arg, set, after, uid
+                );
+                return Err(Errcode::InvalidArgument(arg).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply the capability sets to the current process.
+/// Called after switching to the container user, the permitted set
+/// is still there thanks to keep-caps (see `user_namespace`).
+pub fn set_capa(config: &CapConfig) -> anyhow::Result<()> {
+    debug!("Setting capabilities {:?}", config);
+    let capa_error = |code: u8| {
+        move |e| {
+            error!("Unable to set capabilities: {:?}", e);
+            Errcode::CapaError(code)
+        }
+    };
+
+    //setresuidでeffectiveが空になっているので、PR_CAPBSET_DROPに必要なCAP_SETPCAPを戻す
+    let mut state = CapState::get_current().map_err(capa_error(0))?;
+    state.effective = state.permitted;
+    state.set_current().map_err(capa_error(1))?;
+
+    for cap in Cap::probe_supported().iter() {
+        if !config.bounding.has(cap) {
+            bounding::drop(cap).map_err(capa_error(2))?;
+        }
+    }
+
+    //securebitsの変更にもCAP_SETPCAPが必要
+    set_keepcaps(false).map_err(capa_error(5))?;
+    if !config.securebits.is_empty() {
+        set_securebits(config.securebits).map_err(capa_error(8))?;
+    }
+
+    let mut state = CapState::empty();
+    state.permitted = config.permitted;
+    state.effective = config.effective;
+    state.inheritable = config.inheritable;
+    state.set_current().map_err(capa_error(3))?;
+
+    //ambientはpermittedとinheritableの両方にある必要がある
+    ambient::clear().map_err(capa_error(4))?;
+    for cap in config.ambient.iter() {
+        ambient::raise(cap).map_err(capa_error(4))?;
+    }
+
+    Ok(())
+}
+
+/// Set PR_SET_NO_NEW_PRIVS, it is kept across fork and execve and can not be unset.
+pub fn set_nnp() -> anyhow::Result<()> {
+    debug!("Setting no_new_privs");
+    if let Err(e) = set_no_new_privs() {
+        error!("Unable to set no_new_privs: {:?}", e);
+        return Err(Errcode::CapaError(9).into());
+    }
+    Ok(())
+}
+
+/// Parse a capability mask line of /proc/<pid>/status like `CapEff:\t00000000a80425fb`.
+fn status_caps(status: &str, key: &str) -> Option<CapSet> {
+    let line = status.lines().find(|line| line.starts_with(key))?;
+    let mask = line[key.len()..].trim_start_matches(':').trim();
+    u64::from_str_radix(mask, 16)
+        .ok()
+        .map(CapSet::from_bitmask_truncate)
+}
+
+/// Check the capabilities of the current process right before execve.
+/// `status` is /proc/self/status, opened before the root was changed.
+pub fn check_capa(status: &mut File, config: &CapConfig) -> anyhow::Result<()> {
+    let mut buf = String::new();
+    if let Err(e) = status
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| status.read_to_string(&mut buf))
+    {
+        error!("Unable to read the status of the container: {}", e);
+        return Err(Errcode::CapaError(6).into());
+    }
+
+    let nnp = buf
+        .lines()
+        .any(|line| line.starts_with("NoNewPrivs:") && line.ends_with('1'));
+    if nnp != config.no_new_privs {
+        error!(
+            "no_new_privs of the container is {}, expected {}",
+            nnp, config.no_new_privs
+        );
+        return Err(Errcode::CapaError(7).into());
+    }
+
+    if let Some((key, actual, expected)) = diff_caps(
+        &buf,
+        &[
+            ("CapInh", config.inheritable),
+            ("CapPrm", config.permitted),
+            ("CapEff", config.effective),
+            ("CapBnd", config.bounding),
+            ("CapAmb", config.ambient),
+        ],
+    ) {
+        error!(
+            "{} of the container is {:?}, expected {:?}",
+            key, actual, expected
+        );
+        return Err(Errcode::CapaError(7).into());
+    }
+    debug!("Capabilities verified");
+    Ok(())
+}
+
+/// Report the capabilities the command got from execve, `pid` must have executed it.
+/// The command can change its own sets right after execve
+/// (switching user, dropping capabilities, file capabilities without no_new_privs),
+/// so a difference is only logged.
+pub fn report_exec_capa(pid: Pid, config: &CapConfig, uid: u32) {
+    let status = match fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(e) => {
+            warn!("Unable to read the status of {}: {}", pid, e);
+            return;
+        }
+    };
+    // a command that has already exited has no capabilities to show
+    if status
+        .lines()
+        .any(|line| line.starts_with("State:") && line.contains('Z'))
+    {
+        return;
+    }
+
+    let after = config.after_exec(uid);
+    match diff_caps(
+        &status,
+        &[
+            ("CapInh", config.inheritable),
+            ("CapPrm", after),
+            ("CapEff", after),
+            ("CapBnd", config.bounding),
+            ("CapAmb", config.ambient),
+        ],
+    ) {
+        Some((key, actual, expected)) => warn!(
+            "{} of the command is {:?} after execve, expected {:?}",
+            key, actual, expected
+        ),
+        None => info!("Capabilities verified after execve"),
+    }
+}
+
+/// Find the first set of a /proc/<pid>/status that differs from the expected one.
+fn diff_caps<'a>(
+    status: &str,
+    expected: &[(&'a str, CapSet)],
+) -> Option<(&'a str, Option<CapSet>, CapSet)> {
+    // the kernel does not show the capabilities it does not know
+    let supported = Cap::probe_supported();
+    expected.iter().find_map(|(key, expected)| {
+        let expected = expected.intersection(supported);
+        let actual = status_caps(status, key);
+        (actual != Some(expected)).then_some((*key, actual, expected))
+    })
+}
+
+/// Result of `check_capa` sent by the child process before execve.
+/// Fails as well when the child process exits before.
+pub fn recv_capa_check(fd: RawFd) -> anyhow::Result<()> {
+    if recv_boolean(fd)? {
+        error!("Capabilities of the container differ from the requested ones");
+        return Err(Errcode::CapaError(7).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn cap_config_add_drop() {
+        let config = CapConfig::new(
+            &names(&["net_admin"]),
+            &names(&["CAP_MKNOD"]),
+            &[],
+            &CapSetArgs::default(),
+        )
+        .unwrap();
+        assert!(config.bounding.has(Cap::NET_ADMIN));
+        assert!(!config.effective.has(Cap::MKNOD));
+        assert!(config.permitted.has(Cap::CHOWN));
+        assert!(config.ambient.is_empty());
+
+        let config = CapConfig::new(
+            &names(&["NET_BIND_SERVICE"]),
+            &names(&["ALL"]),
+            &names(&["NET_BIND_SERVICE"]),
+            &CapSetArgs::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.bounding.iter().collect::<Vec<_>>(),
+            vec![Cap::NET_BIND_SERVICE]
+        );
+        assert!(config.inheritable.has(Cap::NET_BIND_SERVICE));
+        assert!(config.ambient.has(Cap::NET_BIND_SERVICE));
+
+        let config = CapConfig::new(
+            &names(&["all"]),
+            &names(&["SYS_ADMIN"]),
+            &[],
+            &CapSetArgs::default(),
+        )
+        .unwrap();
+        assert!(config.bounding.has(Cap::SYS_MODULE));
+        assert!(!config.bounding.has(Cap::SYS_ADMIN));
+    }
+
+    #[test]
+    fn cap_config_invalid() {
+        let sets = CapSetArgs::default();
+        assert!(CapConfig::new(&names(&["FOO"]), &[], &[], &sets).is_err());
+        // not in the permitted set
+        assert!(CapConfig::new(&[], &[], &names(&["SYS_ADMIN"]), &sets).is_err());
+        let sets = CapSetArgs {
+            cap_bounding: Some(names(&["CHOWN"])),
+            cap_permitted: Some(names(&["CHOWN", "KILL"])),
+            ..Default::default()
+        };
+        assert!(CapConfig::new(&[], &[], &[], &sets).is_err());
+        let sets = CapSetArgs {
+            cap_permitted: Some(names(&["CHOWN"])),
+            cap_effective: Some(names(&["KILL"])),
+            ..Default::default()
+        };
+        assert!(CapConfig::new(&[], &[], &[], &sets).is_err());
+        // not in the inheritable set
+        let sets = CapSetArgs {
+            cap_inheritable: Some(names(&[""])),
+            ..Default::default()
+        };
+        assert!(CapConfig::new(&[], &[], &names(&["CHOWN"]), &sets).is_err());
+    }
+
+    #[test]
+    fn cap_config_sets() {
+        let sets = CapSetArgs {
+            cap_bounding: Some(names(&["CHOWN", "KILL", "NET_BIND_SERVICE"])),
+            cap_permitted: Some(names(&["KILL", "NET_BIND_SERVICE"])),
+            cap_effective: Some(names(&[""])),
+            cap_inheritable: Some(names(&["KILL", "NET_BIND_SERVICE"])),
+        };
+        let config = CapConfig::new(
+            &[],
+            &names(&["CHOWN"]),
+            &names(&["NET_BIND_SERVICE"]),
+            &sets,
+        )
+        .unwrap();
+        assert_eq!(
+            config.bounding.iter().collect::<Vec<_>>(),
+            vec![Cap::KILL, Cap::NET_BIND_SERVICE]
+        );
+        assert_eq!(config.permitted, config.bounding);
+        assert!(config.effective.is_empty());
+        assert!(config.inheritable.has(Cap::KILL));
+        assert_eq!(
+            config.ambient.iter().collect::<Vec<_>>(),
+            vec![Cap::NET_BIND_SERVICE]
+        );
+        // unset sets follow the set they are included in
+        let sets = CapSetArgs {
+            cap_permitted: Some(names(&["KILL"])),
+            ..Default::default()
+        };
+        let config = CapConfig::new(&[], &[], &[], &sets).unwrap();
+        assert_eq!(config.effective, config.permitted);
+        assert_eq!(config.bounding, CapConfig::default().bounding);
+    }
+
+    #[test]
+    fn status_caps_success() {
+        let status = "Name:\tsh\nCapInh:\t0000000000000000\nCapEff:\t0000000000000401\nCapBnd:\t000001ffffffffff\n";
+        let eff = status_caps(status, "CapEff").unwrap();
+        assert_eq!(
+            eff.iter().collect::<Vec<_>>(),
+            vec![Cap::CHOWN, Cap::NET_BIND_SERVICE]
+        );
+        assert!(status_caps(status, "CapBnd").unwrap().has(Cap::SYS_ADMIN));
+        assert!(status_caps(status, "CapAmb").is_none());
+
+        let config = CapConfig::new(&[], &names(&["ALL"]), &[], &CapSetArgs::default()).unwrap();
+        assert!(config.after_exec(0).is_empty());
+        let mut config = CapConfig::default();
+        assert_eq!(config.after_exec(0), config.effective);
+        assert!(config.after_exec(1000).is_empty());
+        config.securebits = Securebit::Noroot.flag();
+        assert!(config.after_exec(0).is_empty());
+    }
+
+    #[test]
+    fn cap_config_check_exec() {
+        let sets = CapSetArgs {
+            cap_permitted: Some(names(&["NET_BIND_SERVICE"])),
+            cap_effective: Some(names(&["NET_BIND_SERVICE"])),
+            ..Default::default()
+        };
+        let ambient = names(&["NET_BIND_SERVICE"]);
+        let config = CapConfig::new(&[], &[], &ambient, &sets).unwrap();
+        assert!(config.check_exec(&sets, 1000).is_ok());
+        // root gets the whole bounding set
+        assert!(config.check_exec(&sets, 0).is_err());
+        let config = CapConfig::new(&[], &[], &[], &sets).unwrap();
+        assert!(config.check_exec(&sets, 1000).is_err());
+        // not given, nothing to check
+        let config = CapConfig::default();
+        assert!(config.check_exec(&CapSetArgs::default(), 1000).is_ok());
+    }
+
+    #[test]
+    fn check_capa_success() {
+        let state = CapState::get_current().unwrap();
+        let mut config = CapConfig {
+            bounding: bounding::probe(),
+            permitted: state.permitted,
+            effective: state.effective,
+            inheritable: state.inheritable,
+            ambient: ambient::probe().unwrap_or_default(),
+            securebits: Secbits::empty(),
+            no_new_privs: capctl::prctl::get_no_new_privs().unwrap(),
+        };
+        let mut status = File::open("/proc/self/status").unwrap();
+        assert!(check_capa(&mut status, &config).is_ok());
+        // read again from the start
+        assert!(check_capa(&mut status, &config).is_ok());
+        config.no_new_privs = !config.no_new_privs;
+        assert!(check_capa(&mut status, &config).is_err());
+        config.no_new_privs = !config.no_new_privs;
+        config.inheritable = !config.inheritable;
+        assert!(check_capa(&mut status, &config).is_err());
+    }
+
+    #[test]
+    fn diff_caps_success() {
+        let status =
+            "CapInh:\t0000000000000000\nCapPrm:\t0000000000000401\nCapEff:\t0000000000000400\n";
+        let caps = [Cap::CHOWN, Cap::NET_BIND_SERVICE]
+            .iter()
+            .copied()
+            .collect::<CapSet>();
+        assert!(diff_caps(status, &[("CapInh", CapSet::empty()), ("CapPrm", caps)]).is_none());
+        let (key, actual, _) = diff_caps(status, &[("CapEff", caps)]).unwrap();
+        assert_eq!(key, "CapEff");
+        assert_eq!(
+            actual,
+            Some([Cap::NET_BIND_SERVICE].iter().copied().collect::<CapSet>())
+        );
+        // not in the status
+        assert!(diff_caps(status, &[("CapAmb", CapSet::empty())]).is_some());
     }
 }
@@ -1,9 +1,12 @@
-use crate::capa::set_capa;
+use crate::capa::{check_capa, set_capa, set_nnp};
 use crate::config_opts::ContainerOptions;
 use crate::errors::Errcode;
-use crate::host::set_container_hostname;
+use crate::host::{set_container_domainname, set_container_hostname};
+use crate::ipc::send_boolean;
 use crate::mount::set_mount_point;
-use crate::namespace::user_namespace;
+use crate::namespace::{user_namespace, wait_id_maps};
+use crate::netlink::set_loopback_up;
+use crate::resource::set_rlimits;
 use crate::syscalls::set_syscalls;
 
 use nix::sched::clone;
@@ -11,6 +14,7 @@ use nix::sched::CloneFlags;
 use nix::sys::signal::Signal;
 use nix::unistd::{close, execve, Pid};
 use std::ffi::CString;
+use std::fs::File;
 
 use log::{error, info};
 
@@ -20,11 +24,47 @@ const STACK_SIZE: usize = 1024 * 1024;
 
 ///initialize Container
 fn init_container_config(config: &ContainerOptions) -> anyhow::Result<()> {
+    //新しいrootには/procがないので、capabilityの確認に使うstatusを先に開いておく
+    let mut status = match File::open("/proc/self/status") {
+        Ok(status) => status,
+        Err(e) => {
+            error!("Unable to open the status of the container: {}", e);
+            return Err(Errcode::CapaError(6).into());
+        }
+    };
+    if config.rootless {
+        wait_id_maps(config.fd)?;
+    }
     set_container_hostname(&config.hostname)?;
-    set_mount_point(&config.mount_directory, &config.add_paths)?;
-    user_namespace(config.fd, config.uid)?;
-    set_capa()?;
-    set_syscalls()?;
+    if let Some(domainname) = &config.domainname {
+        set_container_domainname(domainname)?;
+    }
+    set_mount_point(
+        &config.mount_directory,
+        &config.add_paths,
+        config.root_propagation,
+        config.rootless,
+    )?;
+    //user namespaceに移るとnetwork namespaceの操作ができなくなるので先に行う
+    set_loopback_up()?;
+    //hard limitを上げるにはhostのCAP_SYS_RESOURCEが必要なのでuser namespaceに移る前に設定する
+    set_rlimits(&config.rlimits)?;
+    user_namespace(config.fd, config.uid, config.rootless)?;
+    //no_new_privsなしでseccompを読み込むにはCAP_SYS_ADMINが必要なので、capabilityを落とす前に読み込む
+    if !config.capabilities.no_new_privs {
+        set_syscalls(config)?;
+    }
+    set_capa(&config.capabilities)?;
+    if config.capabilities.no_new_privs {
+        set_nnp()?;
+    }
+    //要求したcapabilityになっているか確認して、結果をparentに送る
+    let checked = check_capa(&mut status, &config.capabilities);
+    send_boolean(config.fd, checked.is_err())?;
+    checked?;
+    if config.capabilities.no_new_privs {
+        set_syscalls(config)?;
+    }
     Ok(())
 }
 
@@ -51,7 +91,6 @@ fn child(config: ContainerOptions) -> isize {
 
     //CStringを使用していることをRustに伝える.(Cとの互換性が必要)
     //execveが成功した場合、プロセスが実行可能ファイルに置き換えられるので関数はreturnしない
-    
 
     match execve::<CString, CString>(&config.path, &config.args, &[]) {
         Ok(_) => 0,
@@ -86,6 +125,10 @@ pub fn create_child_process(config: ContainerOptions) -> anyhow::Result<Pid> {
     flags.insert(CloneFlags::CLONE_NEWNET);
     //新しいuts namespaceでcloneされたchild processを開始
     flags.insert(CloneFlags::CLONE_NEWUTS);
+    //rootlessの場合、ほかのnamespaceを作成できるように新しいuser namespaceも同時に作成する
+    if config.rootless {
+        flags.insert(CloneFlags::CLONE_NEWUSER);
+    }
 
     //処理が成功したらpid(kernel processの識別番号)を取得
     match clone(
@@ -1,20 +1,99 @@
+use crate::capa::{CapSetArgs, Securebit};
 use crate::errors::Errcode;
+use crate::host::{parse_extra_host, validate_hostname};
+use crate::mount::Propagation;
+use crate::namespace::validate_uid;
+use crate::network::{Ipv4Net, NetworkMode, PortMapping};
+use crate::notify::NotifyRule;
+use crate::resource::{check_rlimits, validate_cgroup_parent, ResourceConfig, Ulimit};
+use crate::seccomp::SeccompMode;
+use crate::state::{new_id, reserve_name, validate_name};
 
-use clap::Parser;
+use clap::{ArgAction, Args, Parser, Subcommand};
 use log::*;
+use nix::unistd::geteuid;
 use simplelog::*;
-use std::fs::File;
-use std::path::PathBuf;
+use std::fs::{create_dir_all, File};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 use anyhow::{self};
 
+//run以外のコマンドのログファイル
+const LOG_FILE: &str = "bowl.log";
+//runのログファイルを置くdirectory(<data root>/logs/<id>.log)
+const LOGS_DIR: &str = "logs";
+//rootで実行した場合のdata root
+const DATA_ROOT: &str = "/var/lib/bowl-rs";
+
 #[derive(Debug, Parser)]
 #[clap(name = "Bowl RS", author = "syuta", version = "v0.1")]
 pub struct BowlArg {
     //ログメッセージのレベルを設定するために使用
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     debug: Option<bool>,
 
+    //volumeなどbowl-rsが管理するデータを置くdirectory
+    //rootでない場合は書き込めるように$XDG_RUNTIME_DIR/bowl-rsを使う
+    #[clap(long, global = true, default_value_os_t = default_data_root())]
+    pub data_root: PathBuf,
+
+    #[clap(subcommand)]
+    pub subcommand: SubCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SubCommand {
+    /// コンテナを作成して実行
+    Run(Box<RunArg>),
+    /// コンテナの一覧を表示
+    Ps,
+    /// コンテナの状態をJSONで表示(ID,IDの先頭部分または名前で指定)
+    Inspect { containers: Vec<String> },
+    /// 終了したコンテナを削除(bowl-rsが強制終了されて残ったものなど)
+    Rm {
+        #[clap(required = true)]
+        containers: Vec<String>,
+    },
+    /// コンテナのCPU,メモリ,プロセス数,block I/Oの使用量を表示(毎秒更新)
+    Stats {
+        #[clap(required = true)]
+        containers: Vec<String>,
+        /// 一度だけ表示して終了する
+        #[clap(long)]
+        no_stream: bool,
+        /// JSONで表示する
+        #[clap(long)]
+        json: bool,
+    },
+    /// 実行中のコンテナのresource制限を変更
+    Update {
+        container: String,
+        #[clap(flatten)]
+        resources: ResourceConfig,
+    },
+    /// volumeを管理
+    #[clap(subcommand)]
+    Volume(VolumeArg),
+    /// CNI networkを操作
+    #[clap(subcommand)]
+    Cni(CniArg),
+}
+
+#[derive(Debug, Args)]
+pub struct RunArg {
+    //コンテナID(CLIからは指定できず、parse_argsで生成する)
+    #[clap(skip)]
+    pub id: String,
+
+    //--nameの予約(開いている間は他のコンテナが同じ名前を使えない)
+    #[clap(skip)]
+    pub name_lock: Option<File>,
+
+    /// コンテナの名前(ID以外にこの名前でも指定できる)
+    #[clap(long)]
+    pub name: Option<String>,
+
     //コンテナ内で実行されるコマンド
     #[clap(short, long)]
     pub command: String,
@@ -28,47 +107,318 @@ pub struct BowlArg {
     pub mount_directory: PathBuf,
 
     /// コンテナ内のディレクトリをマウント
+    /// 書式 <host path>:<container path>[:<options>]
+    /// optionsはpropagationとidmapをカンマ区切りで指定(例: rslave,idmap)
+    /// idmapを指定するとhostのファイルの所有者がコンテナ内でもそのまま見える
     #[clap(short, long)]
     pub add_paths: Vec<PathBuf>,
+
+    /// named volumeをマウント(存在しない場合は作成)
+    /// 書式 <volume name>:<container path>[:<options>]
+    #[clap(short, long)]
+    pub volumes: Vec<String>,
+
+    /// コンテナのホスト名(指定しない場合はランダムに生成)
+    #[clap(long)]
+    pub hostname: Option<String>,
+
+    /// コンテナのNISドメイン名
+    #[clap(long)]
+    pub domainname: Option<String>,
+
+    /// コンテナのnetwork
+    /// bridgeの場合はhostのbridgeにvethで接続してNATで外に出られるようにする
+    /// slirpの場合はslirp4netnsを使うのでhostの権限が不要(rootなしで使えるのはnone,slirpのみ)
+    /// cniの場合は--cni-conf-dirの設定でCNI pluginを呼び出す
+    #[clap(long, value_enum, default_value_t = NetworkMode::None)]
+    pub network: NetworkMode,
+
+    /// --network bridgeで使うhostのbridge名
+    #[clap(long, default_value = "bowl0")]
+    pub bridge: String,
+
+    /// --network bridgeでコンテナにアドレスを割り当てるsubnet
+    /// bridgeにはsubnetの最初のアドレスが割り当てられる
+    #[clap(long, default_value = "172.30.0.0/16")]
+    pub subnet: Ipv4Net,
+
+    /// --network cniで使うnetworkの名前(指定しない場合は--cni-conf-dirの最初のもの)
+    #[clap(long)]
+    pub cni_network: Option<String>,
+
+    /// CNIのnetwork設定(.conflist,.conf)を置くdirectory
+    #[clap(long, default_value = "/etc/cni/net.d")]
+    pub cni_conf_dir: PathBuf,
+
+    /// CNI pluginを置くdirectory
+    #[clap(long, default_value = "/opt/cni/bin")]
+    pub cni_bin_dir: PathBuf,
+
+    /// hostのportをコンテナのportに転送(--network bridge,slirp,cniのみ)
+    /// cniの場合はportMappingsのcapabilityを持つpluginに渡す
+    /// 書式 <host port>:<container port>[/tcp|udp]
+    #[clap(short, long)]
+    pub publish: Vec<PortMapping>,
+
+    /// /etc/hostsにエントリを追加 書式 <hostname>:<ip>
+    #[clap(long)]
+    pub add_host: Vec<String>,
+
+    /// コンテナが使うDNSサーバー(指定しない場合はhostの設定を使う)
+    #[clap(long)]
+    pub dns: Vec<IpAddr>,
+
+    /// DNSのsearch domain(指定しない場合はhostの設定を使う)
+    #[clap(long)]
+    pub dns_search: Vec<String>,
+
+    /// capabilityを追加(ALLですべて) 例: --cap-add NET_ADMIN
+    #[clap(long)]
+    pub cap_add: Vec<String>,
+
+    /// capabilityを削除(ALLですべて)
+    /// --cap-drop ALL --cap-add NET_BIND_SERVICEのように必要なものだけ残せる
+    #[clap(long)]
+    pub cap_drop: Vec<String>,
+
+    /// ambient capability(root以外のuidでもexec後に残る)
+    /// 例: -u 1000 --cap-ambient NET_BIND_SERVICE
+    #[clap(long)]
+    pub cap_ambient: Vec<String>,
+
+    //capabilityのセットを個別に指定(--cap-boundingなど)
+    #[clap(flatten)]
+    pub cap_sets: CapSetArgs,
+
+    /// no_new_privsを設定する(setuidなどでexec後に権限が増えないようにする)
+    /// falseの場合、seccompの読み込みにCAP_SYS_ADMINが必要
+    #[clap(long, action = ArgAction::Set, default_value_t = true)]
+    pub no_new_privs: bool,
+
+    /// securebitsをカンマ区切りで指定 例: --securebits noroot,noroot-locked
+    #[clap(long, value_enum, value_delimiter = ',')]
+    pub securebits: Vec<Securebit>,
+
+    /// seccomp filter
+    /// default: 組み込みのallowlist, legacy: 以前のdenylist, unconfined: filterなし,
+    /// それ以外はseccomp profile(Docker/OCI形式のJSON)のパス
+    #[clap(long, alias = "seccomp-profile", default_value = "default")]
+    pub seccomp: SeccompMode,
+
+    /// seccompで拒否する代わりにkernelのaudit logに記録する(profile作成用)
+    #[clap(long)]
+    pub seccomp_log: bool,
+
+    /// 実行中に使われたsyscallをaudit logから集め、それだけを許可するseccomp profileをFILEに書き出す
+    /// 記録中はすべてのsyscallが許可される(--seccompは無視される)
+    #[clap(long, value_name = "FILE")]
+    pub seccomp_record: Option<PathBuf>,
+
+    /// seccompのuser notificationでbowl-rsに処理させるsyscall 書式 <syscall>[=log|deny|emulate]
+    /// log: 記録して実行, deny: EPERMで拒否, emulate: 代わりに実行する(mknod/mknodatで決まったdevice nodeのみ)
+    /// 例: --seccomp-notify mount --seccomp-notify mknod=emulate
+    /// 指定したsyscallは--seccompのprofileより優先される(profileでは許可される)
+    /// sendmsgはlistenerをbowl-rsに渡すのに使うので指定できない
+    #[clap(long)]
+    pub seccomp_notify: Vec<NotifyRule>,
+
+    /// コンテナのプロセスのrlimit 書式 <name>=<soft>[:<hard>] (unlimitedで無制限)
+    /// 例: --ulimit nofile=1024:4096 --ulimit core=0
+    /// nofileを指定しない場合は64
+    #[clap(long)]
+    pub ulimit: Vec<Ulimit>,
+
+    /// コンテナのcgroupを作る親cgroup(hierarchyのrootからのpath) 例: bowl.slice/team-a
+    /// 親に設定した制限はその下のコンテナ全体に適用される. 存在しない場合は作成する
+    #[clap(long)]
+    pub cgroup_parent: Option<String>,
+
+    //cgroupで制限するresource(--memory,--cpusなど)
+    #[clap(flatten)]
+    pub resources: ResourceConfig,
+
+    /// root("/")のmount propagation
+    /// rslaveにするとhostで後からmountされたものがコンテナ内にも見える
+    #[clap(long, value_enum, default_value_t = Propagation::Rprivate)]
+    pub root_propagation: Propagation,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum VolumeArg {
+    /// volumeを作成
+    Create { name: String },
+    /// volumeの一覧を表示
+    Ls,
+    /// volumeを削除
+    Rm {
+        names: Vec<String>,
+        /// コンテナで使用中でも削除する
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// volumeの詳細をJSONで表示
+    Inspect { names: Vec<String> },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CniArg {
+    /// CNI pluginにCHECKを送ってコンテナのnetworkを確認
+    Check { container: String },
 }
 
 /// parse argument
 pub fn parse_args() -> anyhow::Result<BowlArg> {
-    let args = BowlArg::parse();
+    let mut args = BowlArg::parse();
+    let data_root = args.data_root.clone();
+
+    //runの場合はコンテナIDを発行して、ログもコンテナごとのファイルに書き出す
+    let mut log_file = PathBuf::from(LOG_FILE);
+    let mut result = Ok(());
+    if let SubCommand::Run(run) = &mut args.subcommand {
+        result = validate_run_args(run).and_then(|_| {
+            //同時に実行されても同じ名前にならないように,ここで名前を予約する
+            if let Some(name) = &run.name {
+                run.name_lock = Some(reserve_name(&data_root, name)?);
+            }
+            run.id = new_id(&data_root)?;
+            log_file = data_root.join(LOGS_DIR).join(format!("{}.log", run.id));
+            Ok(())
+        });
+    }
 
     //set log level
     match args.debug {
-        Some(debug) if debug => setting_log(LevelFilter::Debug),
-        _ => setting_log(LevelFilter::Info),
+        Some(debug) if debug => setting_log(LevelFilter::Debug, &log_file)?,
+        _ => setting_log(LevelFilter::Info, &log_file)?,
     }
 
+    result.map(|_| args)
+}
+
+/// default data root
+/// root: /var/lib/bowl-rs, それ以外: $XDG_RUNTIME_DIR/bowl-rs
+/// XDG_RUNTIME_DIRがない場合は一時directoryの下にuidごとに作る
+fn default_data_root() -> PathBuf {
+    let uid = geteuid();
+    if uid.is_root() {
+        return PathBuf::from(DATA_ROOT);
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("bowl-rs"),
+        _ => std::env::temp_dir().join(format!("bowl-rs-{}", uid)),
+    }
+}
+
+/// check args(run)
+fn validate_run_args(run: &RunArg) -> anyhow::Result<()> {
     // check args(mount drectory)
-    if !args.mount_directory.exists() || !args.mount_directory.is_dir() {
+    if !run.mount_directory.exists() || !run.mount_directory.is_dir() {
         return Err(Errcode::InvalidArgument("mount_directory").into());
     }
 
     // check args(command)
-    if args.command.is_empty() {
+    if run.command.is_empty() {
         return Err(Errcode::InvalidArgument("command").into());
     }
 
-    Ok(args)
+    // check args(uid)
+    validate_uid(run.uid)?;
+
+    // check args(hostname, domainname)
+    if let Some(hostname) = &run.hostname {
+        validate_hostname(hostname, "hostname")?;
+    }
+    if let Some(domainname) = &run.domainname {
+        validate_hostname(domainname, "domainname")?;
+    }
+
+    // check args(add_host, dns_search)
+    //どちらも/etc/hosts,resolv.confにそのまま書き込まれる
+    for host in run.add_host.iter() {
+        parse_extra_host(host)?;
+    }
+    for domain in run.dns_search.iter() {
+        validate_hostname(domain, "dns_search")?;
+    }
+
+    // check args(bridge)
+    if run.bridge.is_empty() || run.bridge.len() > 15 || run.bridge.contains('/') {
+        return Err(Errcode::InvalidArgument("bridge").into());
+    }
+
+    // check args(publish)
+    //使用中かどうかはコンテナの作成時に予約しながら確認する
+    if !run.publish.is_empty() && run.network == NetworkMode::None {
+        error!("--publish needs --network bridge, slirp or cni");
+        return Err(Errcode::InvalidArgument("publish").into());
+    }
+
+    // check args(ulimit)
+    check_rlimits(&run.ulimit)?;
+
+    // check args(cgroup parent)
+    if let Some(parent) = &run.cgroup_parent {
+        validate_cgroup_parent(parent)?;
+    }
+
+    // check args(resources)
+    run.resources.validate()?;
+
+    // check args(name)
+    if let Some(name) = &run.name {
+        validate_name(name, "name")?;
+        //ホスト名を指定しない場合は名前がホスト名になる
+        if run.hostname.is_none() && validate_hostname(name, "name").is_err() {
+            error!(
+                "--name {} can not be used as the hostname, set --hostname",
+                name
+            );
+            return Err(Errcode::InvalidArgument("name").into());
+        }
+    }
+
+    // check args(rootless)
+    //rootなしではcgroup,bridge,cniを設定できない
+    if !geteuid().is_root() {
+        if matches!(run.network, NetworkMode::Bridge | NetworkMode::Cni) {
+            error!("--network bridge and cni need root, use --network slirp");
+            return Err(Errcode::InvalidArgument("network").into());
+        }
+        if run.cgroup_parent.is_some() || run.resources != ResourceConfig::default() {
+            error!("Resource limits need root, cgroups are not used without it");
+            return Err(Errcode::InvalidArgument("resources").into());
+        }
+    }
+
+    Ok(())
 }
 
 /// log level setting.
-fn setting_log(log_level: LevelFilter) {
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            log_level,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            log_level,
-            Config::default(),
-            File::create("bowl.log").unwrap(),
-        ),
-    ])
-    .unwrap();
+/// ログファイルを作れない場合もエラーを表示できるようにterminalへのログは設定する
+fn setting_log(log_level: LevelFilter, log_file: &Path) -> anyhow::Result<()> {
+    let file = match log_file.parent() {
+        Some(dir) => create_dir_all(dir).and_then(|_| File::create(log_file)),
+        None => File::create(log_file),
+    };
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        log_level,
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )];
+    let result = match file {
+        Ok(file) => {
+            loggers.push(WriteLogger::new(log_level, Config::default(), file));
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+    if CombinedLogger::init(loggers).is_err() {
+        return Err(Errcode::LogError(0).into());
+    }
+    if let Err(e) = result {
+        error!("Unable to create the log file {:?}: {}", log_file, e);
+        return Err(Errcode::LogError(1).into());
+    }
+    Ok(())
 }
@@ -0,0 +1,476 @@
+use crate::cli::CniArg;
+use crate::errors::Errcode;
+use crate::network::PortMapping;
+use crate::state::lookup;
+
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{self};
+use log::{debug, error, info};
+
+//コンテナ側のinterface
+const CNI_IFNAME: &str = "eth0";
+//ADDからDELまでnetwork namespaceを保持するbind mount(<container dir>/netns)
+const NETNS_FILE: &str = "netns";
+//config listにcniVersionがない場合のversion
+const DEFAULT_CNI_VERSION: &str = "1.0.0";
+
+/// CNI network given on the command line.
+#[derive(Debug, Clone)]
+pub struct CniConfig {
+    /// Directory of the network configuration lists
+    pub conf_dir: PathBuf,
+    /// Directory of the plugin binaries
+    pub bin_dir: PathBuf,
+    /// Network to use, the first one of `conf_dir` if not given
+    pub network: Option<String>,
+    pub ports: Vec<PortMapping>,
+}
+
+/// CNI network of a container, saved in the container state.
+/// The configuration list is kept so that DEL works
+/// even if the file has been changed since ADD.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CniState {
+    pub network: String,
+    pub bin_dir: PathBuf,
+    /// Bind mount of the network namespace, it outlives the container process
+    pub netns: PathBuf,
+    pub ifname: String,
+    pub config: Value,
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+    /// Result of the last plugin on ADD
+    pub result: Value,
+}
+
+/// Load a network configuration list from `conf_dir`.
+/// `.conflist` files are used as is, `.conf` and `.json` files hold a single
+/// plugin and are wrapped into a list. Files are tried in name order.
+pub fn load_network_list(conf_dir: &Path, network: Option<&str>) -> anyhow::Result<Value> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(conf_dir) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+        Err(e) => {
+            error!("Unable to read CNI config directory {:?}: {}", conf_dir, e);
+            return Err(Errcode::NetworkError(28).into());
+        }
+    };
+    files.sort();
+
+    for file in files.iter() {
+        let ext = file.extension().and_then(|e| e.to_str());
+        if !matches!(ext, Some("conflist") | Some("conf") | Some("json")) {
+            continue;
+        }
+        let conf: Value = match fs::read(file).map(|data| serde_json::from_slice(&data)) {
+            Ok(Ok(conf)) => conf,
+            _ => {
+                error!("Skipping broken CNI config {:?}", file);
+                continue;
+            }
+        };
+        let list = if conf.get("plugins").is_some() {
+            conf
+        } else {
+            json!({
+                "cniVersion": conf["cniVersion"],
+                "name": conf["name"],
+                "plugins": [conf],
+            })
+        };
+        if network.is_none() || list["name"].as_str() == network {
+            debug!("Using CNI config {:?}", file);
+            return Ok(list);
+        }
+    }
+
+    error!(
+        "No CNI network {} in {:?}",
+        network.unwrap_or("config"),
+        conf_dir
+    );
+    Err(Errcode::NetworkError(29).into())
+}
+
+/// Configuration given to one plugin of the list on stdin.
+fn plugin_conf(state: &CniState, plugin: &Value, prev_result: Option<&Value>) -> Value {
+    let mut conf = plugin.clone();
+    let version = state.config["cniVersion"]
+        .as_str()
+        .unwrap_or(DEFAULT_CNI_VERSION);
+    conf["cniVersion"] = json!(version);
+    conf["name"] = json!(state.network);
+    if let Some(prev) = prev_result {
+        conf["prevResult"] = prev.clone();
+    }
+    // e.g. the portmap plugin
+    if plugin["capabilities"]["portMappings"].as_bool() == Some(true) {
+        let mappings: Vec<Value> = state
+            .ports
+            .iter()
+            .map(|p| {
+                json!({
+                    "hostPort": p.host_port,
+                    "containerPort": p.container_port,
+                    "protocol": p.protocol.to_string(),
+                })
+            })
+            .collect();
+        conf["runtimeConfig"] = json!({ "portMappings": mappings });
+    }
+    conf
+}
+
+/// Run one plugin and return what it printed, if anything.
+fn exec_plugin(
+    state: &CniState,
+    command: &str,
+    id: &str,
+    conf: &Value,
+) -> anyhow::Result<Option<Value>> {
+    let plugin = conf["type"].as_str().unwrap_or_default();
+    if plugin.is_empty() || plugin.contains('/') {
+        error!("Invalid CNI plugin type {:?}", conf["type"]);
+        return Err(Errcode::NetworkError(30).into());
+    }
+    debug!("CNI {} {}", command, plugin);
+
+    let child = Command::new(state.bin_dir.join(plugin))
+        .env("CNI_COMMAND", command)
+        .env("CNI_CONTAINERID", id)
+        .env("CNI_NETNS", &state.netns)
+        .env("CNI_IFNAME", &state.ifname)
+        .env("CNI_PATH", &state.bin_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Unable to run CNI plugin {}: {}", plugin, e);
+            return Err(Errcode::NetworkError(31).into());
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(conf.to_string().as_bytes()) {
+            error!("Unable to write CNI config to {}: {}", plugin, e);
+        }
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => {
+            error!("Unable to wait for CNI plugin {}: {}", plugin, e);
+            return Err(Errcode::NetworkError(31).into());
+        }
+    };
+
+    let stdout: Option<Value> = serde_json::from_slice(&output.stdout).ok();
+    if !output.status.success() {
+        let msg = stdout
+            .as_ref()
+            .and_then(|v| v["msg"].as_str())
+            .unwrap_or("no error message");
+        error!("CNI plugin {} {} failed: {}", plugin, command, msg);
+        return Err(Errcode::NetworkError(32).into());
+    }
+    Ok(stdout)
+}
+
+fn plugins(state: &CniState) -> Vec<Value> {
+    state.config["plugins"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Keep the network namespace of `pid` at `<container dir>/netns` with a bind mount,
+/// so that the plugins still find it on DEL after the container has exited.
+fn bind_netns(container_dir: &Path, pid: Pid) -> anyhow::Result<PathBuf> {
+    let netns = container_dir.join(NETNS_FILE);
+    if let Err(e) = File::create(&netns) {
+        error!("Unable to create {:?}: {}", netns, e);
+        return Err(Errcode::NetworkError(36).into());
+    }
+    let source = format!("/proc/{}/ns/net", pid.as_raw());
+    if let Err(e) = mount(
+        Some(source.as_str()),
+        &netns,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    ) {
+        error!("Unable to bind mount {} to {:?}: {:?}", source, netns, e);
+        let _ = fs::remove_file(&netns);
+        return Err(Errcode::NetworkError(36).into());
+    }
+    Ok(netns)
+}
+
+/// Release the network namespace kept by `bind_netns`.
+fn unbind_netns(netns: &Path) -> anyhow::Result<()> {
+    match umount2(netns, MntFlags::MNT_DETACH) {
+        // not mounted
+        Ok(_) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+        Err(e) => {
+            error!("Unable to unmount {:?}: {:?}", netns, e);
+            return Err(Errcode::NetworkError(37).into());
+        }
+    }
+    if let Err(e) = fs::remove_file(netns) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            error!("Unable to remove {:?}: {}", netns, e);
+            return Err(Errcode::NetworkError(37).into());
+        }
+    }
+    Ok(())
+}
+
+/// Connect the container `pid` with the plugins of the network list (ADD).
+/// Its network namespace is kept in `container_dir` until `cni_del`.
+pub fn cni_add(
+    config: &CniConfig,
+    container_dir: &Path,
+    id: &str,
+    pid: Pid,
+) -> anyhow::Result<CniState> {
+    let netns = bind_netns(container_dir, pid)?;
+    let result = add_network(config, &netns, id);
+    if result.is_err() {
+        let _ = unbind_netns(&netns);
+    }
+    result
+}
+
+/// Call ADD on the plugins for the network namespace at `netns`.
+/// The result of a plugin is given to the next one as `prevResult`.
+/// On failure the plugins are called with DEL to undo what was done.
+fn add_network(config: &CniConfig, netns: &Path, id: &str) -> anyhow::Result<CniState> {
+    let list = load_network_list(&config.conf_dir, config.network.as_deref())?;
+    let mut state = CniState {
+        network: list["name"].as_str().unwrap_or_default().to_string(),
+        bin_dir: config.bin_dir.clone(),
+        netns: netns.to_path_buf(),
+        ifname: CNI_IFNAME.to_string(),
+        config: list,
+        ports: config.ports.clone(),
+        result: Value::Null,
+    };
+
+    let mut result: Option<Value> = None;
+    for plugin in plugins(&state).iter() {
+        let conf = plugin_conf(&state, plugin, result.as_ref());
+        match exec_plugin(&state, "ADD", id, &conf) {
+            Ok(Some(r)) => result = Some(r),
+            Ok(None) => {}
+            Err(e) => {
+                state.result = result.unwrap_or_default();
+                if let Err(e) = del_network(&state, id) {
+                    error!("Unable to clean up CNI network: {}", e);
+                }
+                return Err(e);
+            }
+        }
+    }
+    state.result = result.unwrap_or_default();
+    info!("Container attached to CNI network {}", state.network);
+    Ok(state)
+}
+
+/// Disconnect the container (DEL) and release its network namespace.
+pub fn cni_del(state: &CniState, id: &str) -> anyhow::Result<()> {
+    let result = del_network(state, id);
+    unbind_netns(&state.netns).and(result)
+}
+
+/// Call DEL on the plugins in reverse order.
+/// Every plugin is called even if one of them fails.
+fn del_network(state: &CniState, id: &str) -> anyhow::Result<()> {
+    let prev = Some(&state.result).filter(|r| !r.is_null());
+    let mut result = Ok(());
+    for plugin in plugins(state).iter().rev() {
+        let conf = plugin_conf(state, plugin, prev);
+        if let Err(e) = exec_plugin(state, "DEL", id, &conf) {
+            result = Err(e);
+        }
+    }
+    result
+}
+
+/// Ask the plugins whether the network is still as expected (CHECK).
+pub fn cni_check(state: &CniState, id: &str) -> anyhow::Result<()> {
+    let prev = Some(&state.result).filter(|r| !r.is_null());
+    for plugin in plugins(state).iter() {
+        let conf = plugin_conf(state, plugin, prev);
+        exec_plugin(state, "CHECK", id, &conf)?;
+    }
+    Ok(())
+}
+
+/// Container addresses in a CNI result.
+pub fn result_ips(result: &Value) -> Vec<IpAddr> {
+    let ips = result["ips"].as_array().cloned().unwrap_or_default();
+    ips.iter()
+        .filter_map(|ip| ip["address"].as_str())
+        .filter_map(|addr| addr.split('/').next())
+        .filter_map(|addr| addr.parse().ok())
+        .collect()
+}
+
+/// `cni` sub command
+pub fn handle_cni_command(data_root: &Path, arg: CniArg) -> anyhow::Result<()> {
+    match arg {
+        CniArg::Check { container } => {
+            let state = lookup(data_root, &container)?;
+            match &state.cni {
+                Some(cni) => {
+                    cni_check(cni, &state.id)?;
+                    println!("{}", cni.network);
+                }
+                None => {
+                    error!("Container {} has no CNI network", container);
+                    return Err(Errcode::NetworkError(33).into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount::test_dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    //CNI_COMMANDを記録して、ADDの場合は結果を返すplugin
+    const FAKE_PLUGIN: &str = r#"#!/bin/sh
+conf=$(cat)
+echo "$CNI_COMMAND $CNI_IFNAME $CNI_CONTAINERID $(basename $0) $conf" >> "$(dirname $0)/calls"
+case "$conf" in *'"fail":true'*) echo '{"code":11,"msg":"failed"}'; exit 1;; esac
+if [ "$CNI_COMMAND" = ADD ]; then
+  echo '{"cniVersion":"1.0.0","ips":[{"address":"10.9.0.2/24"}]}'
+fi
+"#;
+
+    fn setup(conflist: &str) -> (PathBuf, CniConfig) {
+        let root = test_dir();
+        let conf_dir = root.join("net.d");
+        let bin_dir = root.join("bin");
+        fs::create_dir_all(&conf_dir).unwrap();
+        fs::create_dir_all(&bin_dir).unwrap();
+        for name in ["fake", "fake2"] {
+            let plugin = bin_dir.join(name);
+            fs::write(&plugin, FAKE_PLUGIN).unwrap();
+            fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(conf_dir.join("10-test.conflist"), conflist).unwrap();
+        let config = CniConfig {
+            conf_dir,
+            bin_dir,
+            network: None,
+            ports: vec!["8080:80".parse().unwrap()],
+        };
+        (root, config)
+    }
+
+    fn calls(root: &Path) -> Vec<String> {
+        fs::read_to_string(root.join("bin").join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn cni_add_check_del() {
+        let (root, config) = setup(
+            r#"{"cniVersion":"1.0.0","name":"testnet","plugins":[
+                {"type":"fake"},
+                {"type":"fake2","capabilities":{"portMappings":true}}]}"#,
+        );
+        let netns = root.join(NETNS_FILE);
+        let state = add_network(&config, &netns, "abc").unwrap();
+        assert_eq!(state.network, "testnet");
+        assert_eq!(state.netns, netns);
+        assert_eq!(
+            result_ips(&state.result),
+            vec!["10.9.0.2".parse::<IpAddr>().unwrap()]
+        );
+
+        let add = calls(&root);
+        assert!(add[0].starts_with("ADD eth0 abc fake {"));
+        assert!(add[0].contains(r#""name":"testnet""#));
+        // prevResult and port mappings go to the second plugin
+        assert!(add[1].contains(r#""prevResult":{"#));
+        assert!(add[1].contains(r#""hostPort":8080"#));
+
+        cni_check(&state, "abc").unwrap();
+        del_network(&state, "abc").unwrap();
+        let del: Vec<String> = calls(&root).into_iter().skip(4).collect();
+        assert!(del[0].starts_with("DEL eth0 abc fake2"));
+        assert!(del[1].starts_with("DEL eth0 abc fake "));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn cni_add_failure() {
+        let (root, config) = setup(
+            r#"{"cniVersion":"1.0.0","name":"testnet","plugins":[
+                {"type":"fake"},{"type":"fake2","fail":true}]}"#,
+        );
+        assert!(add_network(&config, &root.join(NETNS_FILE), "abc").is_err());
+        let commands: Vec<String> = calls(&root)
+            .iter()
+            .map(|l| l.split(' ').take(4).collect::<Vec<_>>().join(" "))
+            .collect();
+        // DEL is still called with every plugin after ADD failed
+        assert_eq!(
+            commands,
+            vec![
+                "ADD eth0 abc fake",
+                "ADD eth0 abc fake2",
+                "DEL eth0 abc fake2",
+                "DEL eth0 abc fake"
+            ]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn unbind_netns_not_mounted() {
+        let (root, _) = setup(r#"{"cniVersion":"1.0.0","name":"testnet","plugins":[]}"#);
+        let netns = root.join(NETNS_FILE);
+        File::create(&netns).unwrap();
+        unbind_netns(&netns).unwrap();
+        assert!(!netns.exists());
+        // already released
+        unbind_netns(&netns).unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn load_network_list_success() {
+        let (root, config) = setup(r#"{"cniVersion":"1.0.0","name":"testnet","plugins":[]}"#);
+        fs::write(
+            config.conf_dir.join("20-single.conf"),
+            r#"{"cniVersion":"0.4.0","name":"single","type":"fake"}"#,
+        )
+        .unwrap();
+        let list = load_network_list(&config.conf_dir, None).unwrap();
+        assert_eq!(list["name"], "testnet");
+        let list = load_network_list(&config.conf_dir, Some("single")).unwrap();
+        assert_eq!(list["plugins"][0]["type"], "fake");
+        assert_eq!(list["cniVersion"], "0.4.0");
+        assert!(load_network_list(&config.conf_dir, Some("missing")).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
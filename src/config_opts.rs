@@ -1,7 +1,13 @@
+use crate::capa::CapConfig;
 use crate::errors::Errcode;
 use crate::host::generate_host;
 use crate::ipc::create_sockets;
+use crate::mount::{BindMount, Propagation};
+use crate::notify::NotifyRule;
+use crate::resource::Ulimit;
+use crate::seccomp::{default_profile, SeccompFilter};
 
+use nix::unistd::geteuid;
 use std::ffi::CString;
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
@@ -18,10 +24,30 @@ pub struct ContainerOptions {
     pub mount_directory: PathBuf,
     //file descripter
     pub fd: RawFd,
+    ///コンテナの識別子(cgroup名などに使う)
+    pub id: String,
     ///ホスト名
     pub hostname: String,
+    ///NISドメイン名
+    pub domainname: Option<String>,
     //追加パス
-    pub add_paths: Vec<(PathBuf, PathBuf)>,
+    pub add_paths: Vec<BindMount>,
+    //root("/")のmount propagation
+    pub root_propagation: Propagation,
+    ///コンテナ内のプロセスのcapability
+    pub capabilities: CapConfig,
+    ///コンテナ内で読み込むseccomp filter
+    pub seccomp: SeccompFilter,
+    ///拒否する代わりにaudit logに記録する
+    pub seccomp_log: bool,
+    ///user notificationでsupervisorに送るsyscall
+    pub seccomp_notify: Vec<NotifyRule>,
+    //listenerをparentに渡すsocket(seccomp_notifyがある場合のみ)
+    pub notify_socket: Option<RawFd>,
+    ///exec前に設定するrlimit
+    pub rlimits: Vec<Ulimit>,
+    ///hostのrootなしで実行する(user namespaceをcloneで作成し、cgroupは使わない)
+    pub rootless: bool,
 }
 
 impl ContainerOptions {
@@ -29,7 +55,9 @@ impl ContainerOptions {
         command: String,
         uid: u32,
         mount_directory: PathBuf,
-        add_paths: Vec<(PathBuf, PathBuf)>,
+        add_paths: Vec<BindMount>,
+        id: String,
+        hostname: Option<String>,
     ) -> Result<(ContainerOptions, (RawFd, RawFd)), Errcode> {
         let _sockets = create_sockets()?;
 
@@ -41,6 +69,12 @@ impl ContainerOptions {
 
         let sockets = create_sockets()?;
 
+        //ホスト名を指定しない場合はランダムに生成する
+        let hostname = match hostname {
+            Some(hostname) => hostname,
+            None => generate_host()?,
+        };
+
         Ok((
             ContainerOptions {
                 path,
@@ -48,8 +82,19 @@ impl ContainerOptions {
                 uid,
                 mount_directory,
                 fd: sockets.1,
-                hostname: generate_host()?,
+                id,
+                hostname,
+                //ドメイン名,propagationなどはデフォルト値.必要なら作成後に変更する
+                domainname: None,
                 add_paths,
+                root_propagation: Propagation::Rprivate,
+                capabilities: CapConfig::default(),
+                seccomp: SeccompFilter::Profile(default_profile()),
+                seccomp_log: false,
+                seccomp_notify: vec![],
+                notify_socket: None,
+                rlimits: vec![],
+                rootless: !geteuid().is_root(),
             },
             sockets,
         ))
@@ -62,19 +107,36 @@ mod tests {
 
     const PATH: &str = "./test";
     const COMMAND: &str = "bash";
+    const ID: &str = "0123456789abcdef";
 
     #[test]
     fn config_new_success() {
-        let mut add_paths = vec![];
-        let frompath_1 = PathBuf::from("foo");
-        let mntpath_1 = PathBuf::from("bar");
-        add_paths.push((frompath_1, mntpath_1));
-        let frompath_2 = PathBuf::from("buzz");
-        let mntpath_2 = PathBuf::from("hoge");
-        add_paths.push((frompath_2, mntpath_2));
+        let add_paths = vec![
+            BindMount {
+                source: PathBuf::from("foo"),
+                target: PathBuf::from("bar"),
+                propagation: Propagation::Private,
+                idmap: false,
+                tree_fd: None,
+            },
+            BindMount {
+                source: PathBuf::from("buzz"),
+                target: PathBuf::from("hoge"),
+                propagation: Propagation::Rslave,
+                idmap: true,
+                tree_fd: None,
+            },
+        ];
 
         let pb = PathBuf::from(PATH);
-        let config = ContainerOptions::new(COMMAND.to_string(), 0, pb, add_paths);
+        let config = ContainerOptions::new(
+            COMMAND.to_string(),
+            0,
+            pb,
+            add_paths,
+            ID.to_string(),
+            Some("web".to_string()),
+        );
         let args = vec![CString::new("bash").unwrap()];
         println!("{:?}", config);
         match config {
@@ -83,10 +145,19 @@ mod tests {
                 assert_eq!(config.args, args);
                 assert_eq!(config.uid, 0);
                 assert_eq!(config.mount_directory, PathBuf::from(PATH));
-                assert_eq!(config.add_paths[0].0, PathBuf::from("foo"));
-                assert_eq!(config.add_paths[0].1, PathBuf::from("bar"));
-                assert_eq!(config.add_paths[1].0, PathBuf::from("buzz"));
-                assert_eq!(config.add_paths[1].1, PathBuf::from("hoge"));
+                assert_eq!(config.add_paths[0].source, PathBuf::from("foo"));
+                assert_eq!(config.add_paths[0].target, PathBuf::from("bar"));
+                assert_eq!(config.add_paths[1].source, PathBuf::from("buzz"));
+                assert_eq!(config.add_paths[1].target, PathBuf::from("hoge"));
+                assert_eq!(config.add_paths[1].propagation, Propagation::Rslave);
+                assert_eq!(config.root_propagation, Propagation::Rprivate);
+                assert_eq!(config.id, ID);
+                assert_eq!(config.hostname, "web");
+                assert_eq!(config.domainname, None);
+                assert_eq!(config.capabilities, CapConfig::default());
+                assert!(matches!(config.seccomp, SeccompFilter::Profile(_)));
+                assert!(!config.seccomp_log);
+                assert!(config.rlimits.is_empty());
                 assert!(row_fd1 > 0);
                 assert!(row_fd2 > 0);
             }
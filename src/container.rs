@@ -1,51 +1,208 @@
+use crate::audit::Recorder;
+use crate::capa::{recv_capa_check, report_exec_capa, CapConfig};
 use crate::child::create_child_process;
-use crate::cli::BowlArg;
+use crate::cli::RunArg;
+use crate::cni::{cni_add, cni_del, result_ips, CniConfig};
 use crate::config_opts::ContainerOptions;
 use crate::errors::Errcode;
-use crate::mount::clean_mount;
-use crate::namespace::handle_child_uid_map;
+use crate::host::{parse_extra_host, write_etc_files, write_hosts, NameConfig};
+use crate::ipc::{create_exec_pipe, create_sockets, recv_fd, wait_exec};
+use crate::mount::{clean_mount, idmapped_tree, BindMount};
+use crate::namespace::{create_mapped_userns, handle_child_uid_map, handle_rootless_id_map};
+use crate::network::{
+    release_ports, reserve_ports, setup_bridge_network, teardown_bridge_network, BridgeConfig,
+    NetworkMode,
+};
+use crate::notify::{start_supervisor, NotifyRule};
 use crate::resource::clean_cgroups;
-use crate::resource::restrict_resources;
+use crate::resource::{cgroup_path, container_rlimits, restrict_resources, ResourceConfig};
+use crate::seccomp::{syscall_name, write_profile, RuleContext, SeccompFilter, SeccompProfile};
+use crate::slirp::{start_slirp, Slirp, SlirpConfig};
+use crate::state::{container_dir, create_container_dir, lock_container, lookup, ContainerState};
+use crate::volume::volume_mount;
 
+use nix::sys::signal::{kill, Signal};
 use nix::sys::wait::waitpid;
 use nix::unistd::close;
 use nix::unistd::Pid;
+use std::fs::{remove_dir_all, File};
+use std::net::IpAddr;
 use std::os::unix::io::RawFd;
-
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
 
 use anyhow::{self};
-use log::{debug, error};
+use log::{debug, error, info, warn};
 
 pub struct BowlContainer {
-    sockets: (RawFd, RawFd),
+    //child process側(.1)はclone後に閉じる. child processが終了するとEOFになる
+    sockets: (RawFd, Option<RawFd>),
     config: ContainerOptions,
     child_pid: Option<Pid>,
+    //state.json,hostname,hosts,resolv.confなどを置くdirectory
+    container_dir: PathBuf,
+    data_root: PathBuf,
+    state: ContainerState,
+    name_config: NameConfig,
+    //--network bridgeの場合のみ
+    bridge: Option<BridgeConfig>,
+    //--network slirpの場合のみ
+    slirp_config: Option<SlirpConfig>,
+    //実行中のslirp4netns
+    slirp: Option<Slirp>,
+    //--network cniの場合のみ
+    cni: Option<CniConfig>,
+    //cgroupのpath(--cgroup-parent/<id>)と制限
+    cgroup: String,
+    resources: ResourceConfig,
+    //--seccomp-recordの場合のみ. syscallの記録と書き出し先
+    recorder: Option<(Recorder, PathBuf)>,
+    //--seccomp-notifyの場合のみ. notificationを処理するthread
+    supervisor: Option<JoinHandle<()>>,
+    //--publishでhostのportを予約した
+    published: bool,
+    //--nameの場合のみ. コンテナを削除するまで名前の予約を保持する
+    _name_lock: Option<File>,
 }
 
 impl BowlContainer {
     ///ContainerOptionsのCLI引数から構造体を作成する
-    pub fn new(args: BowlArg) -> anyhow::Result<BowlContainer> {
+    pub fn new(args: RunArg, data_root: &Path) -> anyhow::Result<BowlContainer> {
         let mut add_paths = vec![];
         for ap_pair in args.add_paths.iter() {
-            let mut pair = ap_pair.to_str().unwrap().split(':');
-            let frompath = PathBuf::from(pair.next().unwrap())
-                .canonicalize()
-                .expect("Cannot canonicalize path")
-                .to_path_buf();
-            let mntpath = PathBuf::from(pair.next().unwrap())
-                .strip_prefix("/")
-                .expect("Cannot strip prefix from path")
-                .to_path_buf();
-            add_paths.push((frompath, mntpath));
-        }
-
-        let (config, sockets) =
-            ContainerOptions::new(args.command, args.uid, args.mount_directory, add_paths)?;
+            add_paths.push(BindMount::parse(ap_pair.to_str().unwrap())?);
+        }
+        let mut volumes = vec![];
+        for volume in args.volumes.iter() {
+            let (name, bind) = volume_mount(data_root, volume, args.uid)?;
+            volumes.push(name);
+            add_paths.push(bind);
+        }
+
+        let mut name_config = NameConfig {
+            dns: args.dns,
+            dns_search: args.dns_search,
+            ..Default::default()
+        };
+        for host in args.add_host.iter() {
+            name_config.extra_hosts.push(parse_extra_host(host)?);
+        }
+
+        let (mut config, sockets) = ContainerOptions::new(
+            args.command.clone(),
+            args.uid,
+            args.mount_directory.clone(),
+            add_paths,
+            args.id.clone(),
+            //ホスト名を指定しない場合は名前を使う
+            args.hostname.or_else(|| args.name.clone()),
+        )?;
+        config.root_propagation = args.root_propagation;
+        config.capabilities = CapConfig::new(
+            &args.cap_add,
+            &args.cap_drop,
+            &args.cap_ambient,
+            &args.cap_sets,
+        )?;
+        config.capabilities.no_new_privs = args.no_new_privs;
+        for bit in args.securebits.iter() {
+            config.capabilities.securebits |= bit.flag();
+        }
+        config.capabilities.check_exec(&args.cap_sets, args.uid)?;
+        //profileの誤りはclone前に報告する
+        config.seccomp = args.seccomp.filter()?;
+        config.seccomp_log = args.seccomp_log;
+        config.seccomp_notify = args.seccomp_notify;
+        //記録中はすべてのsyscallを拒否するfilterをlogに書き換えて使う
+        if args.seccomp_record.is_some() {
+            config.seccomp = SeccompFilter::Profile(SeccompProfile::allowlist(vec![]));
+            config.seccomp_log = true;
+        }
+        if let SeccompFilter::Profile(profile) = &config.seccomp {
+            profile.build(&RuleContext::new(
+                config.capabilities.bounding,
+                &config.seccomp_notify,
+            ))?;
+        }
+        config.rlimits = container_rlimits(&args.ulimit);
+        config.domainname = args.domainname;
+
+        let container_dir = container_dir(data_root, &config.id);
+        let etc_files = write_etc_files(&container_dir, &config.hostname, &name_config)?;
+        config.add_paths.extend(etc_files);
+
+        //portの確認と予約は同じlockの中で行う
+        let published = !args.publish.is_empty();
+        if published {
+            reserve_ports(data_root, &args.id, &args.publish)?;
+        }
+
+        let (mut bridge, mut slirp_config, mut cni) = (None, None, None);
+        match args.network {
+            NetworkMode::Bridge => {
+                bridge = Some(BridgeConfig {
+                    bridge: args.bridge,
+                    subnet: args.subnet,
+                    ports: args.publish,
+                })
+            }
+            NetworkMode::Slirp => {
+                slirp_config = Some(SlirpConfig {
+                    ports: args.publish,
+                    join_userns: config.rootless,
+                })
+            }
+            NetworkMode::Cni => {
+                cni = Some(CniConfig {
+                    conf_dir: args.cni_conf_dir,
+                    bin_dir: args.cni_bin_dir,
+                    network: args.cni_network,
+                    ports: args.publish,
+                })
+            }
+            NetworkMode::None => {}
+        }
+
+        let cgroup = cgroup_path(args.cgroup_parent.as_deref(), &args.id);
+        let mut state = ContainerState::new(
+            args.id,
+            args.name,
+            config.hostname.clone(),
+            args.command,
+            args.mount_directory,
+        );
+        //rootlessの場合はcgroupを使わないので保存しない
+        if !config.rootless {
+            state.cgroup = Some(cgroup.clone());
+        }
+        //使用中のvolumeはvolume rmで削除できないようにする
+        state.volumes = volumes;
+        state.save(data_root)?;
+
+        //コンテナの作成前から記録を始める
+        let recorder = match args.seccomp_record {
+            Some(path) => Some((Recorder::start()?, path)),
+            None => None,
+        };
+
         Ok(BowlContainer {
-            sockets,
+            sockets: (sockets.0, Some(sockets.1)),
             config,
             child_pid: None,
+            container_dir,
+            data_root: data_root.to_path_buf(),
+            state,
+            name_config,
+            bridge,
+            slirp_config,
+            slirp: None,
+            cni,
+            cgroup,
+            resources: args.resources,
+            recorder,
+            supervisor: None,
+            published,
+            _name_lock: args.name_lock,
         })
     }
 
@@ -55,49 +212,332 @@ impl BowlContainer {
         //containerはhandle)child_uid_mapを実行して
         //シグナルが操作を実行するのを待つ
         debug!("create container start");
-        let pid = create_child_process(self.config.clone())?;
-        restrict_resources(&self.config.hostname, pid)?;
-        handle_child_uid_map(pid, self.sockets.0)?;
+        self.prepare_idmapped_mounts()?;
+        let (exec_r, exec_w) = create_exec_pipe()?;
+        //--seccomp-notifyの場合、child processがlistenerを送ってくるsocket
+        let notify = match self.config.seccomp_notify.is_empty() {
+            true => None,
+            false => match create_sockets() {
+                Ok(sockets) => Some(sockets),
+                Err(e) => {
+                    let _ = close(exec_r);
+                    let _ = close(exec_w);
+                    return Err(e);
+                }
+            },
+        };
+        self.config.notify_socket = notify.map(|(_, notify_w)| notify_w);
+        let pid = create_child_process(self.config.clone());
+        //child processは自分のcopyを持っているのでparent側は閉じる
+        self.close_tree_fds();
+        if let Some(fd) = self.sockets.1.take() {
+            let _ = close(fd);
+        }
+        let _ = close(exec_w);
+        if let Some((_, notify_w)) = notify {
+            let _ = close(notify_w);
+        }
+        let pid = match pid {
+            Ok(pid) => pid,
+            Err(e) => {
+                let _ = close(exec_r);
+                if let Some((notify_r, _)) = notify {
+                    let _ = close(notify_r);
+                }
+                return Err(e);
+            }
+        };
+        if let Some((recorder, _)) = &self.recorder {
+            recorder.watch(pid);
+        }
+        let result = self
+            .setup_child(pid)
+            .and_then(|_| {
+                recv_child_setup(
+                    self.sockets.0,
+                    notify.map(|(notify_r, _)| notify_r),
+                    &self.config.seccomp_notify,
+                    !self.config.capabilities.no_new_privs,
+                )
+            })
+            .map(|supervisor| self.supervisor = supervisor);
+        if let Some((notify_r, _)) = notify {
+            let _ = close(notify_r);
+        }
+        //execまでの設定に失敗した場合はexec_rを閉じる
+        //child processはparentからの合図を待っているので止める
+        if let Err(e) = result {
+            let _ = close(exec_r);
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+            return Err(e);
+        }
+        if let Err(e) = wait_exec(exec_r) {
+            let _ = kill(pid, Signal::SIGKILL);
+            let _ = waitpid(pid, None);
+            return Err(e);
+        }
+        //execveでcapabilityが再計算されるので、exec後の値をログに出す
+        //command自身がexec直後に変更することもあるのでコンテナは止めない
+        report_exec_capa(pid, &self.config.capabilities, self.config.uid);
         self.child_pid = Some(pid);
+        self.state.pid = Some(pid.as_raw());
+        self.state.save(&self.data_root)?;
         debug!("create container finished");
         Ok(())
     }
 
+    ///child processのcgroup,network,uid mapを設定する
+    fn setup_child(&mut self, pid: Pid) -> anyhow::Result<()> {
+        //rootlessの場合はcgroupを使わない. child processはmountの前にuid mapを待っている
+        match self.config.rootless {
+            true => handle_rootless_id_map(pid, self.sockets.0, self.config.uid)?,
+            false => restrict_resources(&self.cgroup, pid, &self.resources)?,
+        }
+        //child processがuid mapを待っている間にnetworkを設定する
+        let mut ips = vec![];
+        if let Some(bridge) = &self.bridge {
+            let network = setup_bridge_network(&self.data_root, bridge, &self.config.id, pid)?;
+            ips.push(IpAddr::V4(network.address));
+            self.state.network = Some(network);
+        }
+        if let Some(slirp_config) = &self.slirp_config {
+            let slirp = start_slirp(&self.container_dir, slirp_config, pid)?;
+            ips.push(IpAddr::V4(slirp.state.address));
+            self.state.slirp = Some(slirp.state.clone());
+            self.slirp = Some(slirp);
+        }
+        if let Some(cni) = &self.cni {
+            let cni = cni_add(cni, &self.container_dir, &self.config.id, pid)?;
+            ips.extend(result_ips(&cni.result));
+            self.state.cni = Some(cni);
+        }
+        if !ips.is_empty() {
+            self.state.save(&self.data_root)?;
+            write_hosts(
+                &self.container_dir,
+                &self.config.hostname,
+                &ips,
+                &self.name_config,
+            )?;
+        }
+        handle_child_uid_map(pid, self.sockets.0)
+    }
+
+    ///idmapを指定されたadd_pathsについて、
+    ///コンテナのUID/GIDマッピングでidmapしたmount treeをclone前に作成する
+    fn prepare_idmapped_mounts(&mut self) -> anyhow::Result<()> {
+        if !self.config.add_paths.iter().any(|bind| bind.idmap) {
+            return Ok(());
+        }
+        if self.config.rootless {
+            error!("idmapped mounts need root");
+            return Err(Errcode::InvalidArgument("add-path").into());
+        }
+
+        let userns = create_mapped_userns()?;
+        let mut result = Ok(());
+        for bind in self.config.add_paths.iter_mut().filter(|bind| bind.idmap) {
+            match idmapped_tree(&bind.source, userns) {
+                Ok(fd) => bind.tree_fd = Some(fd),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let _ = close(userns);
+        if result.is_err() {
+            self.close_tree_fds();
+        }
+        result
+    }
+
+    fn close_tree_fds(&mut self) {
+        for bind in self.config.add_paths.iter_mut() {
+            if let Some(fd) = bind.tree_fd.take() {
+                let _ = close(fd);
+            }
+        }
+    }
+
+    ///--seccomp-recordの場合、記録したsyscallだけを許可するprofileを書き出す
+    pub fn save_seccomp_record(&mut self) -> anyhow::Result<()> {
+        let (recorder, path) = match self.recorder.take() {
+            Some(recorder) => recorder,
+            None => return Ok(()),
+        };
+        let mut names = vec![];
+        for (arch, nr) in recorder.stop() {
+            match syscall_name(arch, nr) {
+                Some(name) => names.push(name),
+                None => warn!("Unknown syscall {} (arch {:x})", nr, arch),
+            }
+        }
+        write_profile(&path, &SeccompProfile::allowlist(names))?;
+        info!("Seccomp profile written to {:?}", path);
+        Ok(())
+    }
+
     ///exit前に呼び出して状態をcleanにする
     pub fn clean(&mut self) -> anyhow::Result<()> {
         debug!("cleanup container");
-        clean_mount(&self.config.mount_directory)?;
+        //途中で失敗しても残りを片付けるため,すべて実行してから最初のerrorを返す
+        let mut errors: Vec<anyhow::Error> = vec![];
+
+        if let Err(e) = clean_mount(&self.config.mount_directory) {
+            errors.push(e.into());
+        }
 
         if let Err(e) = close(self.sockets.0) {
             error!("Unable to close write socket: {:?}", e);
-            return Err(Errcode::SocketError(3).into());
+            errors.push(Errcode::SocketError(3).into());
         }
 
-        if let Err(e) = close(self.sockets.1) {
+        if let Some(Err(e)) = self.sockets.1.take().map(close) {
             error!("Unable to close read socket: {:?}", e);
-            return Err(Errcode::SocketError(4).into());
+            errors.push(Errcode::SocketError(4).into());
         }
 
-        if let Err(e) = clean_cgroups(&self.config.hostname) {
-            log::error!("Cgroups cleaning failed: {}", e);
-            return Err(e);
+        if let Some(slirp) = self.slirp.take() {
+            if let Err(e) = slirp.stop() {
+                error!("Network cleaning failed: {}", e);
+                errors.push(e);
+            }
         }
 
-        Ok(())
+        //コンテナのプロセスがすべて終了するとsupervisorも終了する
+        if let Some(supervisor) = self.supervisor.take() {
+            let _ = supervisor.join();
+        }
+
+        errors.extend(clean_state(&self.data_root, &mut self.state));
+
+        if self.published {
+            if let Err(e) = release_ports(&self.data_root, &self.config.id) {
+                error!("Unable to release the published ports: {}", e);
+                errors.push(e);
+            }
+        }
+
+        if let Err(e) = remove_dir_all(&self.container_dir) {
+            error!("Unable to remove {:?}: {:?}", self.container_dir, e);
+            errors.push(Errcode::ContainerError(2).into());
+        }
+
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+///child processがexecveの前に送ってくるcapabilityの確認結果と,
+///--seccomp-notifyの場合はseccompのlistenerを受け取る
+///listenerを送った後のchild processのsyscallもsupervisorの応答を待つので,
+///child processが送る順に受け取り,listenerを受け取ったらすぐにsupervisorを始める
+///no_new_privsなしの場合はcapabilityを落とす前にseccompを読み込むのでlistenerが先になる
+fn recv_child_setup(
+    sock: RawFd,
+    notify: Option<RawFd>,
+    rules: &[NotifyRule],
+    listener_first: bool,
+) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let recv_listener = || match notify {
+        Some(notify_r) => recv_fd(notify_r)
+            .and_then(|listener| start_supervisor(listener, rules.to_vec()))
+            .map(Some),
+        None => Ok(None),
+    };
+    if listener_first {
+        let supervisor = recv_listener()?;
+        recv_capa_check(sock)?;
+        Ok(supervisor)
+    } else {
+        recv_capa_check(sock)?;
+        recv_listener()
     }
+}
 
-    pub fn clean_exit(&mut self) -> anyhow::Result<()> {
-        clean_mount(&self.config.mount_directory)?;
+///stateに記録されたnetworkとcgroupを片付ける(cleanとrmで共通)
+///途中で失敗しても残りを片付けるため,すべて実行してerrorを返す
+fn clean_state(data_root: &Path, state: &mut ContainerState) -> Vec<anyhow::Error> {
+    let mut errors: Vec<anyhow::Error> = vec![];
 
-        Ok(())
+    if let Some(network) = state.network.take() {
+        if let Err(e) = teardown_bridge_network(data_root, &network, &state.id) {
+            error!("Network cleaning failed: {}", e);
+            errors.push(e);
+        }
+    }
+
+    if let Some(cni) = state.cni.take() {
+        if let Err(e) = cni_del(&cni, &state.id) {
+            error!("Network cleaning failed: {}", e);
+            errors.push(e);
+        }
+    }
+
+    //slirp4netnsはbowl-rsが終了するとexit_fdが閉じられて終了する
+    state.slirp = None;
+
+    match state.cgroup.take() {
+        Some(cgroup) => {
+            if let Err(e) = clean_cgroups(&cgroup) {
+                log::error!("Cgroups cleaning failed: {}", e);
+                errors.push(e);
+            }
+        }
+        None => debug!("No cgroup to clean without root"),
     }
+
+    errors
+}
+
+///rmから引数を取得して,bowl-rsが強制終了されて残ったコンテナを削除する
+pub fn handle_rm(data_root: &Path, containers: &[String]) -> anyhow::Result<()> {
+    for id_or_name in containers.iter() {
+        let mut state = lookup(data_root, id_or_name)?;
+        if state.is_running() {
+            error!("Container {} is running", state.short_id());
+            return Err(Errcode::StateError(11).into());
+        }
+        //作成中や終了処理中のコンテナはbowl-rsがlockしている
+        let dir = container_dir(data_root, &state.id);
+        let _lock = lock_container(&dir)?;
+
+        let mut errors = clean_state(data_root, &mut state);
+        if let Err(e) = release_ports(data_root, &state.id) {
+            error!("Unable to release the published ports: {}", e);
+            errors.push(e);
+        }
+        if let Err(e) = remove_dir_all(&dir) {
+            error!("Unable to remove {:?}: {:?}", dir, e);
+            errors.push(Errcode::ContainerError(2).into());
+        }
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+        println!("{}", state.id);
+    }
+    Ok(())
 }
 
 ///startから引数を取得してContainer作成から終了まですべてを処理
-pub fn start(args: BowlArg) -> anyhow::Result<()> {
-    let mut container = BowlContainer::new(args)?;
+pub fn start(args: RunArg, data_root: &Path) -> anyhow::Result<()> {
+    //コンテナを削除するまでdirectoryをlockして,rmで削除されないようにする
+    let (container_dir, _lock) = create_container_dir(data_root, &args.id)?;
+    let mut container = match BowlContainer::new(args, data_root) {
+        Ok(container) => container,
+        Err(e) => {
+            //作成途中のdirectoryを削除(作成後はcleanで削除する)
+            let _ = remove_dir_all(container_dir);
+            return Err(e);
+        }
+    };
+    info!("Container {} created", container.state.id);
     debug!(
-        "Container sockets: ({}, {})",
+        "Container sockets: ({}, {:?})",
         container.sockets.0, container.sockets.1
     );
     if let Err(e1) = container.create_process() {
@@ -106,11 +546,13 @@ pub fn start(args: BowlArg) -> anyhow::Result<()> {
             error!("Error while create container: {:?}", e2);
             Errcode::CleanupFailure(e2)
         })?;
+        return Err(e1);
     }
     debug!("Container child PID: {:?}", container.child_pid);
     wait(container.child_pid)?;
+    let recorded = container.save_seccomp_record();
     debug!("Success, cleanup and exit");
-    container.clean()
+    container.clean().and(recorded)
 }
 
 ///child processを作成して終了するまでwait
@@ -124,3 +566,49 @@ pub fn wait(pid: Option<Pid>) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::{send_boolean, send_fd};
+    use crate::syscalls::load_notify_filter;
+    use nix::unistd::getppid;
+    use std::thread;
+
+    //child processはlistenerを送った後,通知されるsyscallで止まってから結果を送る
+    #[test]
+    fn recv_child_setup_listener_first() {
+        let (sock, child_sock) = create_sockets().unwrap();
+        let (notify_r, notify_w) = create_sockets().unwrap();
+        let rules = vec!["getppid".parse::<NotifyRule>().unwrap()];
+        let child_rules = rules.clone();
+        let child = thread::spawn(move || {
+            // seccomp filters and no_new_privs only apply to this thread
+            capctl::prctl::set_no_new_privs().unwrap();
+            let listener = load_notify_filter(&child_rules).unwrap();
+            send_fd(notify_w, listener).unwrap();
+            close(listener).unwrap();
+            // waits for the supervisor
+            getppid();
+            send_boolean(child_sock, false).unwrap();
+        });
+        let supervisor = recv_child_setup(sock, Some(notify_r), &rules, true).unwrap();
+        child.join().unwrap();
+        // stops once the thread using the filter is gone
+        supervisor.unwrap().join().unwrap();
+        for fd in [sock, child_sock, notify_r, notify_w] {
+            close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn recv_child_setup_failed_check() {
+        let (sock, child_sock) = create_sockets().unwrap();
+        send_boolean(child_sock, true).unwrap();
+        assert!(recv_child_setup(sock, None, &[], false).is_err());
+        // closed before sending the result
+        close(child_sock).unwrap();
+        assert!(recv_child_setup(sock, None, &[], false).is_err());
+        close(sock).unwrap();
+    }
+}
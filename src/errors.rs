@@ -37,4 +37,16 @@ pub enum Errcode {
 
     #[error("Resources Error")]
     ResourcesError(u8),
+
+    #[error("Volume Error")]
+    VolumeError(u8),
+
+    #[error("State Error")]
+    StateError(u8),
+
+    #[error("Network Error")]
+    NetworkError(u8),
+
+    #[error("Log Error")]
+    LogError(u8),
 }
@@ -1,7 +1,26 @@
 use crate::errors::Errcode;
+use crate::mount::{BindMount, MountOptions};
 use anyhow::{self};
 use log::{debug, error};
 use nix::unistd::sethostname;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+//hostのresolv.confにnameserverが残らなかった場合に使う
+const DEFAULT_DNS: [&str; 2] = ["8.8.8.8", "8.8.4.4"];
+
+///コンテナの/etc/hostsと/etc/resolv.confの設定
+#[derive(Debug, Clone, Default)]
+pub struct NameConfig {
+    ///--add-hostで追加するエントリ
+    pub extra_hosts: Vec<(String, IpAddr)>,
+    ///nameserver(空ならhostのresolv.confを使う)
+    pub dns: Vec<IpAddr>,
+    ///search domain(空ならhostのresolv.confを使う)
+    pub dns_search: Vec<String>,
+}
 
 ///ランダムなホスト名を生成
 pub fn generate_host() -> anyhow::Result<String> {
@@ -10,6 +29,30 @@ pub fn generate_host() -> anyhow::Result<String> {
     Ok(format!("{}-{}", host1, host2))
 }
 
+//HOST_NAME_MAX
+const HOSTNAME_MAX_LEN: usize = 64;
+
+///RFC 1123のホスト名かどうかを確認.
+///ラベルは英数字とハイフンの1-63文字で、先頭と末尾はハイフン以外.
+///ラベルは'.'で区切り、全体でHOST_NAME_MAX(64)文字まで.
+///ドメイン名も同じ規則で確認し、argはエラーで報告する引数名
+pub fn validate_hostname(name: &str, arg: &'static str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= HOSTNAME_MAX_LEN
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid {
+        error!("Invalid {} {:?}", arg, name);
+        return Err(Errcode::InvalidArgument(arg).into());
+    }
+    Ok(())
+}
+
 ///ホスト名をセット
 pub fn set_container_hostname(hostname: &String) -> anyhow::Result<()> {
     match sethostname(hostname) {
@@ -23,6 +66,150 @@ pub fn set_container_hostname(hostname: &String) -> anyhow::Result<()> {
         }
     }
 }
+///`<hostname>:<ip>`形式の--add-hostをparse
+pub fn parse_extra_host(spec: &str) -> anyhow::Result<(String, IpAddr)> {
+    //IPv6アドレスにも':'が含まれるので最初の':'で分割する
+    match spec.split_once(':') {
+        //名前はそのまま/etc/hostsに書き込むので改行などを含まないか確認する
+        Some((name, ip)) if !name.is_empty() => {
+            validate_hostname(name, "add_host")?;
+            match ip.parse::<IpAddr>() {
+                Ok(ip) => Ok((name.to_string(), ip)),
+                Err(_) => Err(Errcode::InvalidArgument("add_host").into()),
+            }
+        }
+        _ => Err(Errcode::InvalidArgument("add_host").into()),
+    }
+}
+
+///`/etc/hosts`の内容を生成
+///コンテナのIPがない場合はhostnameを127.0.1.1で解決できるようにする
+pub fn hosts_content(hostname: &str, ips: &[IpAddr], config: &NameConfig) -> String {
+    let mut hosts =
+        String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
+    if ips.is_empty() {
+        hosts.push_str(&format!("127.0.1.1\t{}\n", hostname));
+    }
+    for ip in ips.iter() {
+        hosts.push_str(&format!("{}\t{}\n", ip, hostname));
+    }
+    for (name, ip) in config.extra_hosts.iter() {
+        hosts.push_str(&format!("{}\t{}\n", ip, name));
+    }
+    hosts
+}
+
+///`/etc/resolv.conf`の内容を生成
+///指定がない項目はhostのresolv.confから引き継ぐ.
+///loopbackのnameserverはコンテナのnetwork namespaceからは届かないので除く
+pub fn resolv_content(config: &NameConfig, host_resolv: &str) -> String {
+    let mut nameservers: Vec<String> = config.dns.iter().map(|ip| ip.to_string()).collect();
+    let mut search: Vec<String> = config.dns_search.clone();
+    let mut options = vec![];
+
+    for line in host_resolv.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") if config.dns.is_empty() => {
+                if let Some(Ok(ip)) = fields.next().map(|ip| ip.parse::<IpAddr>()) {
+                    if !ip.is_loopback() {
+                        nameservers.push(ip.to_string());
+                    }
+                }
+            }
+            Some("search") | Some("domain") if config.dns_search.is_empty() => {
+                search.extend(fields.map(|s| s.to_string()));
+            }
+            Some("options") => options.extend(fields.map(|s| s.to_string())),
+            _ => {}
+        }
+    }
+    if nameservers.is_empty() {
+        nameservers = DEFAULT_DNS.iter().map(|s| s.to_string()).collect();
+    }
+
+    let mut resolv = String::new();
+    for ns in nameservers.iter() {
+        resolv.push_str(&format!("nameserver {}\n", ns));
+    }
+    if !search.is_empty() {
+        resolv.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    if !options.is_empty() {
+        resolv.push_str(&format!("options {}\n", options.join(" ")));
+    }
+    resolv
+}
+
+fn write_file(path: &Path, content: &str) -> anyhow::Result<()> {
+    if let Err(e) = fs::write(path, content) {
+        error!("Unable to write {}: {}", path.to_str().unwrap(), e);
+        return Err(Errcode::MountError(10).into());
+    }
+    Ok(())
+}
+
+///コンテナのIPが決まった後などに`/etc/hosts`を書き直す.
+///bind mountされているのでコンテナ内にもそのまま反映される
+pub fn write_hosts(
+    dir: &Path,
+    hostname: &str,
+    ips: &[IpAddr],
+    config: &NameConfig,
+) -> anyhow::Result<()> {
+    write_file(&dir.join("hosts"), &hosts_content(hostname, ips, config))
+}
+
+///dirにhostname,hosts,resolv.confを生成して、
+///コンテナの/etc以下にbind mountするためのBindMountを返す
+pub fn write_etc_files(
+    dir: &Path,
+    hostname: &str,
+    config: &NameConfig,
+) -> anyhow::Result<Vec<BindMount>> {
+    if let Err(e) = fs::create_dir_all(dir) {
+        error!(
+            "Unable to create directory {}: {}",
+            dir.to_str().unwrap(),
+            e
+        );
+        return Err(Errcode::MountError(11).into());
+    }
+
+    let host_resolv = fs::read_to_string(HOST_RESOLV_CONF).unwrap_or_default();
+    write_file(&dir.join("hostname"), &format!("{}\n", hostname))?;
+    write_hosts(dir, hostname, &[], config)?;
+    write_file(
+        &dir.join("resolv.conf"),
+        &resolv_content(config, &host_resolv),
+    )?;
+
+    let mut binds = vec![];
+    for name in ["hostname", "hosts", "resolv.conf"] {
+        binds.push(BindMount::new(
+            dir.join(name),
+            &format!("/etc/{}", name),
+            MountOptions::default(),
+        )?);
+    }
+    Ok(binds)
+}
+
+///NISドメイン名をセット
+pub fn set_container_domainname(domainname: &str) -> anyhow::Result<()> {
+    let res = unsafe { libc::setdomainname(domainname.as_ptr().cast(), domainname.len()) };
+    if res != 0 {
+        error!(
+            "Can not set domainname {}: {}",
+            domainname,
+            nix::errno::Errno::last()
+        );
+        return Err(Errcode::HostnameError(3).into());
+    }
+    debug!("Container domainname is now {}", domainname);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -33,4 +220,74 @@ mod tests {
         println!("{:?}", host);
         assert!(host.unwrap().len() > 3);
     }
+
+    #[test]
+    fn validate_hostname_success() {
+        assert!(validate_hostname("web", "hostname").is_ok());
+        assert!(validate_hostname("web-1.example.com", "hostname").is_ok());
+        assert!(validate_hostname("1web", "hostname").is_ok());
+        assert!(validate_hostname("", "hostname").is_err());
+        assert!(validate_hostname("-web", "hostname").is_err());
+        assert!(validate_hostname("web-", "hostname").is_err());
+        assert!(validate_hostname("web..local", "hostname").is_err());
+        assert!(validate_hostname("web_1", "hostname").is_err());
+        assert!(validate_hostname(&"a".repeat(64), "hostname").is_err());
+        assert!(validate_hostname(&["a".repeat(32), "b".repeat(31)].join("."), "hostname").is_ok());
+        assert!(
+            validate_hostname(&["a".repeat(32), "b".repeat(32)].join("."), "hostname").is_err()
+        );
+        let err = validate_hostname("example_com", "domainname").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid Argument : domainname");
+    }
+
+    #[test]
+    fn parse_extra_host_success() {
+        let (name, ip) = parse_extra_host("db:10.0.0.2").unwrap();
+        assert_eq!(name, "db");
+        assert_eq!(ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        let (_, ip) = parse_extra_host("db6:fd00::2").unwrap();
+        assert_eq!(ip, "fd00::2".parse::<IpAddr>().unwrap());
+        assert!(parse_extra_host("db").is_err());
+        assert!(parse_extra_host(":10.0.0.2").is_err());
+        assert!(parse_extra_host("db\n10.0.0.3 evil:10.0.0.2").is_err());
+        assert!(parse_extra_host("db evil:10.0.0.2").is_err());
+    }
+
+    #[test]
+    fn hosts_content_success() {
+        let config = NameConfig {
+            extra_hosts: vec![("db".to_string(), "10.0.0.2".parse().unwrap())],
+            ..Default::default()
+        };
+        let hosts = hosts_content("foo-bar", &[], &config);
+        assert!(hosts.starts_with("127.0.0.1\tlocalhost\n"));
+        assert!(hosts.contains("127.0.1.1\tfoo-bar\n"));
+        assert!(hosts.ends_with("10.0.0.2\tdb\n"));
+
+        let hosts = hosts_content("foo-bar", &["172.18.0.2".parse().unwrap()], &config);
+        assert!(!hosts.contains("127.0.1.1"));
+        assert!(hosts.contains("172.18.0.2\tfoo-bar\n"));
+    }
+
+    #[test]
+    fn resolv_content_success() {
+        let host =
+            "nameserver 127.0.0.53\nnameserver 10.0.0.1\nsearch example.com\noptions ndots:2\n";
+        let resolv = resolv_content(&NameConfig::default(), host);
+        assert_eq!(
+            resolv,
+            "nameserver 10.0.0.1\nsearch example.com\noptions ndots:2\n"
+        );
+
+        let config = NameConfig {
+            dns: vec!["1.1.1.1".parse().unwrap()],
+            dns_search: vec!["corp".to_string()],
+            ..Default::default()
+        };
+        let resolv = resolv_content(&config, host);
+        assert_eq!(resolv, "nameserver 1.1.1.1\nsearch corp\noptions ndots:2\n");
+
+        let resolv = resolv_content(&NameConfig::default(), "nameserver 127.0.0.53\n");
+        assert_eq!(resolv, "nameserver 8.8.8.8\nnameserver 8.8.4.4\n");
+    }
 }
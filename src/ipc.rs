@@ -1,7 +1,13 @@
 use crate::errors::Errcode;
 
 use log::error;
-use nix::sys::socket::{recv, send, socketpair, AddressFamily, MsgFlags, SockFlag, SockType};
+use nix::fcntl::OFlag;
+use nix::sys::socket::{
+    recv, recvmsg, send, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned,
+    MsgFlags, SockFlag, SockType,
+};
+use nix::unistd::{close, pipe2, read};
+use std::io::{IoSlice, IoSliceMut};
 use std::os::unix::io::RawFd;
 
 use anyhow::{self};
@@ -30,9 +36,104 @@ pub fn send_boolean(fd: RawFd, boolean: bool) -> anyhow::Result<()> {
 
 pub fn recv_boolean(fd: RawFd) -> anyhow::Result<bool> {
     let mut data: [u8; 1] = [0];
-    if let Err(e) = recv(fd, &mut data, MsgFlags::empty()) {
-        error!("Cannot receive boolean from socket: {:?}", e);
-        return Err(Errcode::SocketError(2).into());
+    match recv(fd, &mut data, MsgFlags::empty()) {
+        Ok(0) => {
+            error!("Cannot receive boolean from socket: closed by the other end");
+            Err(Errcode::SocketError(2).into())
+        }
+        Ok(_) => Ok(data[0] == 1),
+        Err(e) => {
+            error!("Cannot receive boolean from socket: {:?}", e);
+            Err(Errcode::SocketError(2).into())
+        }
+    }
+}
+
+/// Pass a file descriptor to the other end of the socket (SCM_RIGHTS).
+pub fn send_fd(sock: RawFd, fd: RawFd) -> anyhow::Result<()> {
+    let data = [0u8; 1];
+    let iov = [IoSlice::new(&data)];
+    let fds = [fd];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+    if let Err(e) = sendmsg::<()>(sock, &iov, &cmsg, MsgFlags::empty(), None) {
+        error!("Cannot send file descriptor with socket: {:?}", e);
+        return Err(Errcode::SocketError(7).into());
+    }
+    Ok(())
+}
+
+/// Receive a file descriptor sent with `send_fd`.
+/// Fails if the other end is closed without sending one.
+pub fn recv_fd(sock: RawFd) -> anyhow::Result<RawFd> {
+    let mut data = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut data)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+    let msg = match recvmsg::<()>(sock, &mut iov, Some(&mut cmsg), MsgFlags::MSG_CMSG_CLOEXEC) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("Cannot receive file descriptor from socket: {:?}", e);
+            return Err(Errcode::SocketError(8).into());
+        }
+    };
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(*fd);
+            }
+        }
+    }
+    error!("No file descriptor received from socket");
+    Err(Errcode::SocketError(8).into())
+}
+
+/// Create a close-on-exec pipe.
+/// The child process keeps the write end until execve (or exit),
+/// so reading from the parent returns EOF once the command is executed.
+pub fn create_exec_pipe() -> anyhow::Result<(RawFd, RawFd)> {
+    match pipe2(OFlag::O_CLOEXEC) {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("Cannot create exec pipe: {:?}", e);
+            Err(Errcode::SocketError(5).into())
+        }
+    }
+}
+
+/// Wait until the child process has executed its command and close `fd`.
+pub fn wait_exec(fd: RawFd) -> anyhow::Result<()> {
+    let mut data: [u8; 1] = [0];
+    let result = read(fd, &mut data);
+    let _ = close(fd);
+    if let Err(e) = result {
+        error!("Cannot wait for execve: {:?}", e);
+        return Err(Errcode::SocketError(6).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{pipe, write};
+
+    #[test]
+    fn send_recv_fd_success() {
+        let (sock_r, sock_w) = create_sockets().unwrap();
+        let (pipe_r, pipe_w) = pipe().unwrap();
+        send_fd(sock_w, pipe_w).unwrap();
+        let received = recv_fd(sock_r).unwrap();
+        assert_ne!(received, pipe_w);
+        write(received, b"x").unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(read(pipe_r, &mut buf).unwrap(), 1);
+        for fd in [sock_r, pipe_r, pipe_w, received] {
+            close(fd).unwrap();
+        }
+        // the other end closed without sending
+        close(sock_w).unwrap();
+        let (sock_r, sock_w) = create_sockets().unwrap();
+        close(sock_w).unwrap();
+        assert!(recv_fd(sock_r).is_err());
+        close(sock_r).unwrap();
     }
-    Ok(data[0] == 1)
 }
@@ -1,6 +1,8 @@
+mod audit;
 mod capa;
 mod child;
 mod cli;
+mod cni;
 mod config_opts;
 mod container;
 mod errors;
@@ -8,19 +10,45 @@ mod host;
 mod ipc;
 mod mount;
 mod namespace;
+mod netlink;
+mod network;
+mod notify;
 mod resource;
+mod seccomp;
+mod slirp;
+mod state;
+mod stats;
 mod syscalls;
+mod volume;
 
-use cli::parse_args;
+use cli::{parse_args, SubCommand};
 use log::{error, info};
 
 fn main() -> anyhow::Result<()> {
     match parse_args() {
         Ok(args) => {
             info!("cli args : {:?}", args);
-            //exit_with_retcode(Ok(()))
-            container::start(args);
-            Ok(())
+            match args.subcommand {
+                SubCommand::Run(run) => container::start(*run, &args.data_root),
+                SubCommand::Ps => state::handle_ps(&args.data_root),
+                SubCommand::Inspect { containers } => {
+                    state::handle_inspect(&args.data_root, &containers)
+                }
+                SubCommand::Rm { containers } => container::handle_rm(&args.data_root, &containers),
+                SubCommand::Stats {
+                    containers,
+                    no_stream,
+                    json,
+                } => stats::handle_stats(&args.data_root, &containers, no_stream, json),
+                SubCommand::Update {
+                    container,
+                    resources,
+                } => resource::handle_update(&args.data_root, &container, &resources),
+                SubCommand::Volume(volume) => {
+                    volume::handle_volume_command(&args.data_root, volume)
+                }
+                SubCommand::Cni(cni) => cni::handle_cni_command(&args.data_root, cni),
+            }
         }
         Err(err) => {
             error!("Error occurred while parsing arguments -> {}", err);
@@ -1,12 +1,18 @@
 use crate::errors::Errcode;
 use anyhow::{self};
+use clap::ValueEnum;
 use log::{debug, error};
-use std::path::PathBuf;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, readlinkat, OFlag};
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
-use nix::unistd::{chdir, pivot_root};
+use nix::sys::stat::{mkdirat, Mode};
+use nix::unistd::{chdir, close, pivot_root};
 use std::fs::create_dir_all;
-use std::fs::remove_dir;
 
 /// create random directory name
 pub fn random_string(mut n: usize) -> String {
@@ -23,10 +29,254 @@ pub fn random_string(mut n: usize) -> String {
     dir_name.to_owned()
 }
 
+/// create random path under the temp directory for tests
+#[cfg(test)]
+pub fn test_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("bowl-test.{}", random_string(8)))
+}
+
+/// Mount propagation type.
+/// Decides whether mount and unmount events are passed between
+/// this mount and its peers on the host or other containers.
+/// The "r" variants are applied recursively to every submount.
+/// see : https://man7.org/linux/man-pages/man7/mount_namespaces.7.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Propagation {
+    /// Events are neither received nor sent
+    Private,
+    Rprivate,
+    /// Events are received from the host but never sent back
+    Slave,
+    Rslave,
+    /// Events are received and sent between all peers.
+    /// A bind mount is a peer of its source on the host
+    /// if the source is on a shared mount there (`mount --make-shared`).
+    /// Without root, events are only received from the host.
+    Shared,
+    Rshared,
+}
+
+impl Propagation {
+    /// Flags passed to mount(2) to change the propagation type.
+    pub fn flags(&self) -> Vec<MsFlags> {
+        match self {
+            Propagation::Private => vec![MsFlags::MS_PRIVATE],
+            Propagation::Rprivate => vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE],
+            Propagation::Slave => vec![MsFlags::MS_SLAVE],
+            Propagation::Rslave => vec![MsFlags::MS_REC, MsFlags::MS_SLAVE],
+            Propagation::Shared => vec![MsFlags::MS_SHARED],
+            Propagation::Rshared => vec![MsFlags::MS_REC, MsFlags::MS_SHARED],
+        }
+    }
+}
+
+/// Options given after the paths of a bind mount,
+/// a comma separated list like `rslave,idmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MountOptions {
+    pub propagation: Propagation,
+    /// Present the files with the ownership they have on the host,
+    /// shifted through the container user namespace.
+    pub idmap: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            propagation: Propagation::Private,
+            idmap: false,
+        }
+    }
+}
+
+impl MountOptions {
+    /// Parse `opt[,opt...]`, `arg` names the CLI argument in the error.
+    pub fn parse(options: &str, arg: &'static str) -> anyhow::Result<MountOptions> {
+        let mut opts = MountOptions::default();
+        for opt in options.split(',') {
+            if opt == "idmap" {
+                opts.idmap = true;
+            } else {
+                opts.propagation =
+                    Propagation::from_str(opt, true).map_err(|_| Errcode::InvalidArgument(arg))?;
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Path bind-mounted from the host into the container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindMount {
+    /// Host path (canonicalized)
+    pub source: PathBuf,
+    /// Path inside the container, relative to the new root
+    pub target: PathBuf,
+    pub propagation: Propagation,
+    pub idmap: bool,
+    /// Detached mount tree prepared by the parent (see `idmapped_tree`),
+    /// moved into place instead of a clone of `source`.
+    pub tree_fd: Option<RawFd>,
+}
+
+impl BindMount {
+    /// Parse `<host path>:<container path>[:<options>]`.
+    /// The propagation defaults to "private".
+    pub fn parse(spec: &str) -> anyhow::Result<BindMount> {
+        let (source, target, options) = split_mount_spec(spec, "add_paths")?;
+        let source = PathBuf::from(source).canonicalize().map_err(|e| {
+            error!("Cannot canonicalize path {}: {}", source, e);
+            Errcode::InvalidArgument("add_paths")
+        })?;
+        BindMount::new(source, target, options)
+    }
+
+    /// Create a bind mount of the host path `source` on the absolute path `target`.
+    pub fn new(source: PathBuf, target: &str, options: MountOptions) -> anyhow::Result<BindMount> {
+        let target = PathBuf::from(target)
+            .strip_prefix("/")
+            .map_err(|_| Errcode::InvalidArgument("mount target must be absolute"))?
+            .to_path_buf();
+
+        Ok(BindMount {
+            source,
+            target,
+            propagation: options.propagation,
+            idmap: options.idmap,
+            tree_fd: None,
+        })
+    }
+}
+
+/// Split `<source>:<target>[:<options>]` into its parts.
+/// `arg` names the CLI argument in the error.
+pub fn split_mount_spec<'a>(
+    spec: &'a str,
+    arg: &'static str,
+) -> anyhow::Result<(&'a str, &'a str, MountOptions)> {
+    let mut pair = spec.split(':');
+    let (source, target) = match (pair.next(), pair.next()) {
+        (Some(source), Some(target)) if !source.is_empty() && !target.is_empty() => {
+            (source, target)
+        }
+        _ => return Err(Errcode::InvalidArgument(arg).into()),
+    };
+    let options = match pair.next() {
+        Some(options) => MountOptions::parse(options, arg)?,
+        None => MountOptions::default(),
+    };
+    if pair.next().is_some() {
+        return Err(Errcode::InvalidArgument(arg).into());
+    }
+    Ok((source, target, options))
+}
+
+fn path_to_cstring(path: &Path) -> anyhow::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Errcode::InvalidArgument("path contains a nul byte").into())
+}
+
+/// Create a detached copy of the `source` tree, idmapped through
+/// the user namespace `userns_fd` (MOUNT_ATTR_IDMAP, Linux 5.12+).
+/// A file owned by UID n on the host is seen as UID n inside
+/// a container that uses the same mapping.
+/// see : https://man7.org/linux/man-pages/man2/mount_setattr.2.html
+pub fn idmapped_tree(source: &Path, userns_fd: RawFd) -> anyhow::Result<RawFd> {
+    debug!("Create idmapped mount of {}", source.to_str().unwrap());
+    let fd = clone_tree(source, true)?;
+
+    let attr = libc::mount_attr {
+        attr_set: libc::MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns_fd as u64,
+    };
+    let empty = CString::default();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            fd,
+            empty.as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_RECURSIVE,
+            &attr as *const libc::mount_attr,
+            std::mem::size_of::<libc::mount_attr>(),
+        )
+    };
+    if res < 0 {
+        error!(
+            "Unable to idmap mount {}: {}",
+            source.to_str().unwrap(),
+            Errno::last()
+        );
+        let _ = close(fd);
+        return Err(Errcode::MountError(7).into());
+    }
+    Ok(fd)
+}
+
+/// Create a detached bind mount of `source` (with its submounts if `recursive`).
+/// Like a bind mount, it is a peer of the source mount if that one is shared.
+fn clone_tree(source: &Path, recursive: bool) -> anyhow::Result<RawFd> {
+    let path = path_to_cstring(source)?;
+    let mut flags = libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC;
+    if recursive {
+        flags |= libc::AT_RECURSIVE as u32;
+    }
+    let fd = unsafe { libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), flags) };
+    if fd < 0 {
+        error!(
+            "Unable to clone mount tree {}: {}",
+            source.to_str().unwrap(),
+            Errno::last()
+        );
+        return Err(Errcode::MountError(6).into());
+    }
+    Ok(fd as RawFd)
+}
+
+/// Clone the sources of the binds that are not cloned yet (idmap).
+/// A bind is a peer or a slave of its source on the host only if it is cloned
+/// while the source is still a peer, before the propagation of "/" is changed.
+fn clone_sources(add_paths: &[BindMount]) -> anyhow::Result<Vec<RawFd>> {
+    add_paths
+        .iter()
+        .map(|bind| match bind.tree_fd {
+            Some(fd) => Ok(fd),
+            None => clone_tree(&bind.source, false),
+        })
+        .collect()
+}
+
+/// Attach a detached mount tree on the mount point opened as `target_fd`.
+/// move_mount(2) does not follow /proc/self/fd, the fd itself is given.
+fn move_tree(tree_fd: RawFd, target_fd: RawFd) -> anyhow::Result<()> {
+    let empty = CString::default();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree_fd,
+            empty.as_ptr(),
+            target_fd,
+            empty.as_ptr(),
+            libc::MOVE_MOUNT_F_EMPTY_PATH | libc::MOVE_MOUNT_T_EMPTY_PATH,
+        )
+    };
+    let _ = close(tree_fd);
+    if res < 0 {
+        error!(
+            "Unable to move mount to {}: {}",
+            fd_path(target_fd).to_str().unwrap(),
+            Errno::last()
+        );
+        return Err(Errcode::MountError(8).into());
+    }
+    Ok(())
+}
+
 /// Unmount the "old root".
 /// so that included applications cannot access
 /// the entire file system
-pub fn unmount_path(path: &PathBuf) -> anyhow::Result<()> {
+pub fn unmount_path(path: &Path) -> anyhow::Result<()> {
     //Remove the (top-level) filesystem mounted on the path
     match umount2(path, MntFlags::MNT_DETACH) {
         Ok(_) => Ok(()),
@@ -37,25 +287,8 @@ pub fn unmount_path(path: &PathBuf) -> anyhow::Result<()> {
     }
 }
 
-/// Delete the Directory.
-/// so that included applications cannot access
-/// the entire file system
-pub fn delete_dir(path: &PathBuf) -> anyhow::Result<()> {
-    match remove_dir(path.as_path()) {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            error!(
-                "Unable to delete directory {}: {}",
-                path.to_str().unwrap(),
-                e
-            );
-            Err(Errcode::MountError(1).into())
-        }
-    }
-}
-
 /// Create new directory for mount.
-pub fn create_directory(path: &PathBuf) -> anyhow::Result<()> {
+pub fn create_directory(path: &Path) -> anyhow::Result<()> {
     match create_dir_all(path) {
         Ok(_) => Ok(()),
         Err(e) => {
@@ -69,13 +302,106 @@ pub fn create_directory(path: &PathBuf) -> anyhow::Result<()> {
     }
 }
 
+// symbolic links followed before giving up (MAXSYMLINKS)
+const MAX_SYMLINKS: usize = 40;
+
+/// Open `path` with O_PATH, resolved as if the directory `root_fd` was "/".
+/// Symbolic links of the container image can not lead out of it,
+/// an absolute link is resolved from `root_fd` and ".." stops there.
+/// see : https://man7.org/linux/man-pages/man2/openat2.2.html
+fn open_in_root(root_fd: RawFd, path: &Path) -> Result<RawFd, Errno> {
+    let path = match path.as_os_str().is_empty() {
+        true => CString::new(".").unwrap(),
+        false => CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?,
+    };
+    // open_how is non_exhaustive
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root_fd,
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    Errno::result(fd).map(|fd| fd as RawFd)
+}
+
+/// Path that mount(2) follows to the file opened as `fd`.
+fn fd_path(fd: RawFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd))
+}
+
+/// Open the mount point `target` in the new root `root_fd` (see `open_in_root`).
+/// A missing target is created as a directory, or an empty file if `dir` is false,
+/// together with its parents. An existing one is left untouched.
+fn open_mount_point(root_fd: RawFd, target: &Path, dir: bool) -> anyhow::Result<RawFd> {
+    let mut target = target.to_path_buf();
+    for _ in 0..MAX_SYMLINKS {
+        match open_in_root(root_fd, &target) {
+            Ok(fd) => return Ok(fd),
+            Err(Errno::ENOENT) => {}
+            Err(e) => {
+                error!("Unable to open {}: {}", target.to_str().unwrap(), e);
+                return Err(Errcode::MountError(12).into());
+            }
+        }
+        let (parent, name) = match (target.parent(), target.file_name()) {
+            (Some(parent), Some(name)) => (parent.to_path_buf(), name.to_owned()),
+            _ => {
+                error!("Unable to open {}: no parent", target.to_str().unwrap());
+                return Err(Errcode::MountError(12).into());
+            }
+        };
+        let parent_fd = open_mount_point(root_fd, &parent, true)?;
+
+        // created in the resolved parent, never through a symbolic link
+        let created = match dir {
+            true => mkdirat(parent_fd, name.as_os_str(), Mode::from_bits_truncate(0o777)),
+            false => openat(
+                parent_fd,
+                name.as_os_str(),
+                OFlag::O_CREAT
+                    | OFlag::O_EXCL
+                    | OFlag::O_NOFOLLOW
+                    | OFlag::O_WRONLY
+                    | OFlag::O_CLOEXEC,
+                Mode::from_bits_truncate(0o666),
+            )
+            .and_then(close),
+        };
+        // a dangling link, like resolv.conf of some images, is created where it points to
+        let link = match created {
+            Err(Errno::EEXIST) => readlinkat(parent_fd, name.as_os_str()).ok(),
+            _ => None,
+        };
+        let _ = close(parent_fd);
+        match (created, link) {
+            (Ok(_), _) => {}
+            (Err(_), Some(link)) => {
+                target = parent.join(link);
+                continue;
+            }
+            (Err(e), None) => {
+                error!("Unable to create {}: {}", target.to_str().unwrap(), e);
+                return Err(Errcode::MountError(13).into());
+            }
+        }
+    }
+    error!("Too many symbolic links in {}", target.to_str().unwrap());
+    Err(Errcode::MountError(12).into())
+}
+
 /// Function that is a mount_directory wrapper for syscall
 /// Remount the filesystem root "/" using the flag "MS_PRIVATE"
 /// which prevents the mount operation from being propagated.
 /// see : https://lwn.net/Articles/689856/
 pub fn mount_directory(
-    path: Option<&PathBuf>,
-    mount_point: &PathBuf,
+    path: Option<&Path>,
+    mount_point: &Path,
     flags: Vec<MsFlags>,
 ) -> anyhow::Result<()> {
     let mut ms_flags = MsFlags::empty();
@@ -83,7 +409,7 @@ pub fn mount_directory(
         ms_flags.insert(*flag);
     }
 
-    match mount::<PathBuf, PathBuf, PathBuf, PathBuf>(path, mount_point, None, ms_flags, None) {
+    match mount::<Path, Path, Path, Path>(path, mount_point, None, ms_flags, None) {
         Ok(_) => Ok(()),
         Err(e) => {
             if let Some(p) = path {
@@ -101,6 +427,37 @@ pub fn mount_directory(
     }
 }
 
+/// Change the propagation type of an existing mount point.
+/// This has to be a separate mount(2) call,
+/// the kernel ignores propagation flags combined with MS_BIND.
+pub fn set_propagation(mount_point: &Path, propagation: Propagation) -> anyhow::Result<()> {
+    debug!(
+        "Set propagation of {} to {:?}",
+        mount_point.to_str().unwrap(),
+        propagation
+    );
+    mount_directory(None, mount_point, propagation.flags())
+}
+
+/// Attach the `trees` of additional paths in the new root `root_fd`.
+/// The image is not trusted, the targets are resolved inside it
+/// and mounted through their fd instead of a path resolved on the host.
+fn mount_add_paths(root_fd: RawFd, add_paths: &[BindMount], trees: &[RawFd]) -> anyhow::Result<()> {
+    for (bind, tree_fd) in add_paths.iter().zip(trees.iter()) {
+        let target_fd = open_mount_point(root_fd, &bind.target, bind.source.is_dir())?;
+        let mounted = move_tree(*tree_fd, target_fd);
+        let _ = close(target_fd);
+        mounted?;
+
+        // the fd was opened on what is now under the mount, open the mount itself
+        let mount_fd = open_mount_point(root_fd, &bind.target, bind.source.is_dir())?;
+        let propagated = set_propagation(&fd_path(mount_fd), bind.propagation);
+        let _ = close(mount_fd);
+        propagated?;
+    }
+    Ok(())
+}
+
 /// Changing a Container's Mount Point.
 /// 1.Mount the system root in /container
 /// 2.Create a new temporary directory
@@ -109,17 +466,32 @@ pub fn mount_directory(
 /// 5.Unmount and delete unneeded directories
 /// see : https://man7.org/linux/man-pages/man7/mount_namespaces.7.html
 pub fn set_mount_point(
-    _mount_directory: &PathBuf,
-    add_paths: &Vec<(PathBuf, PathBuf)>,
+    _mount_directory: &Path,
+    add_paths: &[BindMount],
+    root_propagation: Propagation,
+    rootless: bool,
 ) -> anyhow::Result<()> {
     debug!("Setting mount points ...");
 
     // 1.Mount the system root in /container
-    mount_directory(
-        None,
-        &PathBuf::from("/"),
-        vec![MsFlags::MS_REC, MsFlags::MS_PRIVATE],
-    )?;
+    // "rslave" lets the container receive mounts made later on the host,
+    // "rshared" also propagates mounts of the container back to its peers.
+    // pivot_root(2) fails when the current root or new_root is shared,
+    // so shared modes are slaves until the root is pivoted.
+    let pre_pivot = match root_propagation {
+        Propagation::Shared => Propagation::Slave,
+        Propagation::Rshared => Propagation::Rslave,
+        propagation => propagation,
+    };
+    let trees = clone_sources(add_paths)?;
+    // The old root is unmounted after the pivot, none of its mounts may be
+    // shared so that the unmount does not propagate back to the host.
+    // The new root and the binds are mounted later with their own mode.
+    let old_root = match pre_pivot {
+        Propagation::Private | Propagation::Rprivate => Propagation::Rprivate,
+        _ => Propagation::Rslave,
+    };
+    set_propagation(Path::new("/"), old_root)?;
     let new_root = PathBuf::from(format!("/tmp/bowl.{}", random_string(12)));
     debug!(
         "Mount temp directory {}",
@@ -130,49 +502,68 @@ pub fn set_mount_point(
     create_directory(&new_root)?;
 
     // 3.Mount a user-specified directory in the temporary directory
-    mount_directory(
-        Some(_mount_directory),
-        &new_root,
-        vec![MsFlags::MS_BIND, MsFlags::MS_PRIVATE],
-    )?;
+    // A user namespace can not bind a directory without the mounts below it,
+    // as that would reveal what they hide.
+    let mut root_flags = vec![MsFlags::MS_BIND];
+    if rootless {
+        root_flags.push(MsFlags::MS_REC);
+    }
+    mount_directory(Some(_mount_directory), &new_root, root_flags)?;
+    set_propagation(&new_root, pre_pivot)?;
 
     // 3.5 Mount additional paths
     log::debug!("Mounting additionnal paths");
-    for (inpath, mntpath) in add_paths.iter() {
-        let outpath = new_root.join(mntpath);
-        create_directory(&outpath)?;
-        mount_directory(
-            Some(inpath),
-            &outpath,
-            vec![MsFlags::MS_PRIVATE, MsFlags::MS_BIND],
-        )?;
-    }
+    let root_fd = match open(
+        &new_root,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("Unable to open {}: {}", new_root.to_str().unwrap(), e);
+            return Err(Errcode::MountError(12).into());
+        }
+    };
+    let mounted = mount_add_paths(root_fd, add_paths, &trees);
+    let _ = close(root_fd);
+    mounted?;
 
     // 4.Perform a root pivot on the two mounted directories
+    // pivot_root(".", ".") stacks the old root on the new one, so nothing
+    // has to be created in the new root, which may not be writable without root.
     debug!("Pivoting root");
-    let old_root_tail = format!("oldroot.{}", random_string(6));
-    let put_old = new_root.join(PathBuf::from(old_root_tail.clone()));
-    create_directory(&put_old)?;
-    if pivot_root(&new_root, &put_old).is_err() {
+    if chdir(&new_root).is_err() {
+        return Err(Errcode::MountError(5).into());
+    }
+    if pivot_root(".", ".").is_err() {
         return Err(Errcode::MountError(4).into());
     }
 
-    // 5.Unmount and delete unneeded directories
+    // 5.Unmount the old root stacked on "/"
+    // Changing the propagation of "." here would also change the new root
+    // and the binds, which have to keep their peers.
     debug!("Unmounting old root");
-    let old_root = PathBuf::from(format!("/{}", old_root_tail));
+    unmount_path(Path::new("."))?;
     if chdir(&PathBuf::from("/")).is_err() {
         return Err(Errcode::MountError(5).into());
     }
 
-    unmount_path(&old_root)?;
-    delete_dir(&old_root)?;
+    if pre_pivot != root_propagation {
+        set_propagation(Path::new("/"), root_propagation)?;
+        // "rshared" also changed the bind mounts, give them back their own mode
+        if root_propagation == Propagation::Rshared {
+            for bind in add_paths.iter() {
+                set_propagation(&Path::new("/").join(&bind.target), bind.propagation)?;
+            }
+        }
+    }
 
     Ok(())
 }
 
 /// Clean mount.
 /// Placeholder for when you need it someday
-pub fn clean_mount(_rootpath: &PathBuf) -> Result<(), Errcode> {
+pub fn clean_mount(_rootpath: &Path) -> Result<(), Errcode> {
     //unmount_path(&rootpath)?;
     Ok(())
 }
@@ -180,6 +571,9 @@ pub fn clean_mount(_rootpath: &PathBuf) -> Result<(), Errcode> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use nix::sched::{unshare, CloneFlags};
+    use nix::unistd::geteuid;
+    use std::os::unix::fs::MetadataExt;
 
     #[test]
     fn random_string_success() {
@@ -192,4 +586,158 @@ mod tests {
         let dir_name = random_string(0);
         assert_eq!(dir_name.len(), 3);
     }
+
+    #[test]
+    fn bind_mount_parse_default_propagation() {
+        let bind = BindMount::parse("/tmp:/mnt/tmp").unwrap();
+        assert_eq!(bind.source, PathBuf::from("/tmp").canonicalize().unwrap());
+        assert_eq!(bind.target, PathBuf::from("mnt/tmp"));
+        assert_eq!(bind.propagation, Propagation::Private);
+    }
+
+    #[test]
+    fn bind_mount_parse_propagation() {
+        let bind = BindMount::parse("/tmp:/data:rslave").unwrap();
+        assert_eq!(bind.propagation, Propagation::Rslave);
+        assert!(!bind.idmap);
+        let bind = BindMount::parse("/tmp:/data:idmap,rshared").unwrap();
+        assert_eq!(bind.propagation, Propagation::Rshared);
+        assert!(bind.idmap);
+        assert_eq!(
+            Propagation::Rshared.flags(),
+            vec![MsFlags::MS_REC, MsFlags::MS_SHARED]
+        );
+    }
+
+    #[test]
+    fn bind_mount_parse_invalid() {
+        assert!(BindMount::parse("/tmp").is_err());
+        assert!(BindMount::parse("/tmp:/data:unknown").is_err());
+        assert!(BindMount::parse("/tmp:data").is_err());
+        assert!(BindMount::parse("/tmp:/data:idmap,").is_err());
+    }
+
+    #[test]
+    fn open_mount_point_in_root() {
+        let root = test_dir();
+        let outside = test_dir();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("etc/hosts")).unwrap();
+        std::os::unix::fs::symlink("../../../..", root.join("up")).unwrap();
+        std::os::unix::fs::symlink("../run/resolv.conf", root.join("etc/resolv.conf")).unwrap();
+        let root_fd = open(&root, OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+        let real_root = root.canonicalize().unwrap();
+
+        // an absolute link is resolved in the root, not on the host
+        let fd = open_mount_point(root_fd, Path::new("etc/hosts"), false).unwrap();
+        let path = std::fs::read_link(fd_path(fd)).unwrap();
+        assert_eq!(path, real_root.join(outside.strip_prefix("/").unwrap()));
+        assert!(!outside.exists());
+        close(fd).unwrap();
+
+        // ".." stops at the root
+        let fd = open_mount_point(root_fd, Path::new("up/data"), true).unwrap();
+        assert_eq!(
+            std::fs::read_link(fd_path(fd)).unwrap(),
+            real_root.join("data")
+        );
+        close(fd).unwrap();
+
+        // a dangling relative link is created where it points to
+        let fd = open_mount_point(root_fd, Path::new("etc/resolv.conf"), false).unwrap();
+        assert!(root.join("run/resolv.conf").is_file());
+        close(fd).unwrap();
+
+        close(root_fd).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn mount_tmpfs(path: &Path) {
+        mount(
+            Some("tmpfs"),
+            path,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .unwrap();
+    }
+
+    fn is_mount(path: &Path) -> bool {
+        let dev = |path: &Path| std::fs::metadata(path).unwrap().dev();
+        dev(path) != dev(path.parent().unwrap())
+    }
+
+    #[test]
+    fn bind_propagation_with_host() {
+        // mount namespaces need CAP_SYS_ADMIN
+        if !geteuid().is_root() {
+            return;
+        }
+        let source = test_dir();
+        let root = test_dir();
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+
+        // this thread plays the host in a mount namespace of its own,
+        // the source of the binds is on a shared mount
+        unshare(CloneFlags::CLONE_NEWNS).unwrap();
+        set_propagation(Path::new("/"), Propagation::Rprivate).unwrap();
+        mount_directory(Some(&source), &source, vec![MsFlags::MS_BIND]).unwrap();
+        set_propagation(&source, Propagation::Shared).unwrap();
+
+        let binds: Vec<BindMount> = ["shared", "slave", "private"]
+            .iter()
+            .map(|p| BindMount::parse(&format!("{}:/{}:{}", source.display(), p, p)).unwrap())
+            .collect();
+        let (to_host, from_container) = std::sync::mpsc::channel();
+        let (to_container, from_host) = std::sync::mpsc::channel();
+        let container_root = root.clone();
+        // and a thread with another mount namespace plays the container
+        let container = std::thread::spawn(move || {
+            let root = container_root;
+            unshare(CloneFlags::CLONE_NEWNS).unwrap();
+            let trees = clone_sources(&binds).unwrap();
+            set_propagation(Path::new("/"), Propagation::Rprivate).unwrap();
+            let root_fd = open(&root, OFlag::O_PATH, Mode::empty()).unwrap();
+            mount_add_paths(root_fd, &binds, &trees).unwrap();
+            close(root_fd).unwrap();
+            to_host.send(()).unwrap();
+
+            // mounted on the host
+            from_host.recv().unwrap();
+            assert!(is_mount(&root.join("shared/sub")));
+            assert!(is_mount(&root.join("slave/sub")));
+            assert!(!is_mount(&root.join("private/sub")));
+            to_host.send(()).unwrap();
+
+            from_host.recv().unwrap();
+            mount_tmpfs(&root.join("slave/sub"));
+            mount_tmpfs(&root.join("private/sub"));
+            to_host.send(()).unwrap();
+
+            from_host.recv().unwrap();
+            mount_tmpfs(&root.join("shared/sub"));
+            to_host.send(()).unwrap();
+        });
+
+        from_container.recv().unwrap();
+        mount_tmpfs(&source.join("sub"));
+        to_container.send(()).unwrap();
+        from_container.recv().unwrap();
+        unmount_path(&source.join("sub")).unwrap();
+        to_container.send(()).unwrap();
+
+        // only the shared bind sends the mounts of the container back
+        from_container.recv().unwrap();
+        assert!(!is_mount(&source.join("sub")));
+        to_container.send(()).unwrap();
+        from_container.recv().unwrap();
+        assert!(is_mount(&source.join("sub")));
+        container.join().unwrap();
+
+        unmount_path(&source).unwrap();
+        std::fs::remove_dir_all(&source).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
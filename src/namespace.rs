@@ -1,34 +1,40 @@
 use crate::errors::Errcode;
 use crate::ipc::{recv_boolean, send_boolean};
 
-use nix::sched::{unshare, CloneFlags};
-use nix::unistd::{setgroups, setresgid, setresuid};
+use capctl::prctl::set_keepcaps;
+use nix::fcntl::{open, OFlag};
+use nix::sched::{clone, unshare, CloneFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::wait::waitpid;
+use nix::unistd::{getegid, geteuid, pause, setgroups, setresgid, setresuid};
 use nix::unistd::{Gid, Pid, Uid};
 use std::fs::File;
 use std::io::Write;
 use std::os::unix::io::RawFd;
 
 use anyhow::{self};
-use log::{debug, info};
+use log::{debug, error, info};
 
 ///setup user namespace with UID
-pub fn user_namespace(fd: RawFd, uid: u32) -> anyhow::Result<()> {
-    ///ユーザー名前空間の共有を解除して、
-    ///呼び出し元のプロセスが既存のプロセスと共有されていない
-    ///新しいユーザー名前空間に移動.
-    ///see:https://man7.org/linux/man-pages/man2/unshare.2.html
+///rootlessの場合、user namespaceはcloneで作成されマッピングも書き込まれているので、
+///parentがnetworkを設定し終わるのを待つだけ
+pub fn user_namespace(fd: RawFd, uid: u32, rootless: bool) -> anyhow::Result<()> {
+    //ユーザー名前空間の共有を解除して、
+    //呼び出し元のプロセスが既存のプロセスと共有されていない
+    //新しいユーザー名前空間に移動.
+    //see:https://man7.org/linux/man-pages/man2/unshare.2.html
     debug!("setup user namespace with UID {}", uid);
-    let has_userns = match unshare(CloneFlags::CLONE_NEWUSER) {
-        Ok(_) => true,
-        Err(_) => false,
-    };
+    let has_userns = !rootless && unshare(CloneFlags::CLONE_NEWUSER).is_ok();
     send_boolean(fd, has_userns)?;
 
     if recv_boolean(fd)? {
         return Err(Errcode::NamespaceError(0).into());
     }
 
-    if has_userns {
+    if rootless {
+        info!("User namespace created with the container");
+    } else if has_userns {
         info!("User namespaces set up");
     } else {
         info!("User namespaces not supported, continuing...");
@@ -42,7 +48,8 @@ pub fn user_namespace(fd: RawFd, uid: u32) -> anyhow::Result<()> {
     //ここではプロセスのGIDを追加します。
     //※GID:group name.１人のユーザが複数のグループに属することもある
     //see:https://man7.org/linux/man-pages/man2/getgroups.2.html
-    if setgroups(&[gid]).is_err() {
+    //rootlessのマッピングではsetgroupsが禁止されている
+    if !rootless && setgroups(&[gid]).is_err() {
         return Err(Errcode::NamespaceError(1).into());
     }
 
@@ -51,7 +58,7 @@ pub fn user_namespace(fd: RawFd, uid: u32) -> anyhow::Result<()> {
     //real user ID:あなたが誰であるか(あなたがログインした人) であり、
     //the effective user ID:自分が誰であるかを主張するもの(sudoで一時的に特権を与えたりするときなど)
     //保存されたuser ID：あなたが以前誰であったかを示す
-    ///
+    //
     //そのため、隔離された環境で、封じ込められたプロセスを root にすることができ、
     //システムによって実際の UID >10000 にマップされ、
     //親システムを汚染することなくユーザーとグループを管理できます。
@@ -60,6 +67,11 @@ pub fn user_namespace(fd: RawFd, uid: u32) -> anyhow::Result<()> {
         return Err(Errcode::NamespaceError(2).into());
     }
 
+    //root以外のuidに切り替えるとpermittedが空になるので、set_capaまで残しておく
+    if set_keepcaps(true).is_err() {
+        return Err(Errcode::NamespaceError(10).into());
+    }
+
     if setresuid(uid, uid, uid).is_err() {
         return Err(Errcode::NamespaceError(3).into());
     }
@@ -68,11 +80,11 @@ pub fn user_namespace(fd: RawFd, uid: u32) -> anyhow::Result<()> {
 }
 
 ///Linux カーネルは、/proc/<pid>/uidmap というファイルを用いて、
-//プロセスの名前空間内外のユーザ ID をマッピングしている。
+///プロセスの名前空間内外のユーザ ID をマッピングしている。
 ///書式 [ID-inside-ns ID-outside-ns length]
 ///
-////proc/<pid>/uidmap ファイルに
-// 0 1000 5
+///`/proc/<pid>/uidmap` ファイルに
+/// 0 1000 5
 ///が含まれている場合、コンテナ内で UID 0 を持つユーザーは、
 ///コンテナの外では UID 1000 を持つ。
 ///同様に、内部で1のUIDは外部で1001のUIDにマップされる.
@@ -85,35 +97,131 @@ pub fn user_namespace(fd: RawFd, uid: u32) -> anyhow::Result<()> {
 ///これから再開すると、含まれるプロセス (PIDで一致) が UID 0 を持つと主張する
 ///(あるいは自分自身を設定する) 場合、カーネルはそれを 10000 の UID で見ることになります。
 ///GIDについても同じ.
-
 const USERNS_OFFSET: u64 = 10000;
 const USERNS_COUNT: u64 = 2000;
+const HELPER_STACK_SIZE: usize = 64 * 1024;
+
+///コンテナ内のUID/GIDがhost上でマッピングされるID
+///rootlessの場合はコンテナのuidだけが実行したユーザーにマッピングされる
+pub fn mapped_id(id: u32) -> u32 {
+    match geteuid() {
+        euid if !euid.is_root() => euid.as_raw(),
+        _ => USERNS_OFFSET as u32 + id,
+    }
+}
+
+///コンテナのUIDがマッピングされる範囲にあるか確認する.
+///rootlessの場合は指定したuidだけがマッピングされるので制限はない
+pub fn validate_uid(uid: u32) -> anyhow::Result<()> {
+    if geteuid().is_root() && uid as u64 >= USERNS_COUNT {
+        error!(
+            "UID {} is not mapped, it must be less than {}",
+            uid, USERNS_COUNT
+        );
+        return Err(Errcode::InvalidArgument("uid").into());
+    }
+    Ok(())
+}
 
 pub fn handle_child_uid_map(pid: Pid, fd: RawFd) -> anyhow::Result<()> {
     if recv_boolean(fd)? {
-        if let Ok(mut uid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "uid_map")) {
-            if let Err(_) =
-                uid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes())
-            {
-                return Err(Errcode::NamespaceError(4).into());
-            }
-        } else {
-            return Err(Errcode::NamespaceError(5).into());
+        let map = format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT);
+        write_id_maps(pid, &map, &map)?;
+    } else {
+        info!("No user namespace to map from child process");
+    }
+
+    debug!("Child UID/GID map done, sending signal to child to continue...");
+    send_boolean(fd, false)
+}
+
+///rootlessの場合、child processはmountの前にマッピングを待つ.
+///マッピングがないuser namespaceではファイルを作成できないため
+pub fn wait_id_maps(fd: RawFd) -> anyhow::Result<()> {
+    send_boolean(fd, true)?;
+    if recv_boolean(fd)? {
+        return Err(Errcode::NamespaceError(0).into());
+    }
+    Ok(())
+}
+
+///rootlessの場合のマッピング.
+///newuidmapなどを使わずに書き込めるのは自分のUID/GIDを1つだけで、
+///コンテナのuidをそれにマッピングする
+pub fn handle_rootless_id_map(pid: Pid, fd: RawFd, uid: u32) -> anyhow::Result<()> {
+    if !recv_boolean(fd)? {
+        return Err(Errcode::NamespaceError(0).into());
+    }
+    //gid_mapを書き込む前にsetgroupsを禁止する必要がある
+    if let Err(e) = std::fs::write(format!("/proc/{}/setgroups", pid.as_raw()), "deny") {
+        error!("Unable to deny setgroups: {}", e);
+        return Err(Errcode::NamespaceError(11).into());
+    }
+    write_id_maps(
+        pid,
+        &format!("{} {} 1", uid, geteuid()),
+        &format!("{} {} 1", uid, getegid()),
+    )?;
+    debug!("Rootless UID/GID map done, sending signal to child to continue...");
+    send_boolean(fd, false)
+}
+
+///pidのuser namespaceにUID/GIDのマッピングを書き込む
+fn write_id_maps(pid: Pid, uid_map: &str, gid_map: &str) -> anyhow::Result<()> {
+    if let Ok(mut file) = File::create(format!("/proc/{}/{}", pid.as_raw(), "uid_map")) {
+        if file.write_all(uid_map.as_bytes()).is_err() {
+            return Err(Errcode::NamespaceError(4).into());
         }
+    } else {
+        return Err(Errcode::NamespaceError(5).into());
+    }
 
-        if let Ok(mut gid_map) = File::create(format!("/proc/{}/{}", pid.as_raw(), "gid_map")) {
-            if let Err(_) =
-                gid_map.write_all(format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT).as_bytes())
-            {
-                return Err(Errcode::NamespaceError(6).into());
-            }
-        } else {
-            return Err(Errcode::NamespaceError(7).into());
+    if let Ok(mut file) = File::create(format!("/proc/{}/{}", pid.as_raw(), "gid_map")) {
+        if file.write_all(gid_map.as_bytes()).is_err() {
+            return Err(Errcode::NamespaceError(6).into());
         }
     } else {
-        info!("No user namespace set up from child process");
+        return Err(Errcode::NamespaceError(7).into());
     }
+    Ok(())
+}
 
-    debug!("Child UID/GID map done, sending signal to child to continue...");
-    send_boolean(fd, false)
+///コンテナと同じマッピングを持つuser namespaceを作成してそのfdを返す.
+///idmapped mountはmount時にuser namespaceのfdが必要だが、
+///コンテナのuser namespaceはmountの後に作られるため、
+///一時的なprocessを新しいuser namespaceでcloneしてマッピングを書き込み、
+///`/proc/<pid>/ns/user`を開いた後にprocessを終了させる.
+pub fn create_mapped_userns() -> anyhow::Result<RawFd> {
+    let mut stack = vec![0u8; HELPER_STACK_SIZE];
+    let pid = match clone(
+        Box::new(|| loop {
+            pause();
+        }),
+        &mut stack,
+        CloneFlags::CLONE_NEWUSER,
+        Some(Signal::SIGCHLD as i32),
+    ) {
+        Ok(pid) => pid,
+        Err(e) => {
+            error!("Unable to create user namespace: {:?}", e);
+            return Err(Errcode::NamespaceError(8).into());
+        }
+    };
+
+    let map = format!("0 {} {}", USERNS_OFFSET, USERNS_COUNT);
+    let userns = write_id_maps(pid, &map, &map).and_then(|_| {
+        open(
+            format!("/proc/{}/ns/user", pid.as_raw()).as_str(),
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|e| {
+            error!("Unable to open user namespace of {}: {:?}", pid, e);
+            Errcode::NamespaceError(9).into()
+        })
+    });
+
+    let _ = kill(pid, Signal::SIGKILL);
+    let _ = waitpid(pid, None);
+    userns
 }
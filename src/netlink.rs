@@ -0,0 +1,391 @@
+use crate::errors::Errcode;
+
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind, recv, send, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol,
+    SockType,
+};
+use nix::unistd::close;
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+
+use anyhow::{self};
+use log::{debug, error};
+
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+const RTMSG_LEN: usize = 12;
+const RTATTR_HDRLEN: usize = 4;
+const RECV_BUF_SIZE: usize = 8192;
+//linux/veth.h
+const VETH_INFO_PEER: u16 = 1;
+const CREATE_EXCL: i32 = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+
+/// rtnetlink message.
+/// nlmsghdr followed by the family specific header (ifinfomsg, ...).
+/// see : https://man7.org/linux/man-pages/man7/rtnetlink.7.html
+pub struct Message {
+    buf: Vec<u8>,
+    //offsets of the nested attributes not closed yet
+    nested: Vec<usize>,
+}
+
+impl Message {
+    fn new(msg_type: u16, flags: i32) -> Message {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16;
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        Message {
+            buf,
+            nested: vec![],
+        }
+    }
+
+    /// RTM_xxxLINK message with an ifinfomsg header
+    pub fn link(msg_type: u16, flags: i32, index: i32, ifi_flags: u32, change: u32) -> Message {
+        let mut msg = Message::new(msg_type, flags);
+        msg.push_ifinfo(index, ifi_flags, change);
+        msg
+    }
+
+    /// RTM_xxxADDR message with an ifaddrmsg header
+    pub fn addr(msg_type: u16, flags: i32, index: i32, prefix_len: u8) -> Message {
+        let mut msg = Message::new(msg_type, flags);
+        let mut ifaddr = [0u8; IFADDRMSG_LEN];
+        ifaddr[0] = libc::AF_INET as u8;
+        ifaddr[1] = prefix_len;
+        ifaddr[3] = libc::RT_SCOPE_UNIVERSE;
+        ifaddr[4..8].copy_from_slice(&index.to_ne_bytes());
+        msg.buf.extend_from_slice(&ifaddr);
+        msg
+    }
+
+    /// RTM_xxxROUTE message with a rtmsg header (IPv4, main table, unicast)
+    pub fn route(msg_type: u16, flags: i32, dst_len: u8) -> Message {
+        let mut msg = Message::new(msg_type, flags);
+        let mut rtmsg = [0u8; RTMSG_LEN];
+        rtmsg[0] = libc::AF_INET as u8;
+        rtmsg[1] = dst_len;
+        rtmsg[4] = libc::RT_TABLE_MAIN;
+        rtmsg[5] = libc::RTPROT_BOOT;
+        rtmsg[6] = libc::RT_SCOPE_UNIVERSE;
+        rtmsg[7] = libc::RTN_UNICAST;
+        msg.buf.extend_from_slice(&rtmsg);
+        msg
+    }
+
+    fn push_ifinfo(&mut self, index: i32, ifi_flags: u32, change: u32) {
+        let mut ifinfo = [0u8; IFINFOMSG_LEN];
+        ifinfo[0] = libc::AF_UNSPEC as u8;
+        ifinfo[4..8].copy_from_slice(&index.to_ne_bytes());
+        ifinfo[8..12].copy_from_slice(&ifi_flags.to_ne_bytes());
+        ifinfo[12..16].copy_from_slice(&change.to_ne_bytes());
+        self.buf.extend_from_slice(&ifinfo);
+    }
+
+    /// Append a rtattr, padded to 4 bytes.
+    pub fn attr(&mut self, attr_type: u16, data: &[u8]) -> &mut Message {
+        let len = (RTATTR_HDRLEN + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    /// Append a nul terminated string attribute.
+    pub fn attr_str(&mut self, attr_type: u16, value: &str) -> &mut Message {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(attr_type, &data)
+    }
+
+    /// Start a nested attribute, closed by `end_nested`.
+    pub fn begin_nested(&mut self, attr_type: u16) -> &mut Message {
+        self.nested.push(self.buf.len());
+        self.attr(attr_type, &[])
+    }
+
+    pub fn end_nested(&mut self) -> &mut Message {
+        if let Some(start) = self.nested.pop() {
+            let len = (self.buf.len() - start) as u16;
+            self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        }
+        self
+    }
+
+    /// Set the length and sequence number and return the raw message.
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// NETLINK_ROUTE socket talking to the kernel
+/// of the network namespace the process is in.
+pub struct NetlinkSocket {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    pub fn open() -> anyhow::Result<NetlinkSocket> {
+        let fd = match socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            SockProtocol::NetlinkRoute,
+        ) {
+            Ok(fd) => fd,
+            Err(e) => {
+                error!("Unable to open netlink socket: {:?}", e);
+                return Err(Errcode::NetworkError(0).into());
+            }
+        };
+        if let Err(e) = bind(fd, &NetlinkAddr::new(0, 0)) {
+            error!("Unable to bind netlink socket: {:?}", e);
+            let _ = close(fd);
+            return Err(Errcode::NetworkError(1).into());
+        }
+        Ok(NetlinkSocket { fd, seq: 0 })
+    }
+
+    /// Send a request and wait for the kernel acknowledgement.
+    pub fn request(&mut self, msg: Message) -> anyhow::Result<()> {
+        match self.request_errno(msg)? {
+            0 => Ok(()),
+            errno => {
+                error!("Netlink request failed: {}", Errno::from_i32(errno));
+                Err(Errcode::NetworkError(4).into())
+            }
+        }
+    }
+
+    /// Send a request and return the errno set by the kernel (0 on success).
+    pub fn request_errno(&mut self, msg: Message) -> anyhow::Result<i32> {
+        self.seq += 1;
+        let seq = self.seq;
+        if let Err(e) = send(self.fd, &msg.finish(seq), MsgFlags::empty()) {
+            error!("Unable to send netlink message: {:?}", e);
+            return Err(Errcode::NetworkError(2).into());
+        }
+
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        loop {
+            let len = match recv(self.fd, &mut buf, MsgFlags::empty()) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("Unable to receive netlink message: {:?}", e);
+                    return Err(Errcode::NetworkError(3).into());
+                }
+            };
+            let mut offset = 0;
+            while offset + NLMSG_HDRLEN <= len {
+                let msg_len = u32_at(&buf, offset) as usize;
+                let msg_type = u16::from_ne_bytes([buf[offset + 4], buf[offset + 5]]);
+                let msg_seq = u32_at(&buf, offset + 8);
+                if msg_len < NLMSG_HDRLEN {
+                    break;
+                }
+                if msg_seq == seq && msg_type == libc::NLMSG_ERROR as u16 {
+                    // nlmsgerr: error code followed by the original header
+                    let errno = i32::from_ne_bytes(
+                        buf[offset + NLMSG_HDRLEN..offset + NLMSG_HDRLEN + 4]
+                            .try_into()
+                            .unwrap(),
+                    );
+                    return Ok(-errno);
+                }
+                offset += align(msg_len);
+            }
+        }
+    }
+
+    /// Bring the interface `index` up.
+    pub fn set_link_up(&mut self, index: i32) -> anyhow::Result<()> {
+        let up = libc::IFF_UP as u32;
+        self.request(Message::link(libc::RTM_NEWLINK, 0, index, up, up))
+    }
+
+    /// Create a bridge, an existing one is left as is.
+    pub fn create_bridge(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut msg = Message::link(libc::RTM_NEWLINK, CREATE_EXCL, 0, 0, 0);
+        msg.attr_str(libc::IFLA_IFNAME, name)
+            .begin_nested(libc::IFLA_LINKINFO)
+            .attr_str(libc::IFLA_INFO_KIND, "bridge")
+            .end_nested();
+        match self.request_errno(msg)? {
+            0 => Ok(()),
+            libc::EEXIST => Ok(()),
+            errno => {
+                error!(
+                    "Unable to create bridge {}: {}",
+                    name,
+                    Errno::from_i32(errno)
+                );
+                Err(Errcode::NetworkError(6).into())
+            }
+        }
+    }
+
+    /// Create a veth pair.
+    /// The peer is created directly in the network namespace of `peer_pid`.
+    pub fn create_veth(
+        &mut self,
+        name: &str,
+        peer_name: &str,
+        peer_pid: i32,
+    ) -> anyhow::Result<()> {
+        let mut msg = Message::link(libc::RTM_NEWLINK, CREATE_EXCL, 0, 0, 0);
+        msg.attr_str(libc::IFLA_IFNAME, name)
+            .begin_nested(libc::IFLA_LINKINFO)
+            .attr_str(libc::IFLA_INFO_KIND, "veth")
+            .begin_nested(libc::IFLA_INFO_DATA)
+            .begin_nested(VETH_INFO_PEER);
+        msg.push_ifinfo(0, 0, 0);
+        msg.attr_str(libc::IFLA_IFNAME, peer_name)
+            .attr(libc::IFLA_NET_NS_PID, &(peer_pid as u32).to_ne_bytes())
+            .end_nested()
+            .end_nested()
+            .end_nested();
+        self.request(msg)
+    }
+
+    /// Attach the interface `index` to the bridge `master`.
+    pub fn set_master(&mut self, index: i32, master: i32) -> anyhow::Result<()> {
+        let mut msg = Message::link(libc::RTM_NEWLINK, 0, index, 0, 0);
+        msg.attr(libc::IFLA_MASTER, &(master as u32).to_ne_bytes());
+        self.request(msg)
+    }
+
+    /// Delete the interface `name`, a missing interface is ignored.
+    pub fn delete_link(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut msg = Message::link(libc::RTM_DELLINK, 0, 0, 0, 0);
+        msg.attr_str(libc::IFLA_IFNAME, name);
+        match self.request_errno(msg)? {
+            0 | libc::ENODEV => Ok(()),
+            errno => {
+                error!("Unable to delete link {}: {}", name, Errno::from_i32(errno));
+                Err(Errcode::NetworkError(7).into())
+            }
+        }
+    }
+
+    /// Add an IPv4 address, an existing one is left as is.
+    pub fn add_address(
+        &mut self,
+        index: i32,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+    ) -> anyhow::Result<()> {
+        let mut msg = Message::addr(libc::RTM_NEWADDR, CREATE_EXCL, index, prefix_len);
+        msg.attr(libc::IFA_LOCAL, &addr.octets())
+            .attr(libc::IFA_ADDRESS, &addr.octets());
+        match self.request_errno(msg)? {
+            0 | libc::EEXIST => Ok(()),
+            errno => {
+                error!("Unable to add address {}: {}", addr, Errno::from_i32(errno));
+                Err(Errcode::NetworkError(8).into())
+            }
+        }
+    }
+
+    /// Add the default route through `gateway`.
+    pub fn add_default_route(&mut self, gateway: Ipv4Addr, index: i32) -> anyhow::Result<()> {
+        let mut msg = Message::route(libc::RTM_NEWROUTE, CREATE_EXCL, 0);
+        msg.attr(libc::RTA_GATEWAY, &gateway.octets())
+            .attr(libc::RTA_OIF, &(index as u32).to_ne_bytes());
+        self.request(msg)
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Netlink messages and attributes are aligned to 4 bytes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Index of the interface `name` in the current network namespace.
+pub fn link_index(name: &str) -> anyhow::Result<i32> {
+    let cname = CString::new(name).map_err(|_| Errcode::InvalidArgument("interface name"))?;
+    match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+        0 => {
+            error!("No such network interface: {}", name);
+            Err(Errcode::NetworkError(5).into())
+        }
+        index => Ok(index as i32),
+    }
+}
+
+/// Bring the loopback interface up.
+/// A new network namespace only has `lo`, and it starts down.
+pub fn set_loopback_up() -> anyhow::Result<()> {
+    debug!("Bringing up loopback interface");
+    let index = link_index("lo")?;
+    NetlinkSocket::open()?.set_link_up(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_message_layout() {
+        let up = libc::IFF_UP as u32;
+        let raw = Message::link(libc::RTM_NEWLINK, 0, 1, up, up).finish(7);
+        assert_eq!(raw.len(), NLMSG_HDRLEN + IFINFOMSG_LEN);
+        assert_eq!(u32_at(&raw, 0) as usize, raw.len());
+        assert_eq!(u16::from_ne_bytes([raw[4], raw[5]]), libc::RTM_NEWLINK);
+        let flags = u16::from_ne_bytes([raw[6], raw[7]]) as i32;
+        assert_eq!(flags, libc::NLM_F_REQUEST | libc::NLM_F_ACK);
+        assert_eq!(u32_at(&raw, 8), 7);
+        // ifi_index, ifi_flags, ifi_change
+        assert_eq!(u32_at(&raw, 20), 1);
+        assert_eq!(u32_at(&raw, 24), up);
+        assert_eq!(u32_at(&raw, 28), up);
+    }
+
+    #[test]
+    fn nested_attr_layout() {
+        let mut msg = Message::link(libc::RTM_NEWLINK, 0, 0, 0, 0);
+        msg.attr_str(libc::IFLA_IFNAME, "br0")
+            .begin_nested(libc::IFLA_LINKINFO)
+            .attr_str(libc::IFLA_INFO_KIND, "bridge")
+            .end_nested();
+        let raw = msg.finish(1);
+        let attrs = &raw[NLMSG_HDRLEN + IFINFOMSG_LEN..];
+        // "br0\0" fits in 4 bytes, no padding
+        assert_eq!(u16::from_ne_bytes([attrs[0], attrs[1]]), 8);
+        assert_eq!(&attrs[4..8], b"br0\0");
+        // IFLA_LINKINFO wraps IFLA_INFO_KIND ("bridge\0" = 7 bytes, padded to 8)
+        // the nested length includes the padding of the last attribute
+        assert_eq!(u16::from_ne_bytes([attrs[8], attrs[9]]), 4 + 4 + 8);
+        assert_eq!(
+            u16::from_ne_bytes([attrs[10], attrs[11]]),
+            libc::IFLA_LINKINFO
+        );
+        assert_eq!(u16::from_ne_bytes([attrs[12], attrs[13]]), 4 + 7);
+        assert_eq!(raw.len(), NLMSG_HDRLEN + IFINFOMSG_LEN + 8 + 4 + 4 + 8);
+    }
+
+    #[test]
+    fn align_success() {
+        assert_eq!(align(0), 0);
+        assert_eq!(align(5), 8);
+        assert_eq!(align(8), 8);
+    }
+}
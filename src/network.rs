@@ -0,0 +1,807 @@
+use crate::errors::Errcode;
+use crate::netlink::{link_index, NetlinkSocket};
+use crate::state::{container_dir, list, SHORT_ID_LEN};
+
+use clap::ValueEnum;
+use nix::fcntl::{flock, open, FlockArg, OFlag};
+use nix::sched::{setns, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, Pid};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::{self};
+use log::{debug, error, info};
+
+//IPAMのファイルを置くdirectory(<data root>/network/<bridge>.json)
+const NETWORK_DIR: &str = "network";
+//publishしたportを記録するファイル(<data root>/ports.json).
+//bridgeと同じ名前にならないようにdata rootに置く
+const PORTS_FILE: &str = "ports";
+//コンテナ側のveth
+const CONTAINER_IFNAME: &str = "eth0";
+//host側のveth(<prefix><short id>). IFNAMSIZはNULを含むので15文字まで
+const HOST_VETH_PREFIX: &str = "vb";
+const NFT_TABLE: &str = "bowl-rs";
+const IP_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Network of the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NetworkMode {
+    /// Only the loopback interface
+    None,
+    /// veth pair attached to a bridge on the host, with NAT to the outside
+    Bridge,
+    /// Userspace network with slirp4netns, needs no privileges on the host
+    Slirp,
+    /// Network set up by CNI plugins
+    Cni,
+}
+
+/// IPv4 subnet like `172.30.0.0/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ipv4Net {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Net {
+    fn mask(&self) -> u32 {
+        match self.prefix_len {
+            0 => 0,
+            n => u32::MAX << (32 - n),
+        }
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) & self.mask())
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network()) | !self.mask())
+    }
+
+    /// The first host address is given to the bridge.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network()) + 1)
+    }
+
+    /// Addresses that can be given to containers.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let first = u32::from(self.gateway()) + 1;
+        let last = u32::from(self.broadcast());
+        (first..last).map(Ipv4Addr::from)
+    }
+}
+
+impl FromStr for Ipv4Net {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s
+            .split_once('/')
+            .ok_or(Errcode::InvalidArgument("subnet"))?;
+        let addr = addr
+            .parse::<Ipv4Addr>()
+            .map_err(|_| Errcode::InvalidArgument("subnet"))?;
+        let prefix_len = prefix_len
+            .parse::<u8>()
+            .map_err(|_| Errcode::InvalidArgument("subnet"))?;
+        // at least a gateway and one container
+        if prefix_len > 30 {
+            return Err(Errcode::InvalidArgument("subnet"));
+        }
+        let net = Ipv4Net { addr, prefix_len };
+        Ok(Ipv4Net {
+            addr: net.network(),
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Transport protocol of a published port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Port published from the host to the container,
+/// given as `<host port>:<container port>[/tcp|udp]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub host_port: u16,
+    pub container_port: u16,
+    pub protocol: Protocol,
+}
+
+impl FromStr for PortMapping {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some(_) => return Err(Errcode::InvalidArgument("publish")),
+            None => (s, Protocol::Tcp),
+        };
+        let port = |p: &str| match p.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(port),
+            _ => Err(Errcode::InvalidArgument("publish")),
+        };
+        let (host_port, container_port) = ports
+            .split_once(':')
+            .ok_or(Errcode::InvalidArgument("publish"))?;
+        Ok(PortMapping {
+            host_port: port(host_port)?,
+            container_port: port(container_port)?,
+            protocol,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}/{}",
+            self.host_port, self.container_port, self.protocol
+        )
+    }
+}
+
+/// Fail if a host port of `ports` is given twice, or is in `published`.
+fn check_ports(ports: &[PortMapping], published: &[PortMapping]) -> anyhow::Result<()> {
+    let used = |port: &PortMapping, others: &[PortMapping]| {
+        others
+            .iter()
+            .any(|p| p.host_port == port.host_port && p.protocol == port.protocol)
+    };
+    for (i, port) in ports.iter().enumerate() {
+        if used(port, &ports[..i]) {
+            error!("Port {} is published twice", port);
+            return Err(Errcode::InvalidArgument("publish").into());
+        }
+        if used(port, published) {
+            error!(
+                "Host port {}/{} is already in use",
+                port.host_port, port.protocol
+            );
+            return Err(Errcode::NetworkError(21).into());
+        }
+    }
+    Ok(())
+}
+
+/// Reserve the host ports of `ports` for the container `id`, failing if
+/// one is already published by another container. The check and the
+/// reservation hold the same lock, so concurrent runs can not both pass.
+pub fn reserve_ports(data_root: &Path, id: &str, ports: &[PortMapping]) -> anyhow::Result<()> {
+    with_ports(data_root, |reserved| {
+        // containers removed without cleanup
+        reserved.retain(|other, _| container_dir(data_root, other).exists());
+        let mut published: Vec<PortMapping> = reserved
+            .iter()
+            .filter(|(other, _)| *other != id)
+            .flat_map(|(_, ports)| ports.iter().copied())
+            .collect();
+        // containers started before their ports were reserved
+        for state in list(data_root).iter().filter(|state| state.id != id) {
+            let bridge = state.network.as_ref().map(|n| n.ports.as_slice());
+            let slirp = state.slirp.as_ref().map(|s| s.ports.as_slice());
+            let cni = state.cni.as_ref().map(|c| c.ports.as_slice());
+            for ports in [bridge, slirp, cni] {
+                published.extend_from_slice(ports.unwrap_or_default());
+            }
+        }
+        check_ports(ports, &published)?;
+        reserved.insert(id.to_string(), ports.to_vec());
+        Ok(())
+    })
+}
+
+/// Give back the host ports of the container `id`.
+pub fn release_ports(data_root: &Path, id: &str) -> anyhow::Result<()> {
+    with_ports(data_root, |reserved| {
+        reserved.remove(id);
+        Ok(())
+    })
+}
+
+/// Open `path` and lock it, the lock is released when the file is closed.
+fn lock_file(path: &Path) -> anyhow::Result<File> {
+    let lock = match OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+    {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Unable to open lock {:?}: {}", path, e);
+            return Err(Errcode::NetworkError(11).into());
+        }
+    };
+    if let Err(e) = flock(lock.as_raw_fd(), FlockArg::LockExclusive) {
+        error!("Unable to lock {:?}: {:?}", path, e);
+        return Err(Errcode::NetworkError(11).into());
+    }
+    Ok(lock)
+}
+
+/// Run `f` on the host ports published by each container
+/// while holding their lock, and save them afterwards.
+fn with_ports<T>(
+    data_root: &Path,
+    f: impl FnOnce(&mut BTreeMap<String, Vec<PortMapping>>) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    if let Err(e) = fs::create_dir_all(data_root) {
+        error!("Unable to create {:?}: {}", data_root, e);
+        return Err(Errcode::NetworkError(10).into());
+    }
+    let _lock = lock_file(&data_root.join(format!("{}.lock", PORTS_FILE)))?;
+
+    let path = data_root.join(format!("{}.json", PORTS_FILE));
+    let mut reserved = match File::open(&path) {
+        Ok(f) => serde_json::from_reader(f).map_err(|e| {
+            error!("Broken port store {:?}: {}", path, e);
+            Errcode::NetworkError(34)
+        })?,
+        Err(_) => BTreeMap::new(),
+    };
+    let result = f(&mut reserved)?;
+    let written = File::create(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|f| serde_json::to_writer(f, &reserved).map_err(anyhow::Error::from));
+    if let Err(e) = written {
+        error!("Unable to save port store {:?}: {}", path, e);
+        return Err(Errcode::NetworkError(35).into());
+    }
+    Ok(result)
+}
+
+/// Bridge network given on the command line.
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    pub bridge: String,
+    pub subnet: Ipv4Net,
+    pub ports: Vec<PortMapping>,
+}
+
+/// Network set up for a container, saved in the container state
+/// so that it can be torn down.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkState {
+    pub bridge: String,
+    pub host_veth: String,
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    /// Ports published with DNAT rules
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+}
+
+/// Addresses given to the containers of a bridge.
+#[derive(Debug, Serialize, Deserialize)]
+struct Ipam {
+    subnet: Ipv4Net,
+    /// container ID -> address
+    allocations: BTreeMap<String, Ipv4Addr>,
+}
+
+/// Run `f` on the IPAM store of `bridge` while holding its lock,
+/// and save the store afterwards.
+fn with_ipam<T>(
+    data_root: &Path,
+    bridge: &str,
+    subnet: Option<Ipv4Net>,
+    f: impl FnOnce(&mut Ipam) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let dir = data_root.join(NETWORK_DIR);
+    if let Err(e) = fs::create_dir_all(&dir) {
+        error!("Unable to create {:?}: {}", dir, e);
+        return Err(Errcode::NetworkError(10).into());
+    }
+
+    // released when `_lock` is closed
+    let _lock = lock_file(&dir.join(format!("{}.lock", bridge)))?;
+
+    let path = dir.join(format!("{}.json", bridge));
+    let mut ipam: Ipam = match File::open(&path) {
+        Ok(f) => serde_json::from_reader(f).map_err(|e| {
+            error!("Broken IPAM store {:?}: {}", path, e);
+            Errcode::NetworkError(12)
+        })?,
+        Err(_) => match subnet {
+            Some(subnet) => Ipam {
+                subnet,
+                allocations: BTreeMap::new(),
+            },
+            None => {
+                error!("No IPAM store for bridge {}", bridge);
+                return Err(Errcode::NetworkError(12).into());
+            }
+        },
+    };
+    if let Some(subnet) = subnet {
+        if ipam.subnet != subnet {
+            error!(
+                "Bridge {} already uses subnet {}, not {}",
+                bridge, ipam.subnet, subnet
+            );
+            return Err(Errcode::NetworkError(13).into());
+        }
+    }
+
+    let result = f(&mut ipam)?;
+    let written = File::create(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|f| serde_json::to_writer(f, &ipam).map_err(anyhow::Error::from));
+    if let Err(e) = written {
+        error!("Unable to save IPAM store {:?}: {}", path, e);
+        return Err(Errcode::NetworkError(14).into());
+    }
+    Ok(result)
+}
+
+/// Give the first free address of the subnet to the container `id`.
+pub fn allocate_address(
+    data_root: &Path,
+    config: &BridgeConfig,
+    id: &str,
+) -> anyhow::Result<Ipv4Addr> {
+    with_ipam(data_root, &config.bridge, Some(config.subnet), |ipam| {
+        if let Some(addr) = ipam.allocations.get(id) {
+            return Ok(*addr);
+        }
+        let used: Vec<&Ipv4Addr> = ipam.allocations.values().collect();
+        match ipam.subnet.hosts().find(|addr| !used.contains(&addr)) {
+            Some(addr) => {
+                ipam.allocations.insert(id.to_string(), addr);
+                Ok(addr)
+            }
+            None => {
+                error!("No address left in {}", ipam.subnet);
+                Err(Errcode::NetworkError(15).into())
+            }
+        }
+    })
+}
+
+/// Give back the address of the container `id`.
+pub fn release_address(data_root: &Path, bridge: &str, id: &str) -> anyhow::Result<()> {
+    with_ipam(data_root, bridge, None, |ipam| {
+        ipam.allocations.remove(id);
+        Ok(())
+    })
+}
+
+/// Run `f` inside the network namespace of `pid` and come back.
+/// Netlink sockets opened in `f` keep talking to that namespace.
+fn in_netns<T>(pid: Pid, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let open_ns = |path: String| -> anyhow::Result<RawFd> {
+        open(
+            path.as_str(),
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|e| {
+            error!("Unable to open {}: {:?}", path, e);
+            Errcode::NetworkError(16).into()
+        })
+    };
+    let host_ns = open_ns("/proc/self/ns/net".to_string())?;
+    let container_ns = match open_ns(format!("/proc/{}/ns/net", pid.as_raw())) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let _ = close(host_ns);
+            return Err(e);
+        }
+    };
+
+    let result = match setns(container_ns, CloneFlags::CLONE_NEWNET) {
+        Ok(_) => f(),
+        Err(e) => {
+            error!("Unable to enter network namespace of {}: {:?}", pid, e);
+            Err(Errcode::NetworkError(17).into())
+        }
+    };
+    let back = setns(host_ns, CloneFlags::CLONE_NEWNET);
+    let _ = close(container_ns);
+    let _ = close(host_ns);
+    if let Err(e) = back {
+        error!("Unable to go back to the host network namespace: {:?}", e);
+        return Err(Errcode::NetworkError(17).into());
+    }
+    result
+}
+
+/// nftables ruleset masquerading the traffic of the subnet
+/// and accepting it in the forward hook.
+/// The chains are per bridge and flushed first so that it can be applied again.
+pub fn nat_ruleset(config: &BridgeConfig) -> String {
+    let br = &config.bridge;
+    let mut rules = String::new();
+    rules.push_str(&format!("add table ip {}\n", NFT_TABLE));
+    rules.push_str(&format!(
+        "add chain ip {} postrouting-{} {{ type nat hook postrouting priority 100 ; }}\n",
+        NFT_TABLE, br
+    ));
+    rules.push_str(&format!(
+        "add chain ip {} forward-{} {{ type filter hook forward priority 0 ; }}\n",
+        NFT_TABLE, br
+    ));
+    rules.push_str(&format!(
+        "flush chain ip {} postrouting-{}\n",
+        NFT_TABLE, br
+    ));
+    rules.push_str(&format!("flush chain ip {} forward-{}\n", NFT_TABLE, br));
+    rules.push_str(&format!(
+        "add rule ip {} postrouting-{} ip saddr {} oifname != \"{}\" masquerade\n",
+        NFT_TABLE, br, config.subnet, br
+    ));
+    rules.push_str(&format!(
+        "add rule ip {} forward-{} iifname \"{}\" accept\n",
+        NFT_TABLE, br, br
+    ));
+    rules.push_str(&format!(
+        "add rule ip {} forward-{} oifname \"{}\" ct state related,established accept\n",
+        NFT_TABLE, br, br
+    ));
+    // published ports
+    rules.push_str(&format!(
+        "add rule ip {} forward-{} oifname \"{}\" ct status dnat accept\n",
+        NFT_TABLE, br, br
+    ));
+    rules
+}
+
+/// nftables ruleset publishing the ports of a container.
+/// Connections to a local address of the host are forwarded to the container,
+/// from outside (prerouting) as well as from the host itself (output).
+/// The chains are per container (named after the host veth)
+/// so that they can simply be deleted on cleanup.
+/// Loopback addresses are not forwarded, it would need `route_localnet`.
+pub fn ports_ruleset(state: &NetworkState) -> String {
+    let veth = &state.host_veth;
+    let mut rules = String::new();
+    rules.push_str(&format!("add table ip {}\n", NFT_TABLE));
+    rules.push_str(&format!(
+        "add chain ip {} ports-{} {{ type nat hook prerouting priority -100 ; }}\n",
+        NFT_TABLE, veth
+    ));
+    rules.push_str(&format!(
+        "add chain ip {} ports-out-{} {{ type nat hook output priority -100 ; }}\n",
+        NFT_TABLE, veth
+    ));
+    for port in state.ports.iter() {
+        let dnat = format!(
+            "fib daddr type local {} dport {} dnat to {}:{}",
+            port.protocol, port.host_port, state.address, port.container_port
+        );
+        rules.push_str(&format!(
+            "add rule ip {} ports-{} {}\n",
+            NFT_TABLE, veth, dnat
+        ));
+        rules.push_str(&format!(
+            "add rule ip {} ports-out-{} ip daddr != 127.0.0.0/8 {}\n",
+            NFT_TABLE, veth, dnat
+        ));
+    }
+    rules
+}
+
+/// nftables ruleset removing the chains of `ports_ruleset`.
+fn ports_cleanup_ruleset(state: &NetworkState) -> String {
+    format!(
+        "delete chain ip {table} ports-{veth}\ndelete chain ip {table} ports-out-{veth}\n",
+        table = NFT_TABLE,
+        veth = state.host_veth
+    )
+}
+
+/// Apply a ruleset with `nft -f -`.
+pub fn apply_nft(ruleset: &str) -> anyhow::Result<()> {
+    debug!("nft ruleset:\n{}", ruleset);
+    let child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Unable to run nft: {}", e);
+            return Err(Errcode::NetworkError(18).into());
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(ruleset.as_bytes()) {
+            error!("Unable to write nft ruleset: {}", e);
+        }
+    }
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        _ => {
+            error!("nft failed to apply the ruleset");
+            Err(Errcode::NetworkError(19).into())
+        }
+    }
+}
+
+/// Create the bridge with the gateway address, and enable forwarding and NAT.
+fn setup_bridge(config: &BridgeConfig) -> anyhow::Result<i32> {
+    let mut nl = NetlinkSocket::open()?;
+    nl.create_bridge(&config.bridge)?;
+    let bridge = link_index(&config.bridge)?;
+    nl.add_address(bridge, config.subnet.gateway(), config.subnet.prefix_len)?;
+    nl.set_link_up(bridge)?;
+
+    if let Err(e) = fs::write(IP_FORWARD, "1") {
+        error!("Unable to enable IP forwarding: {}", e);
+        return Err(Errcode::NetworkError(20).into());
+    }
+    apply_nft(&nat_ruleset(config))?;
+    Ok(bridge)
+}
+
+/// Connect the container `pid` to the bridge network.
+/// 1.Allocate an address from the subnet
+/// 2.Create the bridge (once) with forwarding and NAT
+/// 3.Create a veth pair, the peer directly in the container as eth0
+/// 4.Configure eth0 and the default route inside the container
+pub fn setup_bridge_network(
+    data_root: &Path,
+    config: &BridgeConfig,
+    id: &str,
+    pid: Pid,
+) -> anyhow::Result<NetworkState> {
+    debug!("Setting up bridge network {}", config.bridge);
+    let address = allocate_address(data_root, config, id)?;
+    let state = NetworkState {
+        bridge: config.bridge.clone(),
+        host_veth: host_veth_name(id),
+        address,
+        prefix_len: config.subnet.prefix_len,
+        gateway: config.subnet.gateway(),
+        ports: config.ports.clone(),
+    };
+
+    let result = setup_bridge(config).and_then(|bridge| {
+        let mut nl = NetlinkSocket::open()?;
+        nl.create_veth(&state.host_veth, CONTAINER_IFNAME, pid.as_raw())?;
+        let veth = link_index(&state.host_veth)?;
+        nl.set_master(veth, bridge)?;
+        nl.set_link_up(veth)?;
+
+        in_netns(pid, || {
+            let mut nl = NetlinkSocket::open()?;
+            let eth = link_index(CONTAINER_IFNAME)?;
+            nl.add_address(eth, state.address, state.prefix_len)?;
+            nl.set_link_up(eth)?;
+            nl.add_default_route(state.gateway, eth)
+        })?;
+
+        if !state.ports.is_empty() {
+            apply_nft(&ports_ruleset(&state))?;
+        }
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        if let Err(e) = teardown_bridge_network(data_root, &state, id) {
+            error!("Unable to clean up network: {}", e);
+        }
+        return Err(e);
+    }
+    info!(
+        "Container address {}/{} on {}",
+        state.address, state.prefix_len, state.bridge
+    );
+    for port in state.ports.iter() {
+        info!("Publishing {}", port);
+    }
+    Ok(state)
+}
+
+/// Host side veth of the container `id`.
+/// Short IDs are unique among the containers (see `new_id`).
+fn host_veth_name(id: &str) -> String {
+    format!("{}{}", HOST_VETH_PREFIX, &id[..SHORT_ID_LEN.min(id.len())])
+}
+
+/// Delete the port forwarding and the veth pair, and release the address.
+/// The address is released even if the veth could not be deleted.
+/// The bridge and NAT rules are shared with the other containers and kept.
+pub fn teardown_bridge_network(
+    data_root: &Path,
+    state: &NetworkState,
+    id: &str,
+) -> anyhow::Result<()> {
+    debug!("Tearing down network of {}", state.host_veth);
+    if !state.ports.is_empty() {
+        // the chains are missing if the setup failed half way
+        if let Err(e) = apply_nft(&ports_cleanup_ruleset(state)) {
+            error!("Unable to remove published ports: {}", e);
+        }
+    }
+    let deleted = NetlinkSocket::open().and_then(|mut nl| nl.delete_link(&state.host_veth));
+    let released = release_address(data_root, &state.bridge, id);
+    deleted.and(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cni::CniState;
+    use crate::mount::test_dir;
+    use crate::state::{create_container_dir, ContainerState};
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    #[test]
+    fn ipv4net_parse() {
+        let net: Ipv4Net = "172.30.5.9/16".parse().unwrap();
+        assert_eq!(net.to_string(), "172.30.0.0/16");
+        assert_eq!(net.gateway(), Ipv4Addr::new(172, 30, 0, 1));
+        assert_eq!(net.broadcast(), Ipv4Addr::new(172, 30, 255, 255));
+        let net: Ipv4Net = "10.0.0.0/30".parse().unwrap();
+        let hosts: Vec<Ipv4Addr> = net.hosts().collect();
+        assert_eq!(hosts, vec![Ipv4Addr::new(10, 0, 0, 2)]);
+        assert!("10.0.0.0/31".parse::<Ipv4Net>().is_err());
+        assert!("10.0.0.0".parse::<Ipv4Net>().is_err());
+        assert!("10.0.0/24".parse::<Ipv4Net>().is_err());
+    }
+
+    #[test]
+    fn ipam_allocate_release() {
+        let root = test_dir();
+        let config = BridgeConfig {
+            bridge: "br-test".to_string(),
+            subnet: "10.0.0.0/29".parse().unwrap(),
+            ports: vec![],
+        };
+        let a = allocate_address(&root, &config, "a").unwrap();
+        let b = allocate_address(&root, &config, "b").unwrap();
+        assert_eq!(a, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(b, Ipv4Addr::new(10, 0, 0, 3));
+        // same container, same address
+        assert_eq!(allocate_address(&root, &config, "a").unwrap(), a);
+
+        release_address(&root, "br-test", "a").unwrap();
+        assert_eq!(allocate_address(&root, &config, "c").unwrap(), a);
+
+        // 10.0.0.4 - 10.0.0.6 left
+        for id in ["d", "e", "f"] {
+            allocate_address(&root, &config, id).unwrap();
+        }
+        assert!(allocate_address(&root, &config, "g").is_err());
+
+        let other = BridgeConfig {
+            bridge: "br-test".to_string(),
+            subnet: "10.1.0.0/24".parse().unwrap(),
+            ports: vec![],
+        };
+        assert!(allocate_address(&root, &other, "h").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn nat_ruleset_success() {
+        let config = BridgeConfig {
+            bridge: "bowl0".to_string(),
+            subnet: "172.30.0.0/16".parse().unwrap(),
+            ports: vec![],
+        };
+        let rules = nat_ruleset(&config);
+        assert!(rules.contains(
+            "add rule ip bowl-rs postrouting-bowl0 ip saddr 172.30.0.0/16 oifname != \"bowl0\" masquerade\n"
+        ));
+        assert!(rules.contains("flush chain ip bowl-rs forward-bowl0\n"));
+    }
+
+    #[test]
+    fn port_mapping_parse() {
+        let port: PortMapping = "8080:80".parse().unwrap();
+        assert_eq!(port.to_string(), "8080:80/tcp");
+        let port: PortMapping = "5353:53/udp".parse().unwrap();
+        assert_eq!(port.protocol, Protocol::Udp);
+        assert!("8080".parse::<PortMapping>().is_err());
+        assert!("0:80".parse::<PortMapping>().is_err());
+        assert!("8080:80/sctp".parse::<PortMapping>().is_err());
+        assert!("70000:80".parse::<PortMapping>().is_err());
+    }
+
+    #[test]
+    fn host_veth_name_success() {
+        let id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        assert_eq!(host_veth_name(id), "vb0123456789ab");
+        assert!(host_veth_name(id).len() < libc::IFNAMSIZ);
+    }
+
+    #[test]
+    fn ports_ruleset_success() {
+        let state = NetworkState {
+            bridge: "bowl0".to_string(),
+            host_veth: "vb0123456789ab".to_string(),
+            address: Ipv4Addr::new(172, 30, 0, 2),
+            prefix_len: 16,
+            gateway: Ipv4Addr::new(172, 30, 0, 1),
+            ports: vec!["8080:80".parse().unwrap()],
+        };
+        let rules = ports_ruleset(&state);
+        assert!(rules.contains(
+            "add rule ip bowl-rs ports-vb0123456789ab fib daddr type local tcp dport 8080 dnat to 172.30.0.2:80\n"
+        ));
+        assert!(rules.contains("ports-out-vb0123456789ab ip daddr != 127.0.0.0/8"));
+        assert_eq!(
+            ports_cleanup_ruleset(&state),
+            "delete chain ip bowl-rs ports-vb0123456789ab\ndelete chain ip bowl-rs ports-out-vb0123456789ab\n"
+        );
+        assert!(check_ports(&state.ports, &[]).is_ok());
+        let twice = vec![state.ports[0], "8080:81".parse().unwrap()];
+        assert!(check_ports(&twice, &[]).is_err());
+    }
+    #[test]
+    fn ports_reserve_release() {
+        let root = test_dir();
+        let (a, b, c) = ("a".repeat(64), "b".repeat(64), "c".repeat(64));
+        for id in [&a, &b] {
+            create_container_dir(&root, id).unwrap();
+        }
+        let web: Vec<PortMapping> = vec!["8080:80".parse().unwrap()];
+        reserve_ports(&root, &a, &web).unwrap();
+        assert!(reserve_ports(&root, &b, &["8080:81".parse().unwrap()]).is_err());
+        assert!(reserve_ports(&root, &b, &["8080:80/udp".parse().unwrap()]).is_ok());
+        release_ports(&root, &a).unwrap();
+        assert!(reserve_ports(&root, &b, &web).is_ok());
+        // b was removed without cleanup
+        fs::remove_dir_all(container_dir(&root, &b)).unwrap();
+        assert!(reserve_ports(&root, &c, &web).is_ok());
+
+        // ports of a CNI container started before they were reserved
+        let mut state = ContainerState::new(
+            a.clone(),
+            None,
+            "host".to_string(),
+            "/bin/sh".to_string(),
+            PathBuf::from("/"),
+        );
+        state.cni = Some(CniState {
+            network: "bowl-cni".to_string(),
+            bin_dir: PathBuf::from("/opt/cni/bin"),
+            netns: PathBuf::from("/proc/1/ns/net"),
+            ifname: "eth0".to_string(),
+            config: Value::Null,
+            ports: vec!["9090:90".parse().unwrap()],
+            result: Value::Null,
+        });
+        state.save(&root).unwrap();
+        assert!(reserve_ports(&root, &c, &["9090:91".parse().unwrap()]).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+use crate::errors::Errcode;
+use crate::seccomp::syscall_name;
+
+use libc::{seccomp_notif, seccomp_notif_resp, seccomp_notif_sizes};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{read_to_string, File};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use syscallz::Syscall;
+
+use anyhow::{self};
+use log::{debug, error, info, warn};
+
+//device nodeとして作成を許可するもの(major, minor)
+//null, zero, full, random, urandom, tty
+const ALLOWED_DEVICES: [(u32, u32); 6] = [(1, 3), (1, 5), (1, 7), (1, 8), (1, 9), (5, 0)];
+//読み込む文字列(path)の最大長
+const PATH_MAX: usize = 4096;
+const PAGE_SIZE: u64 = 4096;
+
+/// What the supervisor does with a notified syscall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyAction {
+    /// Log the call and let the kernel run it
+    Log,
+    /// Fail with EPERM
+    Deny,
+    /// Run the call on behalf of the container (mknod of allowed device nodes)
+    Emulate,
+}
+
+/// `--seccomp-notify <syscall>[=log|deny|emulate]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotifyRule {
+    pub syscall: Syscall,
+    pub action: NotifyAction,
+}
+
+impl FromStr for NotifyRule {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, action) = s.split_once('=').unwrap_or((s, "log"));
+        let syscall = Syscall::from_name(name).ok_or(Errcode::InvalidArgument("seccomp-notify"))?;
+        // the listener is sent to the supervisor with sendmsg once the filter is loaded,
+        // notifying it would wait for a supervisor that never gets the listener
+        if syscall == Syscall::sendmsg {
+            return Err(Errcode::InvalidArgument("seccomp-notify"));
+        }
+        let action = match action {
+            "log" => NotifyAction::Log,
+            "deny" => NotifyAction::Deny,
+            "emulate" if matches!(syscall, Syscall::mknod | Syscall::mknodat) => {
+                NotifyAction::Emulate
+            }
+            _ => return Err(Errcode::InvalidArgument("seccomp-notify")),
+        };
+        Ok(NotifyRule { syscall, action })
+    }
+}
+
+/// Reply to a notification.
+#[derive(Debug, PartialEq)]
+enum Response {
+    /// Let the kernel run the call
+    Continue,
+    /// Return without running the call
+    Value(i64),
+    Error(Errno),
+}
+
+/// Start the thread answering the notifications of `listener`.
+/// It stops once no process uses the filter any more.
+pub fn start_supervisor(listener: RawFd, rules: Vec<NotifyRule>) -> anyhow::Result<JoinHandle<()>> {
+    // the kernel writes its own struct size, refuse if ours is smaller
+    let mut sizes: seccomp_notif_sizes = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_GET_NOTIF_SIZES,
+            0,
+            &mut sizes as *mut seccomp_notif_sizes,
+        )
+    };
+    if ret != 0
+        || sizes.seccomp_notif as usize > std::mem::size_of::<seccomp_notif>()
+        || sizes.seccomp_notif_resp as usize > std::mem::size_of::<seccomp_notif_resp>()
+    {
+        error!("Unsupported seccomp notification sizes {:?}", sizes);
+        let _ = close(listener);
+        return Err(Errcode::SyscallsError(9).into());
+    }
+    debug!("Starting seccomp supervisor");
+    Ok(thread::spawn(move || {
+        supervise(listener, &rules);
+        let _ = close(listener);
+        debug!("Seccomp supervisor stopped");
+    }))
+}
+
+fn supervise(listener: RawFd, rules: &[NotifyRule]) {
+    loop {
+        let mut fds = [PollFd::new(listener, PollFlags::POLLIN)];
+        match poll(&mut fds, -1) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(_) => return,
+        }
+        let revents = fds[0].revents().unwrap_or(PollFlags::POLLHUP);
+        if !revents.contains(PollFlags::POLLIN) {
+            // no process left
+            return;
+        }
+
+        let mut req: seccomp_notif = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(listener, libc::SECCOMP_IOCTL_NOTIF_RECV, &mut req) } != 0 {
+            // the process was killed before we received it
+            continue;
+        }
+        let rule = rules.iter().find(|r| r.syscall.into_i32() == req.data.nr);
+        let response = match rule {
+            Some(rule) => handle(listener, &req, rule.action),
+            None => Some(Response::Error(Errno::EPERM)),
+        };
+        let Some(response) = response else {
+            continue;
+        };
+        let mut resp: seccomp_notif_resp = unsafe { std::mem::zeroed() };
+        resp.id = req.id;
+        match response {
+            Response::Continue => resp.flags = libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32,
+            Response::Value(val) => resp.val = val,
+            Response::Error(errno) => resp.error = -(errno as i32),
+        }
+        if unsafe { libc::ioctl(listener, libc::SECCOMP_IOCTL_NOTIF_SEND, &mut resp) } != 0 {
+            debug!("Seccomp notification {} is gone", req.id);
+        }
+    }
+}
+
+/// None if the process is gone while handling it.
+fn handle(listener: RawFd, req: &seccomp_notif, action: NotifyAction) -> Option<Response> {
+    let call = Call::read(req);
+    if !id_valid(listener, req.id) {
+        return None;
+    }
+    match action {
+        NotifyAction::Log => {
+            info!("seccomp: pid {} {}", req.pid, call);
+            Some(Response::Continue)
+        }
+        NotifyAction::Deny => {
+            info!("seccomp: pid {} {} denied", req.pid, call);
+            Some(Response::Error(Errno::EPERM))
+        }
+        NotifyAction::Emulate => {
+            let response = emulate_mknod(listener, req);
+            info!("seccomp: pid {} {} emulated: {:?}", req.pid, call, response);
+            response
+        }
+    }
+}
+
+/// The notification is still pending, so its pid is the same process.
+/// Checked after anything read from /proc/<pid>.
+fn id_valid(listener: RawFd, id: u64) -> bool {
+    unsafe { libc::ioctl(listener, libc::SECCOMP_IOCTL_NOTIF_ID_VALID, &id) == 0 }
+}
+
+/// Read a NUL terminated string from the memory of `pid`.
+fn read_string(pid: u32, addr: u64) -> Result<String, Errno> {
+    if addr == 0 {
+        return Err(Errno::EFAULT);
+    }
+    let mem = File::open(format!("/proc/{}/mem", pid)).map_err(|_| Errno::ESRCH)?;
+    let mut bytes = vec![];
+    let mut offset = addr;
+    while bytes.len() < PATH_MAX {
+        // read page by page, the string may end just before an unmapped page
+        let mut buf = vec![0u8; (PAGE_SIZE - offset % PAGE_SIZE) as usize];
+        let len = mem.read_at(&mut buf, offset).map_err(|_| Errno::EFAULT)?;
+        if len == 0 {
+            return Err(Errno::EFAULT);
+        }
+        if let Some(end) = buf[..len].iter().position(|b| *b == 0) {
+            bytes.extend_from_slice(&buf[..end]);
+            return Ok(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        bytes.extend_from_slice(&buf[..len]);
+        offset += len as u64;
+    }
+    Err(Errno::ENAMETOOLONG)
+}
+
+/// Notified call with its arguments, for the logs.
+struct Call {
+    name: String,
+    args: Vec<String>,
+}
+
+impl Call {
+    fn read(req: &seccomp_notif) -> Call {
+        let nr = req.data.nr;
+        // (syscall, arguments shown, string arguments)
+        let known: [(Syscall, usize, &[usize]); 4] = [
+            (Syscall::mount, 4, &[0, 1, 2]),
+            (Syscall::umount2, 2, &[0]),
+            (Syscall::mknod, 3, &[0]),
+            (Syscall::mknodat, 4, &[1]),
+        ];
+        let (argc, strings) = known
+            .iter()
+            .find(|(sc, _, _)| sc.into_i32() == nr)
+            .map(|(_, argc, strings)| (*argc, *strings))
+            .unwrap_or((6, &[]));
+        let args = req.data.args[..argc]
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| match (strings.contains(&i), *arg) {
+                (false, arg) => format!("{:#x}", arg),
+                (true, 0) => "NULL".to_string(),
+                (true, addr) => read_string(req.pid, addr)
+                    .map(|s| format!("{:?}", s))
+                    .unwrap_or_else(|e| format!("<{}>", e)),
+            })
+            .collect();
+        Call {
+            name: syscall_name(0, nr).unwrap_or_else(|| format!("syscall {}", nr)),
+            args,
+        }
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.name, self.args.join(", "))
+    }
+}
+
+/// Field of /proc/<pid>/status, e.g. the fs uid is `status_field(s, "Uid:", 3)`.
+fn status_field(status: &str, key: &str, index: usize) -> Option<u32> {
+    status
+        .lines()
+        .find(|l| l.starts_with(key))
+        .and_then(|l| l.split_ascii_whitespace().nth(index + 1))
+        .and_then(|v| v.parse().ok())
+}
+
+/// Whether mknod may create this node on behalf of the container.
+/// Other file types (fifo, socket, regular) need no privilege and are run by the kernel.
+fn allowed_device(mode: u32, dev: u64) -> Option<bool> {
+    match mode & libc::S_IFMT {
+        libc::S_IFCHR => Some(ALLOWED_DEVICES.contains(&(libc::major(dev), libc::minor(dev)))),
+        libc::S_IFBLK => Some(false),
+        _ => None,
+    }
+}
+
+/// Split a path into its parent directory and the last component.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => (".", path),
+    };
+    match name {
+        "" | "." | ".." => None,
+        _ => Some((parent, name)),
+    }
+}
+
+/// Create an allowed device node in the container.
+/// The path is resolved with RESOLVE_IN_ROOT under the root (or the working
+/// directory) of the process, so symlinks can not lead out of the container.
+fn emulate_mknod(listener: RawFd, req: &seccomp_notif) -> Option<Response> {
+    let args = req.data.args;
+    let (dirfd, path, mode, dev) = if req.data.nr == Syscall::mknod.into_i32() {
+        (libc::AT_FDCWD, args[0], args[1] as u32, args[2])
+    } else {
+        (args[0] as i32, args[1], args[2] as u32, args[3])
+    };
+    match allowed_device(mode, dev) {
+        None => return Some(Response::Continue),
+        Some(false) => return Some(Response::Error(Errno::EPERM)),
+        Some(true) => {}
+    }
+    let path = match read_string(req.pid, path) {
+        Ok(path) => path,
+        Err(e) => return Some(Response::Error(e)),
+    };
+    let Some((parent, name)) = split_path(&path) else {
+        return Some(Response::Error(Errno::EEXIST));
+    };
+    let base = if path.starts_with('/') {
+        format!("/proc/{}/root", req.pid)
+    } else if dirfd == libc::AT_FDCWD {
+        format!("/proc/{}/cwd", req.pid)
+    } else {
+        format!("/proc/{}/fd/{}", req.pid, dirfd)
+    };
+    let status = read_to_string(format!("/proc/{}/status", req.pid)).unwrap_or_default();
+    let base = open(
+        base.as_str(),
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    );
+    // everything about the process has been read, check it is still the same one
+    if !id_valid(listener, req.id) {
+        if let Ok(fd) = base {
+            let _ = close(fd);
+        }
+        return None;
+    }
+    let base = match base {
+        Ok(fd) => fd,
+        Err(e) => return Some(Response::Error(e)),
+    };
+    let result = mknod_in(base, parent, name, mode, dev, &status);
+    let _ = close(base);
+    Some(match result {
+        Ok(_) => Response::Value(0),
+        Err(e) => Response::Error(e),
+    })
+}
+
+fn mknod_in(
+    base: RawFd,
+    parent: &str,
+    name: &str,
+    mode: u32,
+    dev: u64,
+    status: &str,
+) -> Result<(), Errno> {
+    let parent = CString::new(parent.trim_start_matches('/')).map_err(|_| Errno::EINVAL)?;
+    let parent = if parent.as_bytes().is_empty() {
+        CString::new(".").unwrap()
+    } else {
+        parent
+    };
+    let name = CString::new(name).map_err(|_| Errno::EINVAL)?;
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+    let dir = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            base,
+            parent.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if dir < 0 {
+        return Err(Errno::last());
+    }
+    let dir = dir as RawFd;
+
+    let umask = status
+        .lines()
+        .find(|l| l.starts_with("Umask:"))
+        .and_then(|l| u32::from_str_radix(l[6..].trim(), 8).ok())
+        .unwrap_or(0o022);
+    let uid = status_field(status, "Uid:", 3).unwrap_or(0);
+    let gid = status_field(status, "Gid:", 3).unwrap_or(0);
+    let perm = mode & 0o777 & !umask;
+    let result = Errno::result(unsafe {
+        libc::mknodat(dir, name.as_ptr(), libc::S_IFCHR | perm, dev as libc::dev_t)
+    })
+    .and_then(|_| {
+        // owned by the process, not by the root of the host
+        Errno::result(unsafe {
+            libc::fchownat(dir, name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW)
+        })
+    });
+    if let Err(e) = result {
+        warn!("Unable to create device node {:?}: {}", name, e);
+    }
+    let _ = close(dir);
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_rule_parse() {
+        assert_eq!(
+            "mount".parse::<NotifyRule>().unwrap(),
+            NotifyRule {
+                syscall: Syscall::mount,
+                action: NotifyAction::Log
+            }
+        );
+        assert_eq!(
+            "mknodat=emulate".parse::<NotifyRule>().unwrap().action,
+            NotifyAction::Emulate
+        );
+        assert_eq!(
+            "unshare=deny".parse::<NotifyRule>().unwrap().action,
+            NotifyAction::Deny
+        );
+        assert!("mount=emulate".parse::<NotifyRule>().is_err());
+        assert!("no_such_syscall".parse::<NotifyRule>().is_err());
+        assert!("mount=foo".parse::<NotifyRule>().is_err());
+        assert!("sendmsg".parse::<NotifyRule>().is_err());
+    }
+
+    #[test]
+    fn allowed_device_success() {
+        assert_eq!(
+            allowed_device(libc::S_IFCHR | 0o666, libc::makedev(1, 3)),
+            Some(true)
+        );
+        assert_eq!(
+            allowed_device(libc::S_IFCHR | 0o666, libc::makedev(5, 0)),
+            Some(true)
+        );
+        assert_eq!(
+            allowed_device(libc::S_IFCHR | 0o600, libc::makedev(1, 1)),
+            Some(false)
+        );
+        assert_eq!(
+            allowed_device(libc::S_IFBLK | 0o600, libc::makedev(8, 0)),
+            Some(false)
+        );
+        assert_eq!(allowed_device(libc::S_IFIFO | 0o600, 0), None);
+    }
+
+    #[test]
+    fn split_path_success() {
+        assert_eq!(split_path("/dev/null"), Some(("/dev", "null")));
+        assert_eq!(split_path("/null"), Some(("/", "null")));
+        assert_eq!(split_path("null"), Some((".", "null")));
+        assert_eq!(split_path("dev/null/"), Some(("dev", "null")));
+        assert_eq!(split_path("/dev/.."), None);
+        assert_eq!(split_path("/"), None);
+    }
+
+    #[test]
+    fn read_string_success() {
+        let s = CString::new("/dev/null").unwrap();
+        let pid = std::process::id();
+        assert_eq!(read_string(pid, s.as_ptr() as u64).unwrap(), "/dev/null");
+        assert_eq!(read_string(pid, 0), Err(Errno::EFAULT));
+    }
+
+    #[test]
+    fn status_field_success() {
+        let status = "Name:\tsh\nUmask:\t0022\nUid:\t10000\t10000\t10000\t10001\nGid:\t10000\t10000\t10000\t10000\n";
+        assert_eq!(status_field(status, "Uid:", 3), Some(10001));
+        assert_eq!(status_field(status, "Gid:", 0), Some(10000));
+        assert_eq!(status_field(status, "Foo:", 0), None);
+    }
+}
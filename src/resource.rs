@@ -1,80 +1,910 @@
 use crate::errors::Errcode;
+use crate::state::lookup;
 
-use cgroups_rs::cgroup_builder::CgroupBuilder;
-use cgroups_rs::hierarchies::V2;
-use cgroups_rs::{CgroupPid, MaxValue};
+use capctl::caps::{Cap, CapState};
+use cgroups_rs::blkio::BlkIoController;
+use cgroups_rs::cpu::CpuController;
+use cgroups_rs::cpuset::CpuSetController;
+use cgroups_rs::hierarchies::auto;
+use cgroups_rs::memory::MemController;
+use cgroups_rs::pid::PidController;
+use cgroups_rs::{Cgroup, CgroupPid, Controller, Hierarchy, MaxValue, Subsystem};
+use clap::Args;
 use nix::unistd::Pid;
-use rlimit::{setrlimit, Resource};
+use rlimit::{getrlimit, setrlimit, Resource, INFINITY};
 
+use std::collections::BTreeSet;
 use std::convert::TryInto;
-use std::fs::{canonicalize, remove_dir};
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::{self};
-use log::{debug};
+use log::{debug, error, info};
 
-//                       KiB    MiB    Gib
-const KMEM_LIMIT: i64 = 1024 * 1024 * 1024;
-const MEM_LIMIT: i64 = KMEM_LIMIT;
-const MAX_PID: MaxValue = MaxValue::Value(64);
+//                      KiB    MiB    Gib
+const MEM_LIMIT: i64 = 1024 * 1024 * 1024;
+const CPU_SHARES: u64 = 256;
+const MAX_PID: i64 = 64;
+const IO_WEIGHT: u16 = 50;
+//--ulimit nofileを指定しない場合のfile descriptorの数
 const NOFILE_RLIMIT: u64 = 64;
+//これより小さいとコンテナのプロセスを起動できない
+const MIN_MEMORY: i64 = 6 * 1024 * 1024;
+//--cpusのquotaを計算するperiod(us)
+const CPU_PERIOD: u64 = 100_000;
+//kernelが受け付けるquotaの最小値(us)
+const MIN_CPU_QUOTA: u64 = 1000;
+//v1で無制限の場合の値(PAGE_COUNTER_MAX,i64::MAXをpage単位に切り捨てた値)以上
+pub const UNLIMITED_MEMORY: i64 = i64::MAX - 4096;
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+const ONLINE_NODES: &str = "/sys/devices/system/node/online";
+//コンテナのcgroupを作るcontroller(v1ではcontrollerごとに別のhierarchyにdirectoryを作る)
+//cpuacctはv1でCPU使用量を読むために使う
+const CONTROLLERS: [&str; 8] = [
+    "cpu", "cpuacct", "memory", "pids", "blkio", "cpuset", "devices", "freezer",
+];
+
+/// Byte size with an optional unit (b, k, m, g, t), e.g. `512m` or `1.5g`.
+/// `-1` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub i64);
+
+impl FromStr for ByteSize {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "-1" {
+            return Ok(ByteSize(-1));
+        }
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let shift = match unit.trim_end_matches("ib").trim_end_matches('b') {
+            "" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            "t" => 40,
+            _ => return Err(Errcode::InvalidArgument("size")),
+        };
+        let bytes = number
+            .parse::<f64>()
+            .map(|n| n * (1u64 << shift) as f64)
+            .map_err(|_| Errcode::InvalidArgument("size"))?;
+        if !bytes.is_finite() || bytes < 1.0 || bytes >= i64::MAX as f64 {
+            return Err(Errcode::InvalidArgument("size"));
+        }
+        Ok(ByteSize(bytes as i64))
+    }
+}
+
+/// Bandwidth limit of a block device, the same format as the io.max file:
+/// `<major>:<minor> [rbps=<size>] [wbps=<size>] [riops=<n>] [wiops=<n>]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoMax {
+    pub major: u64,
+    pub minor: u64,
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+impl FromStr for IoMax {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        //shellで引用符なしでも渡せるようにカンマ区切りも受け付ける
+        let mut fields = s.split([' ', ',']).filter(|f| !f.is_empty());
+        let (major, minor) = fields
+            .next()
+            .and_then(|device| device.split_once(':'))
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+            .ok_or(Errcode::InvalidArgument("io-max"))?;
+        let mut io_max = IoMax {
+            major,
+            minor,
+            rbps: None,
+            wbps: None,
+            riops: None,
+            wiops: None,
+        };
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or(Errcode::InvalidArgument("io-max"))?;
+            let bytes = || match value.parse::<ByteSize>() {
+                Ok(ByteSize(bytes)) if bytes > 0 => Ok(Some(bytes as u64)),
+                _ => Err(Errcode::InvalidArgument("io-max")),
+            };
+            let ops = || match value.parse::<u64>() {
+                Ok(ops) if ops > 0 => Ok(Some(ops)),
+                _ => Err(Errcode::InvalidArgument("io-max")),
+            };
+            match key {
+                "rbps" => io_max.rbps = bytes()?,
+                "wbps" => io_max.wbps = bytes()?,
+                "riops" => io_max.riops = ops()?,
+                "wiops" => io_max.wiops = ops()?,
+                _ => return Err(Errcode::InvalidArgument("io-max")),
+            }
+        }
+        if io_max.rbps.is_none()
+            && io_max.wbps.is_none()
+            && io_max.riops.is_none()
+            && io_max.wiops.is_none()
+        {
+            return Err(Errcode::InvalidArgument("io-max"));
+        }
+        Ok(io_max)
+    }
+}
+
+impl fmt::Display for IoMax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.major, self.minor)
+    }
+}
+
+/// Resource limit of the container process, `<name>=<soft>[:<hard>]`
+/// e.g. `nofile=1024:4096` or `core=0`. `unlimited` or `-1` removes the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ulimit {
+    pub resource: Resource,
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl FromStr for Ulimit {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s
+            .split_once('=')
+            .ok_or(Errcode::InvalidArgument("ulimit"))?;
+        let resource = format!("RLIMIT_{}", name.to_ascii_uppercase())
+            .parse::<Resource>()
+            .ok()
+            .filter(|resource| resource.is_supported())
+            .ok_or(Errcode::InvalidArgument("ulimit"))?;
+        let value = |v: &str| match v {
+            "unlimited" | "-1" => Ok(INFINITY),
+            v => v
+                .parse::<u64>()
+                .map_err(|_| Errcode::InvalidArgument("ulimit")),
+        };
+        let (soft, hard) = match values.split_once(':') {
+            Some((soft, hard)) => (value(soft)?, value(hard)?),
+            None => (value(values)?, value(values)?),
+        };
+        if soft > hard {
+            return Err(Errcode::InvalidArgument("ulimit"));
+        }
+        Ok(Ulimit {
+            resource,
+            soft,
+            hard,
+        })
+    }
+}
+
+/// The given limits, with the default number of file descriptors unless set.
+pub fn container_rlimits(ulimits: &[Ulimit]) -> Vec<Ulimit> {
+    let mut rlimits = ulimits.to_vec();
+    if !rlimits.iter().any(|u| u.resource == Resource::NOFILE) {
+        rlimits.push(Ulimit {
+            resource: Resource::NOFILE,
+            soft: NOFILE_RLIMIT,
+            hard: NOFILE_RLIMIT,
+        });
+    }
+    rlimits
+}
+
+/// Check before clone that the child can set the limits, as it has the
+/// same capabilities as the runtime when it does.
+pub fn check_rlimits(ulimits: &[Ulimit]) -> anyhow::Result<()> {
+    let can_raise = CapState::get_current()
+        .map(|state| state.effective.has(Cap::SYS_RESOURCE))
+        .unwrap_or(false);
+    for ulimit in ulimits.iter() {
+        let (_, hard) = getrlimit(ulimit.resource).unwrap_or((0, 0));
+        if ulimit.hard > hard && !can_raise {
+            error!(
+                "Raising the hard limit of {} above {} needs CAP_SYS_RESOURCE",
+                ulimit.resource.as_name(),
+                hard
+            );
+            return Err(Errcode::InvalidArgument("ulimit").into());
+        }
+    }
+    Ok(())
+}
+
+/// Set the rlimits of the calling process, in the child before exec.
+pub fn set_rlimits(rlimits: &[Ulimit]) -> anyhow::Result<()> {
+    for rlimit in rlimits.iter() {
+        debug!(
+            "Setting {} to {}:{}",
+            rlimit.resource.as_name(),
+            rlimit.soft,
+            rlimit.hard
+        );
+        if let Err(e) = setrlimit(rlimit.resource, rlimit.soft, rlimit.hard) {
+            error!("Unable to set {}: {}", rlimit.resource.as_name(), e);
+            return Err(Errcode::ResourcesError(1).into());
+        }
+    }
+    Ok(())
+}
+
+/// Cgroup limits of a container.
+/// Unset limits get the default values, which unlike the given ones are
+/// skipped when the host does not support them.
+#[derive(Debug, Clone, Default, PartialEq, Args)]
+pub struct ResourceConfig {
+    /// メモリの上限 例: 512m, 1.5g (指定しない場合は1g)
+    #[clap(long)]
+    pub memory: Option<ByteSize>,
+
+    /// メモリとswapの合計の上限(-1で無制限) --memoryと一緒に指定する
+    #[clap(long, allow_hyphen_values = true)]
+    pub memory_swap: Option<ByteSize>,
+
+    /// 使えるCPUの数 例: 1.5
+    #[clap(long)]
+    pub cpus: Option<f64>,
+
+    /// ほかのプロセスと比べたCPU時間の重み 2-262144 (指定しない場合は256)
+    #[clap(long)]
+    pub cpu_shares: Option<u64>,
+
+    /// 実行するCPU 例: 0-3,5
+    #[clap(long)]
+    pub cpuset_cpus: Option<String>,
+
+    /// メモリを使うNUMA node 例: 0,1
+    #[clap(long)]
+    pub cpuset_mems: Option<String>,
+
+    /// プロセス数の上限(0以下で無制限, 指定しない場合は64)
+    #[clap(long, allow_hyphen_values = true)]
+    pub pids_limit: Option<i64>,
+
+    /// ほかのプロセスと比べたblock I/Oの重み 10-1000 (指定しない場合は50)
+    #[clap(long)]
+    pub io_weight: Option<u16>,
+
+    /// block deviceの帯域の上限
+    /// 書式 <major>:<minor> [rbps=<size>] [wbps=<size>] [riops=<n>] [wiops=<n>]
+    /// 例: --io-max 8:0,rbps=10m,wiops=100
+    #[clap(long)]
+    pub io_max: Vec<IoMax>,
+}
+
+impl ResourceConfig {
+    /// Check the values and that the host cgroup hierarchy has the
+    /// controllers they need.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(ByteSize(memory)) = self.memory {
+            if memory < MIN_MEMORY {
+                error!("--memory must be at least {} bytes", MIN_MEMORY);
+                return Err(Errcode::InvalidArgument("memory").into());
+            }
+        }
+        if let Some(ByteSize(swap)) = self.memory_swap {
+            match self.memory {
+                Some(ByteSize(memory)) if swap == -1 || swap >= memory => {}
+                _ => {
+                    error!("--memory-swap needs --memory and must not be less than it");
+                    return Err(Errcode::InvalidArgument("memory-swap").into());
+                }
+            }
+        }
+        if let Some(cpus) = self.cpus {
+            let online = read_cpu_list(ONLINE_CPUS).map_or(0, |cpus| cpus.len());
+            if !(cpus * CPU_PERIOD as f64 >= MIN_CPU_QUOTA as f64 && cpus <= online as f64) {
+                error!("--cpus must be between 0.01 and {}", online);
+                return Err(Errcode::InvalidArgument("cpus").into());
+            }
+        }
+        if !self
+            .cpu_shares
+            .is_none_or(|shares| (2..=262144).contains(&shares))
+        {
+            return Err(Errcode::InvalidArgument("cpu-shares").into());
+        }
+        if !self
+            .io_weight
+            .is_none_or(|weight| (10..=1000).contains(&weight))
+        {
+            return Err(Errcode::InvalidArgument("io-weight").into());
+        }
+        check_cpu_list(&self.cpuset_cpus, ONLINE_CPUS, "cpuset-cpus")?;
+        check_cpu_list(&self.cpuset_mems, ONLINE_NODES, "cpuset-mems")?;
+        for io_max in self.io_max.iter() {
+            if !Path::new(&format!("/sys/dev/block/{}", io_max)).exists() {
+                error!("Block device {} not found", io_max);
+                return Err(Errcode::InvalidArgument("io-max").into());
+            }
+        }
+
+        let available: Vec<String> = hierarchy()
+            .subsystems()
+            .iter()
+            .map(|s| s.controller_name())
+            .collect();
+        for controller in self.controllers() {
+            if !available.iter().any(|c| c == controller) {
+                error!(
+                    "The {} controller is not available in the host cgroup hierarchy",
+                    controller
+                );
+                return Err(Errcode::ResourcesError(4).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Controllers needed by the given limits.
+    fn controllers(&self) -> BTreeSet<&'static str> {
+        let mut controllers = BTreeSet::new();
+        if self.memory.is_some() || self.memory_swap.is_some() {
+            controllers.insert("memory");
+        }
+        if self.cpus.is_some() || self.cpu_shares.is_some() {
+            controllers.insert("cpu");
+        }
+        if self.cpuset_cpus.is_some() || self.cpuset_mems.is_some() {
+            controllers.insert("cpuset");
+        }
+        if self.pids_limit.is_some() {
+            controllers.insert("pids");
+        }
+        if self.io_weight.is_some() || !self.io_max.is_empty() {
+            controllers.insert("blkio");
+        }
+        controllers
+    }
+
+    /// The default values of the limits not given, None for the given ones.
+    fn defaults(&self) -> ResourceConfig {
+        ResourceConfig {
+            memory: self.memory.xor(Some(ByteSize(MEM_LIMIT))),
+            cpu_shares: self.cpu_shares.xor(Some(CPU_SHARES)),
+            pids_limit: self.pids_limit.xor(Some(MAX_PID)),
+            io_weight: self.io_weight.xor(Some(IO_WEIGHT)),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parse a cpu or node list such as `0-3,5`.
+fn parse_cpu_list(list: &str) -> Option<BTreeSet<u32>> {
+    let mut cpus = BTreeSet::new();
+    for range in list.trim().split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first.parse::<u32>().ok()?, last.parse().ok()?),
+            None => (range.parse::<u32>().ok()?, range.parse().ok()?),
+        };
+        if first > last {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+fn read_cpu_list(path: &str) -> Option<BTreeSet<u32>> {
+    match read_to_string(path) {
+        Ok(list) => parse_cpu_list(&list),
+        //NUMAのないkernelではnode 0だけ
+        Err(_) if path == ONLINE_NODES => Some(BTreeSet::from([0])),
+        Err(_) => None,
+    }
+}
+
+/// Check that a cpuset list only names cpus or nodes online on the host.
+fn check_cpu_list(list: &Option<String>, online: &str, arg: &'static str) -> anyhow::Result<()> {
+    let list = match list {
+        Some(list) => list,
+        None => return Ok(()),
+    };
+    let cpus = parse_cpu_list(list).ok_or(Errcode::InvalidArgument(arg))?;
+    match read_cpu_list(online) {
+        Some(online) if cpus.is_subset(&online) => Ok(()),
+        _ => {
+            error!("--{} {} is not online on the host", arg, list);
+            Err(Errcode::InvalidArgument(arg).into())
+        }
+    }
+}
+
+//BlkIoControllerのthrottle_*_for_device
+type Throttle = fn(&BlkIoController, u64, u64, u64) -> cgroups_rs::error::Result<()>;
+
+/// The host cgroup hierarchy, detected as v2 (unified) or v1 (also in hybrid
+/// mode), limited to the controllers containers use.
+#[derive(Debug)]
+struct ContainerHierarchy(Box<dyn Hierarchy>);
+
+impl Hierarchy for ContainerHierarchy {
+    fn subsystems(&self) -> Vec<Subsystem> {
+        self.0
+            .subsystems()
+            .into_iter()
+            .filter(|s| CONTROLLERS.contains(&s.controller_name().as_str()))
+            .collect()
+    }
+
+    fn root(&self) -> PathBuf {
+        self.0.root()
+    }
+
+    fn root_control_group(&self) -> Cgroup {
+        Cgroup::load(hierarchy(), "")
+    }
+
+    fn v2(&self) -> bool {
+        self.0.v2()
+    }
+}
+
+fn hierarchy() -> Box<dyn Hierarchy> {
+    Box::new(ContainerHierarchy(auto()))
+}
+
+/// Write the limits to the cgroup files, returning the ones that failed.
+fn apply_limits(cgs: &Cgroup, limits: &ResourceConfig) -> Vec<&'static str> {
+    let v2 = cgs.v2();
+    let mut failed = vec![];
+
+    if let Some(mem) = cgs.controller_of::<MemController>() {
+        let swap_file = match v2 {
+            true => "memory.swap.max",
+            false => "memory.memsw.limit_in_bytes",
+        };
+        let current = match v2 {
+            true => (0, 0),
+            false => (
+                mem.memory_stat().limit_in_bytes,
+                mem.memswap().limit_in_bytes,
+            ),
+        };
+        let swap = swap_limit(v2, limits.memory, limits.memory_swap, current);
+        let swap_first = match limits.memory {
+            Some(ByteSize(memory)) => !v2 && memory > current.0,
+            None => true,
+        };
+        if swap_first {
+            if let Some(swap) = &swap {
+                let result = write(mem.path().join(swap_file), swap);
+                check(&mut failed, "memory-swap", result);
+            }
+        }
+        if let Some(ByteSize(memory)) = limits.memory {
+            check(&mut failed, "memory", mem.set_limit(memory));
+        }
+        if !swap_first {
+            if let Some(swap) = &swap {
+                let result = write(mem.path().join(swap_file), swap);
+                check(&mut failed, "memory-swap", result);
+            }
+        }
+    }
+    if let Some(cpu) = cgs.controller_of::<CpuController>() {
+        if let Some(shares) = limits.cpu_shares {
+            // cpu.weight is 1-10000, converted like runc does
+            let shares = match v2 {
+                true => 1 + ((shares - 2) * 9999) / 262142,
+                false => shares,
+            };
+            check(&mut failed, "cpu-shares", cpu.set_shares(shares));
+        }
+        if let Some(cpus) = limits.cpus {
+            let quota = (cpus * CPU_PERIOD as f64) as i64;
+            let result = cpu.set_cfs_quota_and_period(Some(quota), Some(CPU_PERIOD));
+            check(&mut failed, "cpus", result);
+        }
+    }
+    if let Some(cpuset) = cgs.controller_of::<CpuSetController>() {
+        if let Some(cpus) = &limits.cpuset_cpus {
+            check(&mut failed, "cpuset-cpus", cpuset.set_cpus(cpus));
+        }
+        if let Some(mems) = &limits.cpuset_mems {
+            check(&mut failed, "cpuset-mems", cpuset.set_mems(mems));
+        }
+    }
+    if let Some(pids) = cgs.controller_of::<PidController>() {
+        if let Some(limit) = limits.pids_limit {
+            let limit = match limit {
+                limit if limit > 0 => MaxValue::Value(limit),
+                _ => MaxValue::Max,
+            };
+            check(&mut failed, "pids-limit", pids.set_pid_max(limit));
+        }
+    }
+    if let Some(blkio) = cgs.controller_of::<BlkIoController>() {
+        if let Some(weight) = limits.io_weight {
+            // cgroups_rs writes io.bfq.weight on v2, use io.weight (1-10000) instead
+            match v2 {
+                true => {
+                    let weight = 1 + (weight as u64 - 10) * 9999 / 990;
+                    let result = write(
+                        blkio.path().join("io.weight"),
+                        format!("default {}", weight),
+                    );
+                    check(&mut failed, "io-weight", result);
+                }
+                false => check(&mut failed, "io-weight", blkio.set_weight(weight as u64)),
+            }
+        }
+        for io in limits.io_max.iter() {
+            let throttles = [
+                (
+                    io.rbps,
+                    BlkIoController::throttle_read_bps_for_device as Throttle,
+                ),
+                (io.wbps, BlkIoController::throttle_write_bps_for_device),
+                (io.riops, BlkIoController::throttle_read_iops_for_device),
+                (io.wiops, BlkIoController::throttle_write_iops_for_device),
+            ];
+            for (value, throttle) in throttles {
+                if let Some(value) = value {
+                    check(
+                        &mut failed,
+                        "io-max",
+                        throttle(blkio, io.major, io.minor, value),
+                    );
+                }
+            }
+        }
+    }
+
+    failed
+}
+
+/// Value of the swap file: memory+swap on v1, swap only on v2.
+/// `current` is the (memory, memory+swap) limit of a v1 cgroup, when only
+/// memory is changed memory+swap follows it to keep the same swap.
+/// v1 needs memory <= memory+swap after each write, so memory+swap is
+/// written first when memory is raised.
+fn swap_limit(
+    v2: bool,
+    memory: Option<ByteSize>,
+    swap: Option<ByteSize>,
+    current: (i64, i64),
+) -> Option<String> {
+    match (memory, swap) {
+        (_, Some(ByteSize(-1))) if v2 => Some("max".to_string()),
+        (Some(ByteSize(memory)), Some(ByteSize(swap))) if v2 => Some((swap - memory).to_string()),
+        (_, Some(ByteSize(swap))) => Some(swap.to_string()),
+        (Some(ByteSize(memory)), None) if !v2 => match current {
+            (limit, memsw) if limit > 0 && memsw > 0 && memsw < UNLIMITED_MEMORY => {
+                Some((memory + memsw - limit).to_string())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn check<E: fmt::Display>(
+    failed: &mut Vec<&'static str>,
+    name: &'static str,
+    result: Result<(), E>,
+) {
+    if let Err(e) = result {
+        debug!("Unable to set {}: {}", name, e);
+        failed.push(name);
+    }
+}
+
+/// Check a `--cgroup-parent` path such as `bowl.slice/team-a`.
+pub fn validate_cgroup_parent(parent: &str) -> anyhow::Result<()> {
+    let valid = parent
+        .trim_matches('/')
+        .split('/')
+        .all(|c| !c.is_empty() && c != "." && c != "..");
+    if !valid {
+        error!("Invalid cgroup parent {:?}", parent);
+        return Err(Errcode::InvalidArgument("cgroup-parent").into());
+    }
+    Ok(())
+}
+
+/// Cgroup of the container `id`, relative to the root of the hierarchy.
+pub fn cgroup_path(parent: Option<&str>, id: &str) -> String {
+    match parent {
+        Some(parent) => format!("{}/{}", parent.trim_matches('/'), id),
+        None => id.to_string(),
+    }
+}
 
 /// Limit resources in containers
-pub fn restrict_resources(hostname: &String, pid: Pid) -> anyhow::Result<()> {
-    debug!("Restricting resources for hostname {}", hostname);
+pub fn restrict_resources(cgroup: &str, pid: Pid, limits: &ResourceConfig) -> anyhow::Result<()> {
+    debug!("Restricting resources in cgroup {}", cgroup);
 
     // Cgroups
-    let cgs = CgroupBuilder::new(hostname)
-        // Allocate less CPU time than other processes
-        .cpu()
-        .shares(256)
-        .done()
-        // Limiting the memory usage to 1 GiB
-        // The user can limit it to less than this, never increase above 1Gib
-        .memory()
-        .kernel_memory_limit(KMEM_LIMIT)
-        .memory_hard_limit(MEM_LIMIT)
-        .done()
-        // This process can only create a maximum of 64 child processes
-        .pid()
-        .maximum_number_of_processes(MAX_PID)
-        .done()
-        // Give an access priority to block IO lower than the system
-        .blkio()
-        .weight(50)
-        .done()
-        .build(Box::new(V2::new()));
+    // Missing parents are created, their limits also apply to the container
+    let cgs = Cgroup::new(hierarchy(), cgroup);
+    // Not every host has the files of the default limits
+    let failed = apply_limits(&cgs, &limits.defaults());
+    if !failed.is_empty() {
+        debug!("Default limits not applied: {}", failed.join(", "));
+    }
+    let failed = apply_limits(&cgs, limits);
+    if !failed.is_empty() {
+        error!("Unable to set {}", failed.join(", "));
+        return Err(Errcode::ResourcesError(5).into());
+    }
 
     // We apply the cgroups rules to the child process we just created
     let pid: u64 = pid.as_raw().try_into().unwrap();
-    if let Err(_) = cgs.add_task(CgroupPid::from(pid)) {
+    if cgs.add_task_by_tgid(CgroupPid::from(pid)).is_err() {
         return Err(Errcode::ResourcesError(0).into());
     };
 
-    // Rlimit
-    // Can create only 64 file descriptors
-    if let Err(_) = setrlimit(Resource::NOFILE, NOFILE_RLIMIT, NOFILE_RLIMIT) {
-        return Err(Errcode::ResourcesError(1).into());
+    Ok(())
+}
+
+/// Handle to the existing cgroup of a container.
+pub fn load_cgroup(cgroup: &str) -> Cgroup {
+    Cgroup::load(hierarchy(), cgroup)
+}
+
+/// Memory used by the cgroup without the inactive page cache,
+/// which the kernel reclaims before reaching the limit.
+pub fn memory_usage(mem: &MemController) -> u64 {
+    let memory = mem.memory_stat();
+    let inactive = match mem.v2() {
+        true => memory.stat.inactive_file,
+        false => memory.stat.total_inactive_file,
+    };
+    memory.usage_in_bytes.saturating_sub(inactive)
+}
+
+/// `update` sub command
+/// Change the limits of a running container without restarting it.
+pub fn handle_update(
+    data_root: &Path,
+    id_or_name: &str,
+    limits: &ResourceConfig,
+) -> anyhow::Result<()> {
+    if *limits == ResourceConfig::default() {
+        error!("No limit to update");
+        return Err(Errcode::InvalidArgument("update").into());
+    }
+    limits.validate()?;
+    let state = lookup(data_root, id_or_name)?;
+    if !state.is_running() {
+        error!("Container {} is not running", state.short_id());
+        return Err(Errcode::StateError(7).into());
     }
 
+    let cgs = load_cgroup(state.cgroup()?);
+    if let (Some(ByteSize(memory)), Some(mem)) =
+        (limits.memory, cgs.controller_of::<MemController>())
+    {
+        // v2 would reclaim and OOM kill the container, v1 returns EBUSY
+        let usage = memory_usage(mem);
+        if (memory as u64) < usage {
+            error!("--memory {} is below the current usage {}", memory, usage);
+            return Err(Errcode::ResourcesError(6).into());
+        }
+    }
+    let failed = apply_limits(&cgs, limits);
+    if !failed.is_empty() {
+        error!("Unable to set {}", failed.join(", "));
+        return Err(Errcode::ResourcesError(5).into());
+    }
+    info!("Container {} updated", state.short_id());
     Ok(())
 }
 
 /// Clear all added cgroups restrictions.
-pub fn clean_cgroups(hostname: &String) -> anyhow::Result<()> {
+pub fn clean_cgroups(cgroup: &str) -> anyhow::Result<()> {
     debug!("cleanup cgroups");
-    //cgroups v2 は /sys/fs/cgroup/<groupname>/ の
-    //下のディレクトリで一元管理しているので、これを削除するだけ
-    //canonicalize = file pathを正規化する
-    match canonicalize(format!("/sys/fs/cgroup/{}/", hostname)) {
-        Ok(d) => {
-            if let Err(_) = remove_dir(d) {
-                return Err(Errcode::ResourcesError(2).into());
-            }
-        }
-        Err(e) => {
-            log::error!("Error while canonicalize path: {}", e);
-            return Err(Errcode::ResourcesError(3).into());
-        }
+    //v2は /sys/fs/cgroup/<cgroup>/ だけ、
+    //v1はcontrollerごとの /sys/fs/cgroup/<controller>/<cgroup>/ をすべて削除する
+    //--cgroup-parentの親は他のコンテナと共有しているので残す
+    if let Err(e) = load_cgroup(cgroup).delete() {
+        error!("Unable to remove the cgroup {}: {}", cgroup, e);
+        return Err(Errcode::ResourcesError(2).into());
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup_parent_success() {
+        assert!(validate_cgroup_parent("bowl.slice/team-a").is_ok());
+        assert!(validate_cgroup_parent("/bowl/").is_ok());
+        assert!(validate_cgroup_parent("").is_err());
+        assert!(validate_cgroup_parent("a//b").is_err());
+        assert!(validate_cgroup_parent("a/../b").is_err());
+        assert_eq!(cgroup_path(None, "abc"), "abc");
+        assert_eq!(
+            cgroup_path(Some("/bowl.slice/team-a/"), "abc"),
+            "bowl.slice/team-a/abc"
+        );
+    }
+
+    #[test]
+    fn byte_size_parse() {
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("512m".parse::<ByteSize>().unwrap(), ByteSize(512 << 20));
+        assert_eq!("1.5G".parse::<ByteSize>().unwrap(), ByteSize(3 << 29));
+        assert_eq!("64kb".parse::<ByteSize>().unwrap(), ByteSize(64 << 10));
+        assert_eq!("2gib".parse::<ByteSize>().unwrap(), ByteSize(2 << 30));
+        assert_eq!("-1".parse::<ByteSize>().unwrap(), ByteSize(-1));
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("m".parse::<ByteSize>().is_err());
+        assert!("10x".parse::<ByteSize>().is_err());
+        assert!("0".parse::<ByteSize>().is_err());
+        assert!("-2".parse::<ByteSize>().is_err());
+        assert!("1e30".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn ulimit_parse() {
+        assert_eq!(
+            "nofile=1024:4096".parse::<Ulimit>().unwrap(),
+            Ulimit {
+                resource: Resource::NOFILE,
+                soft: 1024,
+                hard: 4096
+            }
+        );
+        let core = "core=0".parse::<Ulimit>().unwrap();
+        assert_eq!(
+            (core.resource, core.soft, core.hard),
+            (Resource::CORE, 0, 0)
+        );
+        let memlock = "MEMLOCK=unlimited:-1".parse::<Ulimit>().unwrap();
+        assert_eq!((memlock.soft, memlock.hard), (INFINITY, INFINITY));
+        assert!("nofile=4096:1024".parse::<Ulimit>().is_err());
+        assert!("nofile".parse::<Ulimit>().is_err());
+        assert!("files=10".parse::<Ulimit>().is_err());
+        assert!("stack=8m".parse::<Ulimit>().is_err());
+    }
+
+    #[test]
+    fn container_rlimits_default() {
+        let rlimits = container_rlimits(&[]);
+        assert_eq!(rlimits.len(), 1);
+        assert_eq!(rlimits[0].resource, Resource::NOFILE);
+        assert_eq!(rlimits[0].soft, NOFILE_RLIMIT);
+        let nproc = "nproc=100".parse::<Ulimit>().unwrap();
+        let nofile = "nofile=1024:4096".parse::<Ulimit>().unwrap();
+        assert_eq!(container_rlimits(&[nproc, nofile]), vec![nproc, nofile]);
+    }
+
+    #[test]
+    fn io_max_parse() {
+        assert_eq!(
+            "8:0 rbps=1m wiops=100".parse::<IoMax>().unwrap(),
+            IoMax {
+                major: 8,
+                minor: 0,
+                rbps: Some(1 << 20),
+                wbps: None,
+                riops: None,
+                wiops: Some(100),
+            }
+        );
+        let io_max = "253:16,wbps=512k,riops=10".parse::<IoMax>().unwrap();
+        assert_eq!((io_max.major, io_max.minor), (253, 16));
+        assert_eq!((io_max.wbps, io_max.riops), (Some(512 << 10), Some(10)));
+        assert!("8:0".parse::<IoMax>().is_err());
+        assert!("sda rbps=1m".parse::<IoMax>().is_err());
+        assert!("8:0 rbps=0".parse::<IoMax>().is_err());
+        assert!("8:0 xbps=1".parse::<IoMax>().is_err());
+    }
+
+    #[test]
+    fn cpu_list_parse() {
+        assert_eq!(parse_cpu_list("0"), Some(BTreeSet::from([0])));
+        assert_eq!(
+            parse_cpu_list("0-2,5\n"),
+            Some(BTreeSet::from([0, 1, 2, 5]))
+        );
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("0,"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+    }
+
+    #[test]
+    fn validate_values() {
+        let config = |f: fn(&mut ResourceConfig)| {
+            let mut config = ResourceConfig::default();
+            f(&mut config);
+            config
+        };
+        assert!(ResourceConfig::default().validate().is_ok());
+        assert!(config(|c| c.memory = Some(ByteSize(1 << 20)))
+            .validate()
+            .is_err());
+        assert!(config(|c| c.memory_swap = Some(ByteSize(1 << 30)))
+            .validate()
+            .is_err());
+        assert!(config(|c| {
+            c.memory = Some(ByteSize(1 << 30));
+            c.memory_swap = Some(ByteSize(1 << 29));
+        })
+        .validate()
+        .is_err());
+        assert!(config(|c| c.cpus = Some(0.001)).validate().is_err());
+        assert!(config(|c| c.cpus = Some(100000.0)).validate().is_err());
+        assert!(config(|c| c.cpu_shares = Some(1)).validate().is_err());
+        assert!(config(|c| c.io_weight = Some(5000)).validate().is_err());
+        assert!(config(|c| c.cpuset_cpus = Some("100000".to_string()))
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn defaults_success() {
+        let defaults = ResourceConfig::default().defaults();
+        assert_eq!(defaults.memory, Some(ByteSize(MEM_LIMIT)));
+        assert_eq!(defaults.cpu_shares, Some(CPU_SHARES));
+        assert_eq!(defaults.pids_limit, Some(MAX_PID));
+        assert_eq!(defaults.io_weight, Some(IO_WEIGHT));
+        // given limits are not applied again
+        let config = ResourceConfig {
+            memory: Some(ByteSize(1 << 29)),
+            pids_limit: Some(-1),
+            ..Default::default()
+        };
+        let defaults = config.defaults();
+        assert_eq!((defaults.memory, defaults.pids_limit), (None, None));
+        assert_eq!(config.controllers(), BTreeSet::from(["memory", "pids"]));
+    }
+
+    #[test]
+    fn update_without_limit() {
+        let result = handle_update(Path::new("/nonexistent"), "foo", &ResourceConfig::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn swap_limit_success() {
+        let mib = |n: i64| Some(ByteSize(n << 20));
+        let v1 = (64 << 20, 128 << 20);
+        assert_eq!(
+            swap_limit(true, mib(64), mib(128), (0, 0)),
+            Some("67108864".into())
+        );
+        assert_eq!(
+            swap_limit(true, mib(64), Some(ByteSize(-1)), (0, 0)),
+            Some("max".into())
+        );
+        assert_eq!(swap_limit(true, mib(64), None, (0, 0)), None);
+        assert_eq!(
+            swap_limit(false, mib(64), mib(128), v1),
+            Some("134217728".into())
+        );
+        // memory only keeps the 64MiB of swap
+        assert_eq!(
+            swap_limit(false, mib(512), None, v1),
+            Some("603979776".into())
+        );
+        assert_eq!(
+            swap_limit(false, mib(32), None, v1),
+            Some("100663296".into())
+        );
+        // memory+swap is not limited
+        assert_eq!(
+            swap_limit(false, mib(512), None, (64 << 20, UNLIMITED_MEMORY)),
+            None
+        );
+        assert_eq!(swap_limit(false, mib(512), None, (64 << 20, 0)), None);
+    }
+}
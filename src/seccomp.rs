@@ -0,0 +1,615 @@
+use crate::errors::Errcode;
+use crate::notify::NotifyRule;
+
+use capctl::caps::{Cap, CapSet};
+use libc::{c_char, c_int};
+use nix::sys::utsname::uname;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use syscallz::{Action, Cmp, Comparator, Context, Syscall};
+
+use anyhow::{self};
+use log::{debug, error, log, warn, Level};
+
+//組み込みのprofile(Dockerのdefault profileに近いallowlist)
+const DEFAULT_PROFILE: &str = include_str!("seccomp_default.json");
+//errnoRetがない場合のerrno(EPERM)
+const DEFAULT_ERRNO: u16 = 1;
+//seccomp(2)で比較できる引数は6つまで
+const MAX_ARGS: u32 = 6;
+
+#[cfg(target_arch = "x86_64")]
+const NATIVE_ARCH: &str = "SCMP_ARCH_X86_64";
+#[cfg(target_arch = "aarch64")]
+const NATIVE_ARCH: &str = "SCMP_ARCH_AARCH64";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const NATIVE_ARCH: &str = "SCMP_ARCH_NATIVE";
+
+const KNOWN_ARCHES: [&str; 8] = [
+    "SCMP_ARCH_X86_64",
+    "SCMP_ARCH_X86",
+    "SCMP_ARCH_X32",
+    "SCMP_ARCH_AARCH64",
+    "SCMP_ARCH_ARM",
+    "SCMP_ARCH_RISCV64",
+    "SCMP_ARCH_S390X",
+    "SCMP_ARCH_PPC64LE",
+];
+
+extern "C" {
+    // libseccomp, linked through syscallz which has no number to name lookup
+    fn seccomp_syscall_resolve_num_arch(arch: u32, num: c_int) -> *mut c_char;
+}
+
+/// Name of the syscall `nr` of the architecture `arch` (AUDIT_ARCH_*, 0 for native).
+pub fn syscall_name(arch: u32, nr: i32) -> Option<String> {
+    let name = unsafe { seccomp_syscall_resolve_num_arch(arch, nr) };
+    if name.is_null() {
+        return None;
+    }
+    let resolved = unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { libc::free(name.cast()) };
+    Some(resolved)
+}
+
+/// `--seccomp` given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeccompMode {
+    /// Built-in allowlist
+    Default,
+    /// Denylist of the earlier versions (`syscalls.rs`)
+    Legacy,
+    /// No filter
+    Unconfined,
+    /// Profile file
+    Profile(PathBuf),
+}
+
+impl FromStr for SeccompMode {
+    type Err = Errcode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(Errcode::InvalidArgument("seccomp")),
+            "default" => Ok(SeccompMode::Default),
+            "legacy" => Ok(SeccompMode::Legacy),
+            "unconfined" => Ok(SeccompMode::Unconfined),
+            path => Ok(SeccompMode::Profile(PathBuf::from(path))),
+        }
+    }
+}
+
+impl SeccompMode {
+    /// Load the profile the child builds its filter from.
+    pub fn filter(&self) -> anyhow::Result<SeccompFilter> {
+        match self {
+            SeccompMode::Default => Ok(SeccompFilter::Profile(default_profile())),
+            SeccompMode::Legacy => Ok(SeccompFilter::Legacy),
+            SeccompMode::Unconfined => Ok(SeccompFilter::Unconfined),
+            SeccompMode::Profile(path) => Ok(SeccompFilter::Profile(load_profile(path)?)),
+        }
+    }
+}
+
+/// Filter loaded in the container.
+#[derive(Debug, Clone)]
+pub enum SeccompFilter {
+    Legacy,
+    Unconfined,
+    Profile(SeccompProfile),
+}
+
+/// Seccomp profile in the Docker/OCI JSON format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompProfile {
+    pub default_action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_errno_ret: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub architectures: Vec<String>,
+    /// Docker style architectures, `architecture` and its `subArchitectures`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arch_map: Vec<ArchMap>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchMap {
+    pub architecture: String,
+    #[serde(default)]
+    pub sub_architectures: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Older profiles give a single name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errno_ret: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<ArgRule>,
+    #[serde(default, skip_serializing_if = "Condition::is_empty")]
+    pub includes: Condition,
+    #[serde(default, skip_serializing_if = "Condition::is_empty")]
+    pub excludes: Condition,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgRule {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: String,
+}
+
+/// Docker `includes`/`excludes` of a rule.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub caps: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arches: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_kernel: Option<String>,
+}
+
+impl Condition {
+    fn is_empty(&self) -> bool {
+        self.caps.is_empty() && self.arches.is_empty() && self.min_kernel.is_none()
+    }
+}
+
+/// The built-in profile.
+pub fn default_profile() -> SeccompProfile {
+    serde_json::from_str(DEFAULT_PROFILE).expect("invalid built-in seccomp profile")
+}
+
+/// Write a profile file.
+pub fn write_profile(path: &Path, profile: &SeccompProfile) -> anyhow::Result<()> {
+    File::create(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| serde_json::to_writer_pretty(file, profile).map_err(anyhow::Error::from))
+        .map_err(|e| {
+            error!("Unable to write seccomp profile {:?}: {}", path, e);
+            Errcode::SyscallsError(8).into()
+        })
+}
+
+/// Read a profile file.
+pub fn load_profile(path: &Path) -> anyhow::Result<SeccompProfile> {
+    let file = File::open(path).map_err(|e| {
+        error!("Unable to open seccomp profile {:?}: {}", path, e);
+        Errcode::SyscallsError(5)
+    })?;
+    serde_json::from_reader(file).map_err(|e| {
+        error!("Invalid seccomp profile {:?}: {}", path, e);
+        Errcode::SyscallsError(5).into()
+    })
+}
+
+/// `SCMP_ACT_*` to a syscallz action.
+fn parse_action(name: &str, errno_ret: Option<u16>) -> Option<Action> {
+    let errno = errno_ret.unwrap_or(DEFAULT_ERRNO);
+    match name {
+        "SCMP_ACT_ALLOW" => Some(Action::Allow),
+        "SCMP_ACT_ERRNO" => Some(Action::Errno(errno)),
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => Some(Action::KillThread),
+        "SCMP_ACT_KILL_PROCESS" => Some(Action::KillProcess),
+        "SCMP_ACT_TRAP" => Some(Action::Trap),
+        "SCMP_ACT_TRACE" => Some(Action::Trace(errno)),
+        _ => None,
+    }
+}
+
+/// `SCMP_CMP_*` to a syscallz comparison.
+fn parse_cmp(op: &str) -> Option<Cmp> {
+    match op {
+        "SCMP_CMP_NE" => Some(Cmp::Ne),
+        "SCMP_CMP_LT" => Some(Cmp::Lt),
+        "SCMP_CMP_LE" => Some(Cmp::Le),
+        "SCMP_CMP_EQ" => Some(Cmp::Eq),
+        "SCMP_CMP_GE" => Some(Cmp::Ge),
+        "SCMP_CMP_GT" => Some(Cmp::Gt),
+        "SCMP_CMP_MASKED_EQ" => Some(Cmp::MaskedEq),
+        _ => None,
+    }
+}
+
+/// "5.8.0-63-generic" -> (5, 8)
+fn kernel_version(release: &str) -> (u32, u32) {
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|p| p.parse::<u32>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Context the includes/excludes of the rules are checked against.
+pub struct RuleContext {
+    pub caps: CapSet,
+    pub arch: &'static str,
+    pub kernel: (u32, u32),
+    /// Calls of `--seccomp-notify`, allowed so that the supervisor decides
+    pub notified: Vec<Syscall>,
+}
+
+impl RuleContext {
+    pub fn new(caps: CapSet, notify: &[NotifyRule]) -> RuleContext {
+        let kernel = uname()
+            .map(|u| kernel_version(u.release().to_str().unwrap_or_default()))
+            .unwrap_or_default();
+        RuleContext {
+            caps,
+            arch: NATIVE_ARCH,
+            kernel,
+            notified: notify.iter().map(|rule| rule.syscall).collect(),
+        }
+    }
+
+    fn has_cap(&self, name: &str) -> bool {
+        name.parse::<Cap>().map(|cap| self.caps.has(cap)) == Ok(true)
+    }
+
+    /// Whether a rule applies (Docker semantics):
+    /// every included capability is kept, the architecture is included,
+    /// the kernel is recent enough and nothing excluded matches.
+    fn applies(&self, rule: &SyscallRule) -> bool {
+        let inc = &rule.includes;
+        let exc = &rule.excludes;
+        let included = inc.caps.iter().all(|c| self.has_cap(c))
+            && (inc.arches.is_empty() || inc.arches.iter().any(|a| self.arch_matches(a)))
+            && inc
+                .min_kernel
+                .as_ref()
+                .is_none_or(|v| self.kernel >= kernel_version(v));
+        let excluded = exc.caps.iter().any(|c| self.has_cap(c))
+            || exc.arches.iter().any(|a| self.arch_matches(a))
+            || exc
+                .min_kernel
+                .as_ref()
+                .is_some_and(|v| self.kernel >= kernel_version(v));
+        included && !excluded
+    }
+
+    /// Docker profiles use both `SCMP_ARCH_X86_64` and `amd64`.
+    fn arch_matches(&self, arch: &str) -> bool {
+        let short = match self.arch {
+            "SCMP_ARCH_X86_64" => "amd64",
+            "SCMP_ARCH_AARCH64" => "arm64",
+            _ => "",
+        };
+        arch == self.arch || arch == short
+    }
+}
+
+impl SeccompProfile {
+    /// Profile allowing only `names`, denying everything else with EPERM.
+    pub fn allowlist(mut names: Vec<String>) -> SeccompProfile {
+        names.sort();
+        names.dedup();
+        let syscalls = if names.is_empty() {
+            vec![]
+        } else {
+            vec![SyscallRule {
+                names,
+                action: "SCMP_ACT_ALLOW".to_string(),
+                ..Default::default()
+            }]
+        };
+        SeccompProfile {
+            default_action: "SCMP_ACT_ERRNO".to_string(),
+            default_errno_ret: Some(DEFAULT_ERRNO),
+            architectures: vec![NATIVE_ARCH.to_string()],
+            arch_map: vec![],
+            syscalls,
+        }
+    }
+
+    fn architectures(&self) -> Vec<&str> {
+        let mut arches: Vec<&str> = self.architectures.iter().map(|a| a.as_str()).collect();
+        for map in self.arch_map.iter() {
+            arches.push(&map.architecture);
+            arches.extend(map.sub_architectures.iter().map(|a| a.as_str()));
+        }
+        arches
+    }
+
+    /// Translate the profile into seccomp rules.
+    /// Names unknown on this architecture are skipped like libseccomp does,
+    /// anything else that can not be translated is an error naming the entry.
+    /// The notified calls are always allowed: the kernel picks the most
+    /// restrictive action of the stacked filters, and an errno here would
+    /// win over the notification.
+    pub fn build(&self, rctx: &RuleContext) -> anyhow::Result<Context> {
+        let invalid = |entry: &str, msg: String| {
+            error!("seccomp profile {}: {}", entry, msg);
+            Errcode::SyscallsError(6)
+        };
+
+        let default_action = parse_action(&self.default_action, self.default_errno_ret)
+            .ok_or_else(|| {
+                invalid(
+                    "defaultAction",
+                    format!("unsupported action {}", self.default_action),
+                )
+            })?;
+        for arch in self.architectures() {
+            if !KNOWN_ARCHES.contains(&arch) {
+                return Err(
+                    invalid("architectures", format!("unknown architecture {}", arch)).into(),
+                );
+            }
+        }
+        // archMap lists every architecture to be portable, only warn on the
+        // ones the profile asks for explicitly
+        for arch in self.architectures() {
+            if arch != rctx.arch {
+                let level = if self.architectures.iter().any(|a| a == arch) {
+                    Level::Warn
+                } else {
+                    Level::Debug
+                };
+                log!(
+                    level,
+                    "seccomp: only native rules are built, {} calls are killed",
+                    arch
+                );
+            }
+        }
+
+        let mut ctx = Context::init_with_action(default_action).map_err(|e| {
+            error!("Unable to initialize seccomp: {}", e);
+            Errcode::SyscallsError(1)
+        })?;
+
+        let allow = u32::from(Action::Allow);
+        // notified calls the profile allows anyway
+        let mut allowed = vec![];
+        for (i, rule) in self.syscalls.iter().enumerate() {
+            let names: Vec<&String> = rule.names.iter().chain(rule.name.iter()).collect();
+            let entry = format!("syscalls[{}] ({:?})", i, names);
+            let action = parse_action(&rule.action, rule.errno_ret)
+                .ok_or_else(|| invalid(&entry, format!("unsupported action {}", rule.action)))?;
+
+            let mut comparators = vec![];
+            for arg in rule.args.iter() {
+                let cmp = parse_cmp(&arg.op)
+                    .ok_or_else(|| invalid(&entry, format!("unknown operator {}", arg.op)))?;
+                if arg.index >= MAX_ARGS {
+                    return Err(
+                        invalid(&entry, format!("invalid argument index {}", arg.index)).into(),
+                    );
+                }
+                let comparator = match cmp {
+                    // value is the mask, valueTwo the masked value
+                    Cmp::MaskedEq => {
+                        Comparator::new(arg.index, cmp, arg.value, Some(arg.value_two))
+                    }
+                    _ => Comparator::new(arg.index, cmp, arg.value, None),
+                };
+                comparators.push(comparator);
+            }
+
+            // libseccomp refuses rules doing the default action,
+            // compared with the errno (e.g. clone3 returns ENOSYS, not EPERM)
+            if !rctx.applies(rule) || u32::from(action) == u32::from(default_action) {
+                continue;
+            }
+            for name in names {
+                let syscall = match Syscall::from_name(name) {
+                    Some(syscall) => syscall,
+                    None => {
+                        debug!("seccomp: unknown syscall {}, skipping", name);
+                        continue;
+                    }
+                };
+                if rctx.notified.contains(&syscall) {
+                    match u32::from(action) == allow && comparators.is_empty() {
+                        true => allowed.push(syscall),
+                        false => warn!(
+                            "seccomp: the rule of {} is replaced by --seccomp-notify",
+                            name
+                        ),
+                    }
+                    continue;
+                }
+                let added = if comparators.is_empty() {
+                    ctx.set_action_for_syscall(action, syscall)
+                } else {
+                    ctx.set_rule_for_syscall(action, syscall, &comparators)
+                };
+                if let Err(e) = added {
+                    return Err(invalid(&entry, format!("unable to add {}: {}", name, e)).into());
+                }
+            }
+        }
+        if u32::from(default_action) != allow {
+            for syscall in rctx.notified.iter() {
+                if !allowed.contains(syscall) {
+                    warn!(
+                        "seccomp: {:?} is denied by the profile but allowed by --seccomp-notify",
+                        syscall
+                    );
+                }
+                if let Err(e) = ctx.set_action_for_syscall(Action::Allow, *syscall) {
+                    error!("Unable to notify {:?}: {}", syscall, e);
+                    return Err(Errcode::SyscallsError(10).into());
+                }
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount::test_dir;
+
+    fn profile(json: &str) -> SeccompProfile {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn rctx() -> RuleContext {
+        RuleContext {
+            caps: [Cap::CHOWN].iter().copied().collect(),
+            arch: "SCMP_ARCH_X86_64",
+            kernel: (5, 10),
+            notified: vec![],
+        }
+    }
+
+    #[test]
+    fn build_profile_success() {
+        let p = profile(
+            r#"{
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "architectures": ["SCMP_ARCH_X86_64", "SCMP_ARCH_X86"],
+            "syscalls": [
+                {"names": ["read", "write", "no_such_syscall"], "action": "SCMP_ACT_ALLOW"},
+                {"names": ["personality"], "action": "SCMP_ACT_ALLOW",
+                 "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]},
+                {"names": ["clone"], "action": "SCMP_ACT_ALLOW",
+                 "args": [{"index": 0, "value": 2114060288, "valueTwo": 0, "op": "SCMP_CMP_MASKED_EQ"}]},
+                {"names": ["mount"], "action": "SCMP_ACT_ALLOW", "includes": {"caps": ["CAP_SYS_ADMIN"]}}
+            ]}"#,
+        );
+        assert!(p.build(&rctx()).is_ok());
+    }
+
+    #[test]
+    fn build_profile_errors() {
+        let bad_action = profile(
+            r#"{"defaultAction": "SCMP_ACT_ALLOW",
+                "syscalls": [{"names": ["read"], "action": "SCMP_ACT_FOO"}]}"#,
+        );
+        assert!(bad_action.build(&rctx()).is_err());
+        let bad_op = profile(
+            r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [{"names": ["read"],
+                "action": "SCMP_ACT_ERRNO", "args": [{"index": 0, "value": 1, "op": "SCMP_CMP_FOO"}]}]}"#,
+        );
+        assert!(bad_op.build(&rctx()).is_err());
+        let bad_arch =
+            profile(r#"{"defaultAction": "SCMP_ACT_ALLOW", "architectures": ["SCMP_ARCH_FOO"]}"#);
+        assert!(bad_arch.build(&rctx()).is_err());
+        let bad_default = profile(r#"{"defaultAction": "SCMP_ACT_FOO"}"#);
+        assert!(bad_default.build(&rctx()).is_err());
+    }
+
+    #[test]
+    fn default_profile_success() {
+        let profile = default_profile();
+        assert_eq!(profile.default_action, "SCMP_ACT_ERRNO");
+        assert!(profile.build(&rctx()).is_ok());
+        // needed by set_capa when seccomp is loaded before dropping capabilities
+        let allowed = &profile.syscalls[0].names;
+        for name in ["capset", "prctl", "execve", "close", "write"] {
+            assert!(allowed.iter().any(|n| n == name));
+        }
+    }
+
+    #[test]
+    fn default_profile_clone3() {
+        // glibc falls back to clone only when clone3 returns ENOSYS
+        let path = test_dir();
+        let mut file = File::create(&path).unwrap();
+        default_profile()
+            .build(&rctx())
+            .unwrap()
+            .export_pfc(&mut file)
+            .unwrap();
+        let pfc = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let clone3 = pfc
+            .split("# filter for syscall")
+            .find(|filter| filter.starts_with(" \"clone3\""))
+            .unwrap();
+        assert!(clone3.contains("ERRNO(38)"));
+    }
+
+    #[test]
+    fn allowlist_success() {
+        let names = ["write", "read", "write"].map(String::from).to_vec();
+        let profile = SeccompProfile::allowlist(names);
+        assert_eq!(profile.syscalls[0].names, vec!["read", "write"]);
+        let json = serde_json::to_value(&profile).unwrap();
+        assert_eq!(json["defaultAction"], "SCMP_ACT_ERRNO");
+        assert_eq!(json["syscalls"][0]["action"], "SCMP_ACT_ALLOW");
+        assert!(json["syscalls"][0].get("args").is_none());
+        assert!(profile.build(&rctx()).is_ok());
+    }
+
+    #[test]
+    fn syscall_name_success() {
+        assert_eq!(
+            syscall_name(0, Syscall::getpid.into_i32()).as_deref(),
+            Some("getpid")
+        );
+        assert_eq!(syscall_name(0, -10), None);
+    }
+
+    #[test]
+    fn seccomp_mode_parse() {
+        assert_eq!(
+            "default".parse::<SeccompMode>().unwrap(),
+            SeccompMode::Default
+        );
+        assert_eq!(
+            "legacy".parse::<SeccompMode>().unwrap(),
+            SeccompMode::Legacy
+        );
+        assert_eq!(
+            "unconfined".parse::<SeccompMode>().unwrap(),
+            SeccompMode::Unconfined
+        );
+        assert_eq!(
+            "./profile.json".parse::<SeccompMode>().unwrap(),
+            SeccompMode::Profile(PathBuf::from("./profile.json"))
+        );
+        assert!("".parse::<SeccompMode>().is_err());
+    }
+
+    #[test]
+    fn rule_conditions() {
+        let rule = |json: &str| -> SyscallRule { serde_json::from_str(json).unwrap() };
+        let rctx = rctx();
+        assert!(rctx.applies(&rule(r#"{"names": ["a"], "action": "A"}"#)));
+        assert!(rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "includes": {"caps": ["CAP_CHOWN"]}}"#
+        )));
+        assert!(!rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "includes": {"caps": ["CAP_SYS_ADMIN"]}}"#
+        )));
+        assert!(!rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "excludes": {"caps": ["CAP_CHOWN"]}}"#
+        )));
+        assert!(rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "includes": {"arches": ["amd64"]}}"#
+        )));
+        assert!(!rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "includes": {"arches": ["s390x"]}}"#
+        )));
+        assert!(rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "includes": {"minKernel": "4.8"}}"#
+        )));
+        assert!(!rctx.applies(&rule(
+            r#"{"names": ["a"], "action": "A", "includes": {"minKernel": "5.11"}}"#
+        )));
+        assert_eq!(kernel_version("6.18.44-fc"), (6, 18));
+    }
+}
@@ -0,0 +1,209 @@
+use crate::errors::Errcode;
+use crate::network::PortMapping;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::{close, pipe, read, Pid};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+use anyhow::{self};
+use log::{debug, error, info};
+
+const SLIRP4NETNS: &str = "slirp4netns";
+//コンテナ側のTAP device
+const TAP_NAME: &str = "tap0";
+const API_SOCKET: &str = "slirp.sock";
+const MTU: u32 = 65520;
+/// Addresses given by `slirp4netns --configure`
+pub const SLIRP_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+
+/// Userspace network given on the command line.
+#[derive(Debug, Clone)]
+pub struct SlirpConfig {
+    pub ports: Vec<PortMapping>,
+    /// The network namespace is owned by the user namespace of the container
+    /// (rootless), slirp4netns has to join it first
+    pub join_userns: bool,
+}
+
+/// Userspace network of a container, saved in the container state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlirpState {
+    /// PID of the slirp4netns process
+    pub pid: i32,
+    pub address: Ipv4Addr,
+    pub api_socket: PathBuf,
+    /// Ports forwarded by slirp4netns
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+}
+
+/// Running slirp4netns process.
+/// It exits by itself when `exit_fd` is closed.
+pub struct Slirp {
+    child: Child,
+    exit_fd: RawFd,
+    pub state: SlirpState,
+}
+
+/// Start slirp4netns for the network namespace of `pid`.
+/// slirp4netns creates the TAP device in the container, configures its
+/// address and default route, and runs a userspace TCP/UDP stack on the host
+/// side, so no privilege is needed on the host.
+/// Without root the container must have been mapped first (see `handle_rootless_id_map`).
+pub fn start_slirp(container_dir: &Path, config: &SlirpConfig, pid: Pid) -> anyhow::Result<Slirp> {
+    let (ready_r, ready_w) = pipe().map_err(|e| {
+        error!("Unable to create pipe: {:?}", e);
+        Errcode::NetworkError(22)
+    })?;
+    let (exit_r, exit_w) = match pipe() {
+        Ok(fds) => fds,
+        Err(e) => {
+            error!("Unable to create pipe: {:?}", e);
+            let _ = close(ready_r);
+            let _ = close(ready_w);
+            return Err(Errcode::NetworkError(22).into());
+        }
+    };
+
+    // slirp4netns must only inherit its own ends
+    for fd in [ready_r, exit_w] {
+        if let Err(e) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            error!("Unable to set close-on-exec: {:?}", e);
+        }
+    }
+
+    let api_socket = container_dir.join(API_SOCKET);
+    debug!("Starting {} for {}", SLIRP4NETNS, pid);
+    let mut command = Command::new(SLIRP4NETNS);
+    if config.join_userns {
+        command.arg(format!("--userns-path=/proc/{}/ns/user", pid.as_raw()));
+    }
+    let child = command
+        .arg("--configure")
+        .arg(format!("--mtu={}", MTU))
+        .arg("--disable-host-loopback")
+        .arg(format!("--ready-fd={}", ready_w))
+        .arg(format!("--exit-fd={}", exit_r))
+        .arg(format!("--api-socket={}", api_socket.display()))
+        .arg(pid.as_raw().to_string())
+        .arg(TAP_NAME)
+        .spawn();
+    // only needed by slirp4netns
+    let _ = close(ready_w);
+    let _ = close(exit_r);
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Unable to run {}: {}", SLIRP4NETNS, e);
+            let _ = close(ready_r);
+            let _ = close(exit_w);
+            return Err(Errcode::NetworkError(23).into());
+        }
+    };
+
+    let mut slirp = Slirp {
+        state: SlirpState {
+            pid: child.id() as i32,
+            address: SLIRP_ADDRESS,
+            api_socket,
+            ports: vec![],
+        },
+        child,
+        exit_fd: exit_w,
+    };
+
+    // slirp4netns writes "1" once the TAP device is configured
+    let mut buf = [0u8; 1];
+    let ready = read(ready_r, &mut buf);
+    let _ = close(ready_r);
+    if !matches!(ready, Ok(1)) {
+        error!("{} exited before the network was ready", SLIRP4NETNS);
+        let _ = slirp.stop();
+        return Err(Errcode::NetworkError(24).into());
+    }
+
+    for port in config.ports.iter() {
+        if let Err(e) = slirp.add_port(port) {
+            let _ = slirp.stop();
+            return Err(e);
+        }
+    }
+    info!("Container address {} on {}", SLIRP_ADDRESS, SLIRP4NETNS);
+    Ok(slirp)
+}
+
+/// Request for the slirp4netns API socket.
+fn hostfwd_request(port: &PortMapping) -> Value {
+    json!({
+        "execute": "add_hostfwd",
+        "arguments": {
+            "proto": port.protocol.to_string(),
+            "host_addr": "0.0.0.0",
+            "host_port": port.host_port,
+            "guest_port": port.container_port,
+        }
+    })
+}
+
+impl Slirp {
+    /// Forward a host port to the container through the API socket.
+    fn add_port(&mut self, port: &PortMapping) -> anyhow::Result<()> {
+        let response = UnixStream::connect(&self.state.api_socket)
+            .and_then(|mut stream| {
+                stream.write_all(hostfwd_request(port).to_string().as_bytes())?;
+                stream.shutdown(std::net::Shutdown::Write)?;
+                let mut line = String::new();
+                BufReader::new(stream).read_line(&mut line)?;
+                Ok(line)
+            })
+            .map_err(|e| {
+                error!("Unable to talk to {}: {}", SLIRP4NETNS, e);
+                Errcode::NetworkError(25)
+            })?;
+
+        match serde_json::from_str::<Value>(&response) {
+            Ok(value) if value.get("return").is_some() => {
+                info!("Publishing {}", port);
+                self.state.ports.push(*port);
+                Ok(())
+            }
+            _ => {
+                error!("Unable to publish {}: {}", port, response.trim());
+                Err(Errcode::NetworkError(26).into())
+            }
+        }
+    }
+
+    /// Stop slirp4netns and wait for it.
+    pub fn stop(mut self) -> anyhow::Result<()> {
+        debug!("Stopping {} {}", SLIRP4NETNS, self.state.pid);
+        let _ = close(self.exit_fd);
+        if let Err(e) = self.child.wait() {
+            error!("Unable to wait for {}: {}", SLIRP4NETNS, e);
+            return Err(Errcode::NetworkError(27).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostfwd_request_success() {
+        let port: PortMapping = "5353:53/udp".parse().unwrap();
+        let request = hostfwd_request(&port);
+        assert_eq!(request["execute"], "add_hostfwd");
+        assert_eq!(request["arguments"]["proto"], "udp");
+        assert_eq!(request["arguments"]["host_port"], 5353);
+        assert_eq!(request["arguments"]["guest_port"], 53);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::cni::CniState;
+use crate::errors::Errcode;
+use crate::network::NetworkState;
+use crate::slirp::SlirpState;
+
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{self};
+use log::error;
+
+//コンテナごとのファイルを置くdirectory(<data root>/containers/<id>)
+const CONTAINERS_DIR: &str = "containers";
+const STATE_FILE: &str = "state.json";
+//コンテナ名を予約するファイルを置くdirectory(<data root>/names/<name>)
+const NAMES_DIR: &str = "names";
+//IDは32byteのランダムな値の16進表記
+const ID_BYTES: usize = 32;
+//psなどで表示する短いID
+pub const SHORT_ID_LEN: usize = 12;
+
+/// State of a container, saved in `<data root>/containers/<id>/state.json`
+/// while the container exists.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    pub name: Option<String>,
+    pub hostname: String,
+    /// PID of the container init process on the host
+    pub pid: Option<i32>,
+    /// Creation time (seconds since the epoch)
+    pub created_at: u64,
+    pub command: String,
+    pub mount_directory: PathBuf,
+    /// Bridge network, torn down when the container is cleaned up
+    #[serde(default)]
+    pub network: Option<NetworkState>,
+    /// Userspace network, slirp4netns is stopped with the container
+    #[serde(default)]
+    pub slirp: Option<SlirpState>,
+    /// CNI network, the plugins are called with DEL on cleanup
+    #[serde(default)]
+    pub cni: Option<CniState>,
+    /// Cgroup of the container, relative to the root of the hierarchy.
+    /// None without root, cgroups are not used then
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    /// Named volumes mounted in the container, they can not be removed meanwhile
+    #[serde(default)]
+    pub volumes: Vec<String>,
+}
+
+/// Directory holding the files of the container `id`.
+pub fn container_dir(data_root: &Path, id: &str) -> PathBuf {
+    data_root.join(CONTAINERS_DIR).join(id)
+}
+
+/// Names of volumes and containers are used as directory names,
+/// only allow `[a-zA-Z0-9][a-zA-Z0-9_.-]*`.
+/// `arg` names the CLI argument in the error.
+pub fn validate_name(name: &str, arg: &'static str) -> anyhow::Result<()> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) if c.is_ascii_alphanumeric() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+        }
+        _ => false,
+    };
+    if !valid {
+        error!("Invalid {} {:?}", arg, name);
+        return Err(Errcode::InvalidArgument(arg).into());
+    }
+    Ok(())
+}
+
+fn random_id() -> anyhow::Result<String> {
+    let mut bytes = [0u8; ID_BYTES];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)) {
+        error!("Unable to read random bytes: {}", e);
+        return Err(Errcode::StateError(0).into());
+    }
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Generate a new container ID.
+/// Its directory is only created with the container by `create_container_dir`.
+pub fn new_id(data_root: &Path) -> anyhow::Result<String> {
+    loop {
+        let id = random_id()?;
+        // short IDs must stay usable as a prefix
+        let short_used = list(data_root)
+            .iter()
+            .any(|state| state.id.starts_with(&id[..SHORT_ID_LEN]));
+        if !short_used && !container_dir(data_root, &id).exists() {
+            return Ok(id);
+        }
+    }
+}
+
+/// Create the directory of the container `id`.
+/// Fails if it already exists, so that two containers never share it.
+/// The directory stays locked while the returned file is open (see `lock_container`).
+pub fn create_container_dir(data_root: &Path, id: &str) -> anyhow::Result<(PathBuf, File)> {
+    let containers = data_root.join(CONTAINERS_DIR);
+    if let Err(e) = fs::create_dir_all(&containers) {
+        error!("Unable to create {:?}: {}", containers, e);
+        return Err(Errcode::StateError(1).into());
+    }
+    let dir = containers.join(id);
+    if let Err(e) = fs::create_dir(&dir) {
+        error!("Unable to create container directory {:?}: {}", dir, e);
+        return Err(Errcode::StateError(2).into());
+    }
+    let lock = lock_container(&dir)?;
+    Ok((dir, lock))
+}
+
+/// Lock the directory of a container.
+/// The runtime holds the lock until the container is cleaned up, so it is
+/// only free for containers whose runtime was killed.
+pub fn lock_container(dir: &Path) -> anyhow::Result<File> {
+    let lock = match File::open(dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Unable to open container directory {:?}: {}", dir, e);
+            return Err(Errcode::StateError(2).into());
+        }
+    };
+    if flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_err() {
+        error!("Container directory {:?} is used by another run", dir);
+        return Err(Errcode::StateError(10).into());
+    }
+    Ok(lock)
+}
+
+/// Reserve `name` for the container being created.
+/// The name stays reserved while the returned file is open, so a killed
+/// runtime does not keep it. The file is locked instead of created
+/// with `create_new` for that reason and is never removed.
+/// The state the killed runtime left keeps the name until it is removed with `rm`.
+pub fn reserve_name(data_root: &Path, name: &str) -> anyhow::Result<File> {
+    validate_name(name, "name")?;
+    let dir = data_root.join(NAMES_DIR);
+    let file = fs::create_dir_all(&dir).and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(name))
+    });
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Unable to reserve the container name {}: {}", name, e);
+            return Err(Errcode::StateError(8).into());
+        }
+    };
+    // another run holds the name until its container is removed
+    let locked = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock).is_ok();
+    // containers whose state was left by a killed runtime
+    let used = list(data_root)
+        .iter()
+        .any(|state| state.name.as_deref() == Some(name));
+    if !locked || used {
+        error!(
+            "Container name {} is already in use, remove an exited container with rm",
+            name
+        );
+        return Err(Errcode::StateError(3).into());
+    }
+    Ok(file)
+}
+
+impl ContainerState {
+    pub fn new(
+        id: String,
+        name: Option<String>,
+        hostname: String,
+        command: String,
+        mount_directory: PathBuf,
+    ) -> ContainerState {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        ContainerState {
+            id,
+            name,
+            hostname,
+            pid: None,
+            created_at,
+            command,
+            mount_directory,
+            network: None,
+            slirp: None,
+            cni: None,
+            cgroup: None,
+            volumes: vec![],
+        }
+    }
+
+    /// Write the state file, replacing it atomically.
+    pub fn save(&self, data_root: &Path) -> anyhow::Result<()> {
+        let dir = container_dir(data_root, &self.id);
+        let tmp = dir.join(format!(".{}", STATE_FILE));
+        let written = File::create(&tmp)
+            .map_err(anyhow::Error::from)
+            .and_then(|f| serde_json::to_writer(f, self).map_err(anyhow::Error::from))
+            .and_then(|_| fs::rename(&tmp, dir.join(STATE_FILE)).map_err(anyhow::Error::from));
+        if let Err(e) = written {
+            error!("Unable to save state of container {}: {}", self.id, e);
+            return Err(Errcode::StateError(4).into());
+        }
+        Ok(())
+    }
+
+    pub fn short_id(&self) -> &str {
+        &self.id[..SHORT_ID_LEN.min(self.id.len())]
+    }
+
+    /// Cgroup of the container, containers run without root have none.
+    pub fn cgroup(&self) -> anyhow::Result<&str> {
+        match self.cgroup.as_deref() {
+            Some(cgroup) => Ok(cgroup),
+            None => {
+                error!(
+                    "Container {} has no cgroup, it was run without root",
+                    self.short_id()
+                );
+                Err(Errcode::StateError(9).into())
+            }
+        }
+    }
+
+    /// The container process is still alive.
+    pub fn is_running(&self) -> bool {
+        match self.pid {
+            Some(pid) => kill(Pid::from_raw(pid), None).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Every container with a state file, oldest first.
+pub fn list(data_root: &Path) -> Vec<ContainerState> {
+    let mut states = vec![];
+    if let Ok(entries) = fs::read_dir(data_root.join(CONTAINERS_DIR)) {
+        for entry in entries.flatten() {
+            let path = entry.path().join(STATE_FILE);
+            if let Ok(Ok(state)) = File::open(path).map(serde_json::from_reader) {
+                states.push(state);
+            }
+        }
+    }
+    states.sort_by_key(|state: &ContainerState| state.created_at);
+    states
+}
+
+/// Find a container by its name, full ID or a unique ID prefix.
+pub fn lookup(data_root: &Path, id_or_name: &str) -> anyhow::Result<ContainerState> {
+    let states = list(data_root);
+    if let Some(state) = states
+        .iter()
+        .find(|s| s.name.as_deref() == Some(id_or_name) || s.id == id_or_name)
+    {
+        return Ok(state.clone());
+    }
+
+    let mut matched = states.into_iter().filter(|s| s.id.starts_with(id_or_name));
+    match (matched.next(), matched.next()) {
+        (Some(state), None) if !id_or_name.is_empty() => Ok(state),
+        (Some(_), Some(_)) => {
+            error!("Container ID prefix {} is ambiguous", id_or_name);
+            Err(Errcode::StateError(6).into())
+        }
+        _ => {
+            error!("No such container: {}", id_or_name);
+            Err(Errcode::StateError(5).into())
+        }
+    }
+}
+
+/// `ps` sub command
+pub fn handle_ps(data_root: &Path) -> anyhow::Result<()> {
+    println!(
+        "{:<14}{:<20}{:<24}{:<10}{:<8}COMMAND",
+        "ID", "NAME", "HOSTNAME", "STATUS", "PID"
+    );
+    for state in list(data_root) {
+        let status = if state.is_running() {
+            "running"
+        } else {
+            "exited"
+        };
+        let pid = state.pid.map(|p| p.to_string()).unwrap_or_default();
+        println!(
+            "{:<14}{:<20}{:<24}{:<10}{:<8}{}",
+            state.short_id(),
+            state.name.as_deref().unwrap_or(""),
+            state.hostname,
+            status,
+            pid,
+            state.command
+        );
+    }
+    Ok(())
+}
+
+/// `inspect` sub command
+pub fn handle_inspect(data_root: &Path, containers: &[String]) -> anyhow::Result<()> {
+    let mut states = vec![];
+    for id_or_name in containers.iter() {
+        states.push(lookup(data_root, id_or_name)?);
+    }
+    println!("{}", serde_json::to_string_pretty(&states)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount::test_dir;
+
+    #[test]
+    fn validate_name_success() {
+        assert!(validate_name("web", "name").is_ok());
+        assert!(validate_name("my_web-1.0", "name").is_ok());
+        assert!(validate_name("", "name").is_err());
+        assert!(validate_name("-web", "name").is_err());
+        assert!(validate_name("../web", "name").is_err());
+    }
+
+    #[test]
+    fn reserve_and_lookup() {
+        let root = test_dir();
+        let id1 = new_id(&root).unwrap();
+        let id2 = new_id(&root).unwrap();
+        assert_eq!(id1.len(), ID_BYTES * 2);
+        assert!(id1.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id1, id2);
+        assert!(!container_dir(&root, &id1).exists());
+        let (dir1, lock) = create_container_dir(&root, &id1).unwrap();
+        assert!(dir1.is_dir());
+        assert!(create_container_dir(&root, &id1).is_err());
+        // locked until the runtime goes away
+        assert!(lock_container(&dir1).is_err());
+        drop(lock);
+        assert!(lock_container(&dir1).is_ok());
+        create_container_dir(&root, &id2).unwrap();
+
+        let state = ContainerState::new(
+            id1.clone(),
+            Some("web".to_string()),
+            "web-host".to_string(),
+            "/bin/sh".to_string(),
+            PathBuf::from("/"),
+        );
+        state.save(&root).unwrap();
+        assert!(reserve_name(&root, "web").is_err());
+        let db = reserve_name(&root, "db").unwrap();
+        assert!(reserve_name(&root, "db").is_err());
+        drop(db);
+        assert!(reserve_name(&root, "db").is_ok());
+        assert!(reserve_name(&root, "../db").is_err());
+
+        assert_eq!(lookup(&root, "web").unwrap().id, id1);
+        assert_eq!(lookup(&root, &id1).unwrap().id, id1);
+        assert_eq!(lookup(&root, &id1[..SHORT_ID_LEN]).unwrap().id, id1);
+        // id2 has no state file yet
+        assert!(lookup(&root, &id2).is_err());
+        assert!(lookup(&root, "").is_err());
+        assert!(!lookup(&root, "web").unwrap().is_running());
+        // saved without a cgroup, like a container run without root
+        assert!(lookup(&root, "web").unwrap().cgroup().is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use crate::errors::Errcode;
+use crate::resource::{load_cgroup, memory_usage, UNLIMITED_MEMORY};
+use crate::state::{lookup, ContainerState};
+
+use cgroups_rs::blkio::BlkIoController;
+use cgroups_rs::cpu::CpuController;
+use cgroups_rs::cpuacct::CpuAcctController;
+use cgroups_rs::memory::MemController;
+use cgroups_rs::pid::PidController;
+use cgroups_rs::{Cgroup, MaxValue};
+use serde::Serialize;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{self};
+use log::error;
+
+//CPU使用率を計算する間隔,streamingの場合は表示する間隔
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counters read from the cgroup of a container.
+#[derive(Debug, Clone)]
+struct Sample {
+    at: Instant,
+    /// CPU time used by the container in nanoseconds
+    cpu_usage: u64,
+    memory_usage: u64,
+    memory_limit: Option<u64>,
+    pids: u64,
+    pids_limit: Option<u64>,
+    block_read: u64,
+    block_write: u64,
+}
+
+/// Resource usage of a container, as printed by the `stats` command.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContainerStats {
+    pub id: String,
+    pub name: Option<String>,
+    /// 100% is one CPU fully used
+    pub cpu_percent: f64,
+    /// Memory used without the page cache that can be reclaimed, in bytes
+    pub memory_usage: u64,
+    /// None when not limited
+    pub memory_limit: Option<u64>,
+    pub memory_percent: Option<f64>,
+    pub pids: u64,
+    pub pids_limit: Option<u64>,
+    /// Bytes read from and written to block devices
+    pub block_read: u64,
+    pub block_write: u64,
+}
+
+/// CPU time used between two samples, in percent of the elapsed time.
+fn cpu_percent(prev: u64, usage: u64, elapsed: Duration) -> f64 {
+    match elapsed.as_nanos() {
+        0 => 0.0,
+        elapsed => usage.saturating_sub(prev) as f64 / elapsed as f64 * 100.0,
+    }
+}
+
+/// `usage_usec` of a v2 cpu.stat file, in nanoseconds.
+fn cpu_stat_usage(stat: &str) -> u64 {
+    stat.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|usec| usec.trim().parse::<u64>().ok())
+        .map_or(0, |usec| usec * 1000)
+}
+
+fn memory_limit(limit: i64) -> Option<u64> {
+    match limit {
+        limit if limit <= 0 || limit >= UNLIMITED_MEMORY => None,
+        limit => Some(limit as u64),
+    }
+}
+
+fn read_sample(cgs: &Cgroup) -> Sample {
+    let mut sample = Sample {
+        at: Instant::now(),
+        cpu_usage: 0,
+        memory_usage: 0,
+        memory_limit: None,
+        pids: 0,
+        pids_limit: None,
+        block_read: 0,
+        block_write: 0,
+    };
+    // v1 accounts the CPU time in cpuacct, v2 in cpu.stat
+    if let Some(cpuacct) = cgs.controller_of::<CpuAcctController>() {
+        sample.cpu_usage = cpuacct.cpuacct().usage;
+    } else if let Some(cpu) = cgs.controller_of::<CpuController>() {
+        sample.cpu_usage = cpu_stat_usage(&cpu.cpu().stat);
+    }
+    if let Some(mem) = cgs.controller_of::<MemController>() {
+        sample.memory_usage = memory_usage(mem);
+        sample.memory_limit = memory_limit(mem.memory_stat().limit_in_bytes);
+    }
+    if let Some(pids) = cgs.controller_of::<PidController>() {
+        sample.pids = pids.get_pid_current().unwrap_or(0);
+        sample.pids_limit = match pids.get_pid_max() {
+            Ok(MaxValue::Value(max)) => Some(max as u64),
+            _ => None,
+        };
+    }
+    if let Some(blkio) = cgs.controller_of::<BlkIoController>() {
+        let blkio = blkio.blkio();
+        match cgs.v2() {
+            true => {
+                sample.block_read = blkio.io_stat.iter().map(|s| s.rbytes).sum();
+                sample.block_write = blkio.io_stat.iter().map(|s| s.wbytes).sum();
+            }
+            false => {
+                let service = &blkio.throttle.io_service_bytes_recursive;
+                sample.block_read = service.iter().map(|s| s.read).sum();
+                sample.block_write = service.iter().map(|s| s.write).sum();
+            }
+        }
+    }
+    sample
+}
+
+fn container_stats(state: &ContainerState, prev: &Sample, sample: &Sample) -> ContainerStats {
+    ContainerStats {
+        id: state.id.clone(),
+        name: state.name.clone(),
+        cpu_percent: cpu_percent(
+            prev.cpu_usage,
+            sample.cpu_usage,
+            sample.at.duration_since(prev.at),
+        ),
+        memory_usage: sample.memory_usage,
+        memory_limit: sample.memory_limit,
+        memory_percent: sample
+            .memory_limit
+            .map(|limit| sample.memory_usage as f64 / limit as f64 * 100.0),
+        pids: sample.pids,
+        pids_limit: sample.pids_limit,
+        block_read: sample.block_read,
+        block_write: sample.block_write,
+    }
+}
+
+/// Size in binary units, e.g. `12.5MiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.1}{}", size, UNITS[unit]),
+    }
+}
+
+fn print_table(stats: &[ContainerStats]) {
+    println!(
+        "{:<14}{:<20}{:<10}{:<24}{:<10}{:<12}BLOCK I/O",
+        "ID", "NAME", "CPU %", "MEM USAGE / LIMIT", "MEM %", "PIDS"
+    );
+    for s in stats.iter() {
+        let memory = format!(
+            "{} / {}",
+            format_bytes(s.memory_usage),
+            s.memory_limit.map_or("unlimited".to_string(), format_bytes)
+        );
+        let pids = match s.pids_limit {
+            Some(limit) => format!("{} / {}", s.pids, limit),
+            None => s.pids.to_string(),
+        };
+        println!(
+            "{:<14}{:<20}{:<10}{:<24}{:<10}{:<12}{} / {}",
+            &s.id[..s.id.len().min(12)],
+            s.name.as_deref().unwrap_or(""),
+            format!("{:.2}%", s.cpu_percent),
+            memory,
+            s.memory_percent
+                .map_or("--".to_string(), |p| format!("{:.2}%", p)),
+            pids,
+            format_bytes(s.block_read),
+            format_bytes(s.block_write)
+        );
+    }
+}
+
+/// `stats` sub command
+/// Prints the usage every second until the containers exit, or once with `no_stream`.
+pub fn handle_stats(
+    data_root: &Path,
+    containers: &[String],
+    no_stream: bool,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut targets = vec![];
+    for id_or_name in containers.iter() {
+        let state = lookup(data_root, id_or_name)?;
+        if !state.is_running() {
+            error!("Container {} is not running", state.short_id());
+            return Err(Errcode::StateError(7).into());
+        }
+        let cgs = load_cgroup(state.cgroup()?);
+        let sample = read_sample(&cgs);
+        targets.push((state, cgs, sample));
+    }
+
+    loop {
+        sleep(INTERVAL);
+        targets.retain(|(state, _, _)| state.is_running());
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let mut stats = vec![];
+        for (state, cgs, prev) in targets.iter_mut() {
+            let sample = read_sample(cgs);
+            stats.push(container_stats(state, prev, &sample));
+            *prev = sample;
+        }
+        match (json, no_stream) {
+            (true, true) => println!("{}", serde_json::to_string_pretty(&stats)?),
+            //streamingの場合は1行に1回分
+            (true, false) => println!("{}", serde_json::to_string(&stats)?),
+            (false, _) => print_table(&stats),
+        }
+        if no_stream {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_percent_success() {
+        let second = Duration::from_secs(1);
+        assert_eq!(cpu_percent(0, 500_000_000, second), 50.0);
+        assert_eq!(cpu_percent(1_000_000_000, 3_000_000_000, second), 200.0);
+        assert_eq!(cpu_percent(10, 5, second), 0.0);
+        assert_eq!(cpu_percent(0, 10, Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn cpu_stat_usage_success() {
+        let stat = "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\n";
+        assert_eq!(cpu_stat_usage(stat), 1_500_000);
+        assert_eq!(cpu_stat_usage(""), 0);
+    }
+
+    #[test]
+    fn memory_limit_success() {
+        assert_eq!(memory_limit(1 << 30), Some(1 << 30));
+        assert_eq!(memory_limit(-1), None);
+        assert_eq!(memory_limit(9223372036854771712), None);
+    }
+
+    #[test]
+    fn format_bytes_success() {
+        assert_eq!(format_bytes(0), "0B");
+        assert_eq!(format_bytes(1023), "1023B");
+        assert_eq!(format_bytes(1536), "1.5KiB");
+        assert_eq!(format_bytes(64 << 20), "64.0MiB");
+        assert_eq!(format_bytes(3 << 40), "3.0TiB");
+    }
+}
@@ -1,17 +1,97 @@
+use crate::config_opts::ContainerOptions;
 use crate::errors::Errcode;
+use crate::ipc::send_fd;
+use crate::notify::NotifyRule;
+use crate::seccomp::{RuleContext, SeccompFilter};
 
-use libc::TIOCSTI;
+use libc::{c_ulong, sock_filter, sock_fprog, TIOCSTI};
+use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_LD, BPF_W, SECCOMP_RET_KILL_PROCESS};
+use libc::{
+    BPF_K, BPF_RET, SECCOMP_RET_ACTION_FULL, SECCOMP_RET_ERRNO, SECCOMP_RET_LOG, SECCOMP_RET_TRAP,
+};
+use libc::{SECCOMP_FILTER_FLAG_NEW_LISTENER, SECCOMP_RET_TRACE, SECCOMP_RET_USER_NOTIF};
 use nix::sched::CloneFlags;
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use nix::sys::stat::Mode;
+use nix::unistd::close;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::{FromRawFd, RawFd};
 use syscallz::{Action, Cmp, Comparator, Context, Syscall};
 
 use anyhow::{self};
-use log::{debug};
+use log::{debug, error};
 
 //operation not permitted error
 const EPERM: u16 = 1;
+//seccomp_dataのoffset
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+//AUDIT_ARCH_*(ruleを作るnativeのarchitecture)
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH_NATIVE: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH_NATIVE: u32 = 0xc000_00b7;
+//x32 ABIのsyscall番号に付くbit(archはx86_64のまま)
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
 
-pub fn set_syscalls() -> anyhow::Result<()> {
+pub fn set_syscalls(config: &ContainerOptions) -> anyhow::Result<()> {
+    //listenerを渡すまでのsyscallが他のfilterに拒否されないように先に読み込む
+    if let Some(sock) = config.notify_socket {
+        let listener = load_notify_filter(&config.seccomp_notify)?;
+        let sent = send_fd(sock, listener);
+        let _ = close(listener);
+        sent?;
+    }
+
+    match &config.seccomp {
+        SeccompFilter::Unconfined => {
+            debug!("Running without seccomp filter");
+            Ok(())
+        }
+        SeccompFilter::Legacy => load_filter(
+            &legacy_filter(&config.seccomp_notify)?,
+            0,
+            config.seccomp_log,
+        ),
+        SeccompFilter::Profile(profile) => {
+            debug!("Loading seccomp profile");
+            let rctx = RuleContext::new(config.capabilities.bounding, &config.seccomp_notify);
+            let ctx = profile.build(&rctx)?;
+            load_filter(&ctx, 0, config.seccomp_log)
+        }
+    }
+}
+
+/// Filter sending the calls of `rules` to the supervisor, returning its listener.
+/// syscallz has no notify action, so the calls are traced and the exported
+/// BPF is rewritten.
+pub fn load_notify_filter(rules: &[NotifyRule]) -> anyhow::Result<RawFd> {
+    debug!("Loading seccomp notify filter");
+    let mut ctx = Context::init_with_action(Action::Allow).map_err(|e| {
+        error!("Unable to initialize seccomp: {}", e);
+        Errcode::SyscallsError(1)
+    })?;
+    for rule in rules.iter() {
+        if let Err(e) = ctx.set_action_for_syscall(Action::Trace(0), rule.syscall) {
+            error!("Unable to notify {:?}: {}", rule.syscall, e);
+            return Err(Errcode::SyscallsError(10).into());
+        }
+    }
+    let mut filter = export_filter(&ctx)?;
+    for insn in filter.iter_mut() {
+        if insn.code == (BPF_RET | BPF_K) as u16 && insn.k == SECCOMP_RET_TRACE {
+            insn.k = SECCOMP_RET_USER_NOTIF;
+        }
+    }
+    load_bpf(&mut filter, SECCOMP_FILTER_FLAG_NEW_LISTENER)
+}
+
+/// Denylist of the earlier versions, kept as `--seccomp legacy`.
+/// The calls of `notify` are left to the supervisor (see `SeccompProfile::build`).
+fn legacy_filter(notify: &[NotifyRule]) -> anyhow::Result<Context> {
     debug!("Refusing / Filtering unwanted syscalls");
     let s_isuid: u64 = Mode::S_ISUID.bits().into();
     let s_isgid: u64 = Mode::S_ISGID.bits().into();
@@ -45,24 +125,152 @@ pub fn set_syscalls() -> anyhow::Result<()> {
 
     // Initialize seccomp profile with all syscalls allowed by default
     if let Ok(mut ctx) = Context::init_with_action(Action::Allow) {
+        let notified = |sc: &Syscall| notify.iter().any(|rule| rule.syscall == *sc);
         for (sc, ind, biteq) in syscalls_reject_conditional.iter() {
-            reject_conditional_syscall(&mut ctx, *ind, sc, *biteq)?;
+            if !notified(sc) {
+                reject_conditional_syscall(&mut ctx, *ind, sc, *biteq)?;
+            }
         }
 
         for sc in syscalls_reject.iter() {
-            reject_syscall(&mut ctx, sc)?;
-        }
-
-        if ctx.load().is_err() {
-            return Err(Errcode::SyscallsError(0).into());
+            if !notified(sc) {
+                reject_syscall(&mut ctx, sc)?;
+            }
         }
 
-        Ok(())
+        Ok(ctx)
     } else {
         Err(Errcode::SyscallsError(1).into())
     }
 }
 
+fn bpf_stmt(code: u32, k: u32) -> sock_filter {
+    bpf_jump(code, k, 0, 0)
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Kill the process on calls of another architecture:
+/// i386 (int 0x80) and x32 on x86_64, arm on aarch64.
+/// Rules are only built for the native one, and libseccomp only kills the thread.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn arch_prologue() -> Vec<sock_filter> {
+    let kill = bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS);
+    let prologue = [
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, DATA_ARCH),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH_NATIVE, 1, 0),
+        kill,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    return [
+        &prologue[..],
+        &[
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, DATA_NR),
+            bpf_jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 2),
+            // -1 skips the call (ptrace), it is not x32
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, u32::MAX, 1, 0),
+            kill,
+        ],
+    ]
+    .concat();
+    #[cfg(target_arch = "aarch64")]
+    prologue.to_vec()
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn arch_prologue() -> Vec<sock_filter> {
+    vec![]
+}
+
+/// Compile the rules of `ctx` into BPF, behind the check of the architecture.
+fn export_filter(ctx: &Context) -> anyhow::Result<Vec<sock_filter>> {
+    let name = CString::new("bowl-seccomp").unwrap();
+    let mut file = match memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC) {
+        Ok(fd) => unsafe { File::from_raw_fd(fd) },
+        Err(e) => {
+            error!("Unable to create memfd: {:?}", e);
+            return Err(Errcode::SyscallsError(4).into());
+        }
+    };
+    let mut bpf = vec![];
+    let exported = ctx
+        .export_bpf(&mut file)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| file.seek(SeekFrom::Start(0)).map_err(anyhow::Error::from))
+        .and_then(|_| file.read_to_end(&mut bpf).map_err(anyhow::Error::from));
+    if let Err(e) = exported {
+        error!("Unable to export seccomp filter: {}", e);
+        return Err(Errcode::SyscallsError(4).into());
+    }
+
+    let mut filter = arch_prologue();
+    filter.extend(bpf.chunks_exact(8).map(|insn| sock_filter {
+        code: u16::from_ne_bytes([insn[0], insn[1]]),
+        jt: insn[2],
+        jf: insn[3],
+        k: u32::from_ne_bytes([insn[4], insn[5], insn[6], insn[7]]),
+    }));
+    Ok(filter)
+}
+
+/// Turn the denying returns (errno, trap) of a filter into SECCOMP_RET_LOG,
+/// so the calls are allowed and written to the audit log.
+/// syscallz has no log action, so the exported BPF is rewritten.
+fn log_actions(filter: &mut [sock_filter]) {
+    for insn in filter.iter_mut() {
+        if insn.code != (BPF_RET | BPF_K) as u16 {
+            continue;
+        }
+        let action = insn.k & SECCOMP_RET_ACTION_FULL;
+        if action == SECCOMP_RET_ERRNO || action == SECCOMP_RET_TRAP {
+            insn.k = SECCOMP_RET_LOG;
+        }
+    }
+}
+
+/// Load the filter with seccomp(2).
+/// `Context::load` always sets no_new_privs (libseccomp default),
+/// so the BPF is loaded directly to keep it optional.
+/// Without no_new_privs this needs CAP_SYS_ADMIN.
+fn load_filter(ctx: &Context, flags: c_ulong, log: bool) -> anyhow::Result<()> {
+    let mut filter = export_filter(ctx)?;
+    if log {
+        log_actions(&mut filter);
+    }
+    load_bpf(&mut filter, flags).map(|_| ())
+}
+
+/// seccomp(2) returns the listener with SECCOMP_FILTER_FLAG_NEW_LISTENER, 0 otherwise.
+fn load_bpf(filter: &mut [sock_filter], flags: c_ulong) -> anyhow::Result<RawFd> {
+    let prog = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &prog as *const sock_fprog,
+        )
+    };
+    if ret < 0 {
+        error!(
+            "Unable to load seccomp filter: {}",
+            std::io::Error::last_os_error()
+        );
+        return Err(Errcode::SyscallsError(0).into());
+    }
+    Ok(ret as RawFd)
+}
+
 /// Restricting Unconditional System Calls
 /// Reject system calls that you do not want your children to execute.
 fn reject_syscall(ctx: &mut Context, sc: &Syscall) -> anyhow::Result<()> {
@@ -91,3 +299,148 @@ fn reject_conditional_syscall(
         Err(_) => Err(Errcode::SyscallsError(2).into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn returns(filter: &[sock_filter]) -> Vec<u32> {
+        filter
+            .iter()
+            .filter(|insn| insn.code == (BPF_RET | BPF_K) as u16)
+            .map(|insn| insn.k & SECCOMP_RET_ACTION_FULL)
+            .collect()
+    }
+
+    #[test]
+    fn log_actions_success() {
+        let mut filter = export_filter(&legacy_filter(&[]).unwrap()).unwrap();
+        assert!(returns(&filter).contains(&SECCOMP_RET_ERRNO));
+        log_actions(&mut filter);
+        let actions = returns(&filter);
+        assert!(!actions.contains(&SECCOMP_RET_ERRNO));
+        assert!(actions.contains(&SECCOMP_RET_LOG));
+        // wrong architecture is still killed
+        assert!(actions.contains(&SECCOMP_RET_KILL_PROCESS));
+    }
+
+    /// Run a filter like the kernel does for a call.
+    fn run(filter: &[sock_filter], arch: u32, nr: i32, args: [u64; 6]) -> u32 {
+        // struct seccomp_data
+        let mut data = vec![];
+        data.extend(nr.to_ne_bytes());
+        data.extend(arch.to_ne_bytes());
+        data.extend(0u64.to_ne_bytes());
+        for arg in args {
+            data.extend(arg.to_ne_bytes());
+        }
+        let (mut pc, mut acc) = (0, 0u32);
+        loop {
+            let insn = filter[pc];
+            pc += 1;
+            let jump = |cond: bool| if cond { insn.jt } else { insn.jf } as usize;
+            match insn.code as u32 {
+                c if c == BPF_LD | BPF_W | BPF_ABS => {
+                    let k = insn.k as usize;
+                    acc = u32::from_ne_bytes(data[k..k + 4].try_into().unwrap());
+                }
+                c if c == libc::BPF_ALU | libc::BPF_AND | BPF_K => acc &= insn.k,
+                c if c == BPF_JMP | libc::BPF_JA => pc += insn.k as usize,
+                c if c == BPF_JMP | BPF_JEQ | BPF_K => pc += jump(acc == insn.k),
+                c if c == BPF_JMP | libc::BPF_JGT | BPF_K => pc += jump(acc > insn.k),
+                c if c == BPF_JMP | BPF_JGE | BPF_K => pc += jump(acc >= insn.k),
+                c if c == BPF_JMP | libc::BPF_JSET | BPF_K => pc += jump(acc & insn.k != 0),
+                c if c == BPF_RET | BPF_K => return insn.k,
+                c => panic!("unsupported instruction {:#x}", c),
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn filter_rejects_compat_calls() {
+        const AUDIT_ARCH_I386: u32 = 0x4000_0003;
+        // keyctl on i386
+        const I386_KEYCTL: i32 = 288;
+        let native = |sc: Syscall| sc.into_i32();
+        let x32 = |sc: Syscall| sc.into_i32() | X32_SYSCALL_BIT as i32;
+        let errno = SECCOMP_RET_ERRNO | EPERM as u32;
+        let allow = libc::SECCOMP_RET_ALLOW;
+        let legacy = export_filter(&legacy_filter(&[]).unwrap()).unwrap();
+        let run_legacy = |arch, nr| run(&legacy, arch, nr, [0; 6]);
+        assert_eq!(
+            run_legacy(AUDIT_ARCH_NATIVE, native(Syscall::keyctl)),
+            errno
+        );
+        assert_eq!(
+            run_legacy(AUDIT_ARCH_NATIVE, native(Syscall::getpid)),
+            allow
+        );
+        assert_eq!(run_legacy(AUDIT_ARCH_NATIVE, -1), allow);
+        assert_eq!(
+            run_legacy(AUDIT_ARCH_NATIVE, x32(Syscall::keyctl)),
+            SECCOMP_RET_KILL_PROCESS
+        );
+        assert_eq!(
+            run_legacy(AUDIT_ARCH_I386, I386_KEYCTL),
+            SECCOMP_RET_KILL_PROCESS
+        );
+        let chmod = native(Syscall::chmod);
+        assert_eq!(
+            run(&legacy, AUDIT_ARCH_NATIVE, chmod, [0, 0o4755, 0, 0, 0, 0]),
+            errno
+        );
+        assert_eq!(
+            run(&legacy, AUDIT_ARCH_NATIVE, chmod, [0, 0o755, 0, 0, 0, 0]),
+            allow
+        );
+
+        let rctx = RuleContext::new(crate::capa::CapConfig::default().bounding, &[]);
+        let ctx = crate::seccomp::default_profile().build(&rctx).unwrap();
+        let default = export_filter(&ctx).unwrap();
+        assert_eq!(
+            run(&default, AUDIT_ARCH_NATIVE, native(Syscall::getpid), [0; 6]),
+            allow
+        );
+        assert_eq!(
+            run(&default, AUDIT_ARCH_NATIVE, native(Syscall::keyctl), [0; 6]),
+            errno
+        );
+        assert_eq!(
+            run(&default, AUDIT_ARCH_NATIVE, x32(Syscall::getpid), [0; 6]),
+            SECCOMP_RET_KILL_PROCESS
+        );
+        assert_eq!(
+            run(&default, AUDIT_ARCH_I386, 20, [0; 6]),
+            SECCOMP_RET_KILL_PROCESS
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn notified_calls_allowed() {
+        let errno = SECCOMP_RET_ERRNO | EPERM as u32;
+        let allow = libc::SECCOMP_RET_ALLOW;
+        let rules: Vec<NotifyRule> = vec!["mount".parse().unwrap(), "chmod=deny".parse().unwrap()];
+        let mount = Syscall::mount.into_i32();
+        let chmod = Syscall::chmod.into_i32();
+
+        let caps = crate::capa::CapConfig::default().bounding;
+        let ctx = crate::seccomp::default_profile()
+            .build(&RuleContext::new(caps, &[]))
+            .unwrap();
+        let default = export_filter(&ctx).unwrap();
+        assert_eq!(run(&default, AUDIT_ARCH_NATIVE, mount, [0; 6]), errno);
+        let ctx = crate::seccomp::default_profile()
+            .build(&RuleContext::new(caps, &rules))
+            .unwrap();
+        let default = export_filter(&ctx).unwrap();
+        assert_eq!(run(&default, AUDIT_ARCH_NATIVE, mount, [0; 6]), allow);
+
+        let legacy = export_filter(&legacy_filter(&rules).unwrap()).unwrap();
+        assert_eq!(
+            run(&legacy, AUDIT_ARCH_NATIVE, chmod, [0, 0o4755, 0, 0, 0, 0]),
+            allow
+        );
+    }
+}
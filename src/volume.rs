@@ -0,0 +1,282 @@
+use crate::cli::VolumeArg;
+use crate::errors::Errcode;
+use crate::mount::{split_mount_spec, BindMount};
+use crate::namespace::{mapped_id, validate_uid};
+use crate::state::{list as list_containers, validate_name};
+
+use nix::unistd::{chown, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{self};
+use log::{debug, error, info};
+
+const VOLUMES_DIR: &str = "volumes";
+const DATA_DIR: &str = "_data";
+const META_FILE: &str = "volume.json";
+
+/// Named volume stored under `<data root>/volumes/<name>`.
+/// The contents live in the `_data` sub directory,
+/// which is what gets bind-mounted into the container.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Volume {
+    pub name: String,
+    pub mountpoint: PathBuf,
+    /// Creation time (seconds since the epoch)
+    pub created_at: u64,
+    /// Host owner of the mountpoint
+    #[serde(skip_deserializing)]
+    pub uid: u32,
+    #[serde(skip_deserializing)]
+    pub gid: u32,
+}
+
+fn volume_dir(data_root: &Path, name: &str) -> PathBuf {
+    data_root.join(VOLUMES_DIR).join(name)
+}
+
+/// Create a volume owned by the host `owner` UID/GID.
+pub fn create(data_root: &Path, name: &str, owner: u32) -> anyhow::Result<Volume> {
+    validate_name(name, "volume name")?;
+    let dir = volume_dir(data_root, name);
+    if dir.exists() {
+        error!("Volume {} already exists", name);
+        return Err(Errcode::VolumeError(0).into());
+    }
+
+    let mountpoint = dir.join(DATA_DIR);
+    if let Err(e) = fs::create_dir_all(&mountpoint) {
+        error!("Unable to create volume directory {:?}: {}", mountpoint, e);
+        return Err(Errcode::VolumeError(1).into());
+    }
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let volume = Volume {
+        name: name.to_string(),
+        mountpoint,
+        created_at,
+        uid: owner,
+        gid: owner,
+    };
+    let written = File::create(dir.join(META_FILE))
+        .map_err(anyhow::Error::from)
+        .and_then(|f| serde_json::to_writer(f, &volume).map_err(anyhow::Error::from));
+    if let Err(e) = written {
+        error!("Unable to write volume metadata for {}: {}", name, e);
+        return Err(Errcode::VolumeError(2).into());
+    }
+
+    set_owner(&volume, owner)?;
+    debug!("Volume {} created at {:?}", name, volume.mountpoint);
+    Ok(volume)
+}
+
+/// Load a volume and the current owner of its mountpoint.
+pub fn load(data_root: &Path, name: &str) -> anyhow::Result<Volume> {
+    validate_name(name, "volume name")?;
+    let dir = volume_dir(data_root, name);
+    let mut volume: Volume = match File::open(dir.join(META_FILE)) {
+        Ok(f) => match serde_json::from_reader(f) {
+            Ok(volume) => volume,
+            Err(e) => {
+                error!("Broken volume metadata for {}: {}", name, e);
+                return Err(Errcode::VolumeError(3).into());
+            }
+        },
+        Err(_) => {
+            error!("No such volume: {}", name);
+            return Err(Errcode::VolumeError(4).into());
+        }
+    };
+
+    let meta = fs::metadata(&volume.mountpoint).map_err(|e| {
+        error!("Unable to stat {:?}: {}", volume.mountpoint, e);
+        Errcode::VolumeError(5)
+    })?;
+    volume.uid = meta.uid();
+    volume.gid = meta.gid();
+    Ok(volume)
+}
+
+/// List every volume sorted by name.
+pub fn list(data_root: &Path) -> anyhow::Result<Vec<Volume>> {
+    let entries = match fs::read_dir(data_root.join(VOLUMES_DIR)) {
+        Ok(entries) => entries,
+        // nothing has been created yet
+        Err(_) => return Ok(vec![]),
+    };
+
+    let mut volumes = vec![];
+    for entry in entries.flatten() {
+        if let Some(name) = entry.file_name().to_str() {
+            match load(data_root, name) {
+                Ok(volume) => volumes.push(volume),
+                Err(e) => error!("Skipping volume {}: {}", name, e),
+            }
+        }
+    }
+    volumes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(volumes)
+}
+
+/// Delete a volume and all of its data.
+/// A volume mounted in a container is only removed with `force`.
+pub fn remove(data_root: &Path, name: &str, force: bool) -> anyhow::Result<()> {
+    let volume = load(data_root, name)?;
+    let used_by = list_containers(data_root)
+        .into_iter()
+        .find(|state| state.volumes.iter().any(|v| v == name));
+    if let (Some(state), false) = (used_by, force) {
+        error!(
+            "Volume {} is used by container {}, remove it first or use --force",
+            name,
+            state.name.as_deref().unwrap_or(state.short_id())
+        );
+        return Err(Errcode::VolumeError(8).into());
+    }
+    if let Err(e) = fs::remove_dir_all(volume_dir(data_root, &volume.name)) {
+        error!("Unable to remove volume {}: {}", name, e);
+        return Err(Errcode::VolumeError(6).into());
+    }
+    Ok(())
+}
+
+/// Change the host owner of the volume mountpoint.
+/// Only the top directory is changed, files created inside are left as is.
+pub fn set_owner(volume: &Volume, owner: u32) -> anyhow::Result<()> {
+    if let Err(e) = chown(
+        &volume.mountpoint,
+        Some(Uid::from_raw(owner)),
+        Some(Gid::from_raw(owner)),
+    ) {
+        error!("Unable to chown volume {} to {}: {}", volume.name, owner, e);
+        return Err(Errcode::VolumeError(7).into());
+    }
+    Ok(())
+}
+
+/// Resolve `-v <volume name>:<container path>[:<options>]` into the volume name
+/// and its bind mount.
+/// A missing volume is created, and the mountpoint is chowned to the host UID
+/// the container user `uid` is mapped to, so it is writable from inside.
+pub fn volume_mount(data_root: &Path, spec: &str, uid: u32) -> anyhow::Result<(String, BindMount)> {
+    let (name, target, options) = split_mount_spec(spec, "volumes")?;
+    validate_uid(uid)?;
+    let owner = mapped_id(uid);
+    validate_name(name, "volume name")?;
+    let volume = if volume_dir(data_root, name).exists() {
+        load(data_root, name)?
+    } else {
+        info!("Creating volume {}", name);
+        create(data_root, name, owner)?
+    };
+    if volume.uid != owner || volume.gid != owner {
+        set_owner(&volume, owner)?;
+    }
+    let bind = BindMount::new(volume.mountpoint, target, options)?;
+    Ok((volume.name, bind))
+}
+
+/// `volume` sub command
+pub fn handle_volume_command(data_root: &Path, arg: VolumeArg) -> anyhow::Result<()> {
+    match arg {
+        VolumeArg::Create { name } => {
+            let volume = create(data_root, &name, mapped_id(0))?;
+            println!("{}", volume.name);
+        }
+        VolumeArg::Ls => {
+            println!("{:<24} MOUNTPOINT", "NAME");
+            for volume in list(data_root)? {
+                println!("{:<24} {}", volume.name, volume.mountpoint.display());
+            }
+        }
+        VolumeArg::Rm { names, force } => {
+            for name in names.iter() {
+                remove(data_root, name, force)?;
+                println!("{}", name);
+            }
+        }
+        VolumeArg::Inspect { names } => {
+            let mut volumes = vec![];
+            for name in names.iter() {
+                volumes.push(load(data_root, name)?);
+            }
+            println!("{}", serde_json::to_string_pretty(&volumes)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mount::{test_dir, Propagation};
+    use crate::state::{create_container_dir, ContainerState};
+    use nix::unistd::getuid;
+
+    #[test]
+    fn invalid_volume_name() {
+        let root = test_dir();
+        assert!(create(&root, "../data", getuid().as_raw()).is_err());
+        assert!(load(&root, "-data").is_err());
+    }
+
+    #[test]
+    fn volume_lifecycle() {
+        let root = test_dir();
+        let owner = getuid().as_raw();
+
+        let volume = create(&root, "foo", owner).unwrap();
+        assert!(volume.mountpoint.is_dir());
+        assert!(create(&root, "foo", owner).is_err());
+        create(&root, "bar", owner).unwrap();
+
+        let names: Vec<String> = list(&root).unwrap().into_iter().map(|v| v.name).collect();
+        assert_eq!(names, vec!["bar", "foo"]);
+
+        let loaded = load(&root, "foo").unwrap();
+        assert_eq!(loaded.mountpoint, volume.mountpoint);
+        assert_eq!(loaded.uid, owner);
+
+        remove(&root, "foo", false).unwrap();
+        assert!(load(&root, "foo").is_err());
+
+        // mounted in a container
+        let mut state = ContainerState::new(
+            "0123456789abcdef".to_string(),
+            Some("web".to_string()),
+            "web".to_string(),
+            "bash".to_string(),
+            PathBuf::from("/"),
+        );
+        state.volumes = vec!["bar".to_string()];
+        create_container_dir(&root, &state.id).unwrap();
+        state.save(&root).unwrap();
+        assert!(remove(&root, "bar", false).is_err());
+        assert!(load(&root, "bar").is_ok());
+        remove(&root, "bar", true).unwrap();
+        assert!(load(&root, "bar").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn volume_mount_spec() {
+        let root = test_dir();
+        let owner = getuid().as_raw();
+        let volume = create(&root, "foo", owner).unwrap();
+
+        let (name, target, options) = split_mount_spec("foo:/data:rslave", "volumes").unwrap();
+        assert_eq!((name, target), ("foo", "/data"));
+        assert_eq!(options.propagation, Propagation::Rslave);
+        let bind = BindMount::new(volume.mountpoint.clone(), target, options).unwrap();
+        assert_eq!(bind.target, PathBuf::from("data"));
+        assert!(volume_mount(&root, "foo", 0).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
 #!/bin/bash
 
 mkdir -p mountdir
-cargo build && clear && sudo ./target/debug/bowl-rs --debug true -u 0 -m ./mountdir/ -c "/bin/bash" -a /lib64:/lib64 -a /lib:/lib
+cargo build && clear && sudo ./target/debug/bowl-rs --debug true run -u 0 -m ./mountdir/ -c "/bin/bash" -a /lib64:/lib64 -a /lib:/lib